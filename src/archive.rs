@@ -0,0 +1,166 @@
+//! Static libraries, objects bundled with an index of the globals
+//! each one defines, one record per line:
+//!
+//! ```text
+//! index square 0
+//! index count 0
+//! member lib.o
+//! section text 00000000 00000020
+//! ...
+//! ```
+//!
+//! A member's records run up to the next `member`.
+
+use std::{fmt, str::FromStr};
+
+use crate::object::Object;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Archive {
+    members: Vec<(Box<str>, Object)>,
+    /// Global symbol to the member defining it.
+    index: Vec<(Box<str>, usize)>,
+}
+
+impl Archive {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `text` looks like an archive rather than an object.
+    pub fn is_archive(text: &str) -> bool {
+        let first = text.lines().find(|l| !l.trim().is_empty());
+        first.is_some_and(|l| {
+            l.starts_with("index ") || l.starts_with("member ")
+        })
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = (&str, &Object)> {
+        self.members.iter().map(|(name, obj)| (&**name, obj))
+    }
+
+    pub fn member(&self, name: &str) -> Option<&Object> {
+        self.members.iter().find(|m| *m.0 == *name).map(|m| &m.1)
+    }
+
+    /// `(symbol, member)` for every global.
+    pub fn index(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.index
+            .iter()
+            .map(|(sym, i)| (&**sym, &*self.members[*i].0))
+    }
+
+    /// Member defining the global `symbol`.
+    pub fn find(&self, symbol: &str) -> Option<(&str, &Object)> {
+        let &(_, i) = self.index.iter().find(|s| *s.0 == *symbol)?;
+        let (name, obj) = &self.members[i];
+        Some((name, obj))
+    }
+
+    /// Add `obj`, replacing a member of the same name.
+    pub fn add(&mut self, name: &str, obj: Object) {
+        match self.members.iter_mut().find(|m| *m.0 == *name) {
+            Some(member) => member.1 = obj,
+            None => self.members.push((name.into(), obj)),
+        }
+        self.reindex();
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Object> {
+        let idx = self.members.iter().position(|m| *m.0 == *name)?;
+        let (_, obj) = self.members.remove(idx);
+        self.reindex();
+        Some(obj)
+    }
+
+    fn reindex(&mut self) {
+        self.index.clear();
+        for (i, (_, obj)) in self.members.iter().enumerate() {
+            for s in obj.symbols.iter().filter(|s| s.global) {
+                // the first member defining a symbol wins
+                if !self.index.iter().any(|e| e.0 == s.name) {
+                    self.index.push((s.name.clone(), i));
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Archive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (sym, i) in &self.index {
+            writeln!(f, "index {sym} {i}")?;
+        }
+        for (name, obj) in &self.members {
+            writeln!(f, "member {name}")?;
+            write!(f, "{obj}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Archive {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut archive = Archive::default();
+        let mut member: Option<(&str, String)> = None;
+        for (i, line) in s.lines().enumerate() {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("index") if member.is_none() => {
+                    let (Some(sym), Some(idx)) =
+                        (fields.next(), fields.next())
+                    else {
+                        return Err(format!(
+                            "truncated index at line {}",
+                            i + 1
+                        )
+                        .into());
+                    };
+                    archive.index.push((sym.into(), idx.parse()?));
+                }
+                Some("member") => {
+                    if let Some((name, text)) = member.take() {
+                        archive
+                            .members
+                            .push((name.into(), text.parse()?));
+                    }
+                    let name = fields.next().ok_or_else(|| {
+                        format!("unnamed member at line {}", i + 1)
+                    })?;
+                    member = Some((name, String::new()));
+                }
+                _ => match &mut member {
+                    Some((_, text)) => {
+                        *text += line;
+                        text.push('\n');
+                    }
+                    None if line.trim().is_empty() => {}
+                    None => {
+                        return Err(format!(
+                            "record outside a member at line {}",
+                            i + 1
+                        )
+                        .into())
+                    }
+                },
+            }
+        }
+        if let Some((name, text)) = member {
+            archive.members.push((name.into(), text.parse()?));
+        }
+        if let Some(bad) = archive
+            .index
+            .iter()
+            .find(|e| e.1 >= archive.members.len())
+        {
+            return Err(format!(
+                "index entry for '{}' names a missing member",
+                bad.0
+            )
+            .into());
+        }
+        Ok(archive)
+    }
+}
@@ -0,0 +1,151 @@
+//! Conditional assembly: `.if`, `.ifdef`, `.ifndef`, `.else` and
+//! `.endif`, evaluated as the first pass reaches them.
+
+use super::{
+    at,
+    directives::Directives,
+    lexer::{Token, TokensKind},
+    symbols::{SymbolKind, SymbolTable},
+};
+
+#[derive(Debug)]
+struct Block {
+    open: Token,
+    /// Tokens in the current branch are assembled.
+    active: bool,
+    /// Some branch was, so `.else` isn't.
+    taken: bool,
+    has_else: bool,
+}
+
+/// Open blocks, innermost last.
+#[derive(Debug, Default)]
+pub(super) struct Conditions {
+    blocks: Vec<Block>,
+}
+
+impl Conditions {
+    /// Whether tokens here are assembled.
+    pub(super) fn active(&self) -> bool {
+        self.blocks.last().is_none_or(|b| b.active)
+    }
+
+    /// Apply the conditional directive `d` at `tok`, `args` being the
+    /// rest of its line.
+    pub(super) fn directive(
+        &mut self,
+        d: Directives,
+        tok: Token,
+        args: &[Token],
+        symbol_table: &SymbolTable,
+    ) -> Result<(), String> {
+        let at = at(symbol_table, &tok);
+        match d {
+            Directives::If
+            | Directives::Ifdef
+            | Directives::Ifndef => {
+                let outer = self.active();
+                // conditions in skipped blocks aren't evaluated
+                let holds = match d {
+                    _ if !outer => Ok(false),
+                    Directives::If => eval(args, symbol_table),
+                    _ => match args {
+                        [name] => Ok(is_defined(name, symbol_table)
+                            == (d == Directives::Ifdef)),
+                        _ => Err("expected one name".to_string()),
+                    },
+                };
+                // still opened on error so its .endif matches up
+                self.blocks.push(Block {
+                    open: tok,
+                    active: holds == Ok(true),
+                    taken: holds == Ok(true),
+                    has_else: false,
+                });
+                holds.map_err(|e| format!("{at}: {e}"))?;
+            }
+            Directives::Else => {
+                let outer = self.blocks.len() < 2
+                    || self.blocks[self.blocks.len() - 2].active;
+                let Some(block) = self.blocks.last_mut() else {
+                    return Err(format!("{at}: .else without .if"));
+                };
+                if block.has_else {
+                    return Err(format!(
+                        "{at}: second .else for the .if at {}",
+                        super::at(symbol_table, &block.open)
+                    ));
+                }
+                block.has_else = true;
+                block.active = outer && !block.taken;
+            }
+            Directives::Endif => {
+                if self.blocks.pop().is_none() {
+                    return Err(format!("{at}: .endif without .if"));
+                }
+            }
+            _ => unreachable!("not a conditional directive"),
+        }
+        Ok(())
+    }
+
+    /// Errors for blocks still open at the end of the source.
+    pub(super) fn finish(
+        &self,
+        symbol_table: &SymbolTable,
+    ) -> Vec<String> {
+        self.blocks
+            .iter()
+            .map(|b| {
+                format!(
+                    "{}: .if without .endif",
+                    at(symbol_table, &b.open)
+                )
+            })
+            .collect()
+    }
+}
+
+/// A constant, or a label defined before here.
+fn is_defined(tok: &Token, symbol_table: &SymbolTable) -> bool {
+    let TokensKind::Label(id) = tok.kind else {
+        return false;
+    };
+    symbol_table.get_symbol(&id).is_some_and(|s| {
+        s.r#type == SymbolKind::Constant
+            || (s.r#type == SymbolKind::Label && s.value.is_some())
+    })
+}
+
+/// `a`, true when non-zero, or `a <relation> b`.
+fn eval(
+    args: &[Token],
+    symbol_table: &SymbolTable,
+) -> Result<bool, String> {
+    let value = |tok: &Token| match tok.kind {
+        TokensKind::Imm(n) => Ok(n as i64),
+        TokensKind::Label(id) => {
+            let sym = symbol_table.get_symbol(&id).unwrap();
+            match (sym.r#type, sym.value) {
+                (SymbolKind::Constant, Some(v)) => {
+                    Ok(v as i32 as i64)
+                }
+                (SymbolKind::Label, Some(v)) => Ok(v as i64),
+                _ => Err(format!("'{}' isn't defined", sym.name)),
+            }
+        }
+        kind => Err(format!("unexpected {kind:?} in condition")),
+    };
+    match args {
+        [a] => Ok(value(a)? != 0),
+        [a, op, b] => match op.kind {
+            TokensKind::Relation(rel) => {
+                Ok(rel.holds(value(a)?, value(b)?))
+            }
+            kind => {
+                Err(format!("expected a comparison, got {kind:?}"))
+            }
+        },
+        _ => Err("expected `a` or `a <op> b`".to_string()),
+    }
+}
@@ -4,6 +4,19 @@ use std::str::FromStr;
 pub enum Directives {
     Entry,
     Section,
+    Global,
+    Extern,
+    Include,
+    Incbin,
+    Equ,
+    If,
+    Ifdef,
+    Ifndef,
+    Else,
+    Endif,
+    Rept,
+    Irp,
+    Endr,
     MacroStart,
     MacroEnd,
 }
@@ -15,6 +28,19 @@ impl FromStr for Directives {
         Ok(match s {
             ".entry" => Self::Entry,
             ".section" => Self::Section,
+            ".global" => Self::Global,
+            ".extern" => Self::Extern,
+            ".include" => Self::Include,
+            ".incbin" => Self::Incbin,
+            ".equ" => Self::Equ,
+            ".if" => Self::If,
+            ".ifdef" => Self::Ifdef,
+            ".ifndef" => Self::Ifndef,
+            ".else" => Self::Else,
+            ".endif" => Self::Endif,
+            ".rept" => Self::Rept,
+            ".irp" => Self::Irp,
+            ".endr" => Self::Endr,
             ".macro" => Self::MacroStart,
             ".endmacro" => Self::MacroEnd,
             _ => return Err(format!("unknown macro {s}").into()),
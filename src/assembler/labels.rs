@@ -0,0 +1,159 @@
+//! Local and anonymous labels, named apart in the symbol table as the
+//! first pass meets them.
+//!
+//! ```text
+//! copy:
+//! .loop:              ; copy.loop
+//!     bne .loop
+//! 1:                  ; 1@0
+//!     bne 1b
+//! ```
+
+use std::collections::HashMap;
+
+use super::{
+    at,
+    lexer::Token,
+    symbols::{SymbolId, SymbolKind, SymbolTable},
+};
+
+#[derive(Debug, Default)]
+pub(super) struct Labels {
+    /// The last label defined, owning the local ones after it.
+    scope: Option<String>,
+    /// `1:` definitions seen so far, by number.
+    anonymous: HashMap<u32, u32>,
+    /// References to check once everything is defined, and what to
+    /// say if they aren't.
+    pending: Vec<(Token, SymbolId, String)>,
+}
+
+impl Labels {
+    /// `id` was defined, starting a new scope.
+    pub(super) fn global(
+        &mut self,
+        id: SymbolId,
+        symbol_table: &SymbolTable,
+    ) {
+        self.scope =
+            symbol_table.get_symbol(&id).map(|s| s.name.to_string());
+    }
+
+    /// The label `.name` at `tok` means here, defined at `addr` for a
+    /// declaration.
+    pub(super) fn local(
+        &mut self,
+        tok: &Token,
+        name: &str,
+        addr: Option<u32>,
+        symbol_table: &mut SymbolTable,
+    ) -> Result<SymbolId, String> {
+        let owner = self.scope.as_deref().unwrap_or_default();
+        let id = symbol_table.insert(
+            &format!("{owner}{name}"),
+            SymbolKind::Label,
+            None,
+            tok.line,
+        );
+        let sym = symbol_table.get_symbol(&id).unwrap();
+        let what = match owner {
+            "" => format!("'{name}'"),
+            _ => format!("'{name}' in '{owner}'"),
+        };
+        match (addr, sym.value) {
+            (Some(_), Some(_)) if sym.r#type == SymbolKind::Label => {
+                return Err(format!(
+                    "{what} is already defined at line {}",
+                    sym.line
+                ))
+            }
+            (Some(addr), _) => {
+                define(symbol_table, id, addr, tok.line)
+            }
+            (None, _) => {
+                self.pending.push((*tok, id, format!("no {what}")))
+            }
+        }
+        Ok(id)
+    }
+
+    /// Define the next `n:` at `addr`.
+    pub(super) fn anonymous(
+        &mut self,
+        tok: &Token,
+        n: u32,
+        addr: u32,
+        symbol_table: &mut SymbolTable,
+    ) -> SymbolId {
+        let count = self.anonymous.entry(n).or_default();
+        let id = symbol_table.insert(
+            &format!("{n}@{count}"),
+            SymbolKind::Label,
+            None,
+            tok.line,
+        );
+        *count += 1;
+        define(symbol_table, id, addr, tok.line);
+        id
+    }
+
+    /// The `n:` that `nb` or `nf` at `tok` refers to.
+    pub(super) fn reference(
+        &mut self,
+        tok: &Token,
+        n: u32,
+        forward: bool,
+        symbol_table: &mut SymbolTable,
+    ) -> Result<SymbolId, String> {
+        let count = self.anonymous.get(&n).copied().unwrap_or(0);
+        let idx = match forward {
+            true => count,
+            false => count
+                .checked_sub(1)
+                .ok_or_else(|| format!("no '{n}:' before '{n}b'"))?,
+        };
+        let id = symbol_table.insert(
+            &format!("{n}@{idx}"),
+            SymbolKind::Label,
+            None,
+            tok.line,
+        );
+        if forward {
+            self.pending.push((
+                *tok,
+                id,
+                format!("no '{n}:' after '{n}f'"),
+            ));
+        }
+        Ok(id)
+    }
+
+    /// Errors for references that never got defined.
+    pub(super) fn finish(
+        &self,
+        symbol_table: &SymbolTable,
+    ) -> Vec<String> {
+        self.pending
+            .iter()
+            .filter(|(_, id, _)| {
+                symbol_table.get_symbol(id).unwrap().value.is_none()
+            })
+            .map(|(tok, _, what)| {
+                format!("{}: {what}", at(symbol_table, tok))
+            })
+            .collect()
+    }
+}
+
+fn define(
+    symbol_table: &mut SymbolTable,
+    id: SymbolId,
+    addr: u32,
+    line: usize,
+) {
+    symbol_table.update(id, |s| {
+        s.r#type = SymbolKind::Label;
+        s.value = Some(addr);
+        s.line = line;
+    });
+}
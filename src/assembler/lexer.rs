@@ -1,8 +1,15 @@
-use super::symbols::{SymbolId, SymbolTable};
+use super::{
+    directives::Directives,
+    pseudo::Pseudo,
+    symbols::{SymbolId, SymbolTable},
+};
 use crate::{
-    assembler::symbols::SymbolKind, opcode::Op, register::Register,
+    assembler::symbols::SymbolKind,
+    debug::Loc,
+    opcode::{Cond, Op},
+    register::{Register, SpecialRegister},
 };
-use std::{str::Chars, sync::MutexGuard};
+use std::str::Chars;
 use TokensKind::*;
 
 #[derive(Debug)]
@@ -10,15 +17,23 @@ pub struct Lexer<'src> {
     pub source: &'src str,
     chars: Chars<'src>,
     start: usize,
-    syms: MutexGuard<'src, SymbolTable<'static>>,
+    syms: &'src mut SymbolTable<'static>,
     line: usize,
+    // byte offset the current line starts at
+    line_start: usize,
+    // index of the file being lexed, see `SymbolTable::add_file`
+    file: u32,
+    // on a `.macro` line, where every name is a label
+    macro_line: bool,
 }
 
 pub fn tokenize<'src>(
     source: &'src str,
-    syms: MutexGuard<'src, SymbolTable<'static>>,
+    syms: &'src mut SymbolTable<'static>,
+    file: u32,
 ) -> impl Iterator<Item = Token> + use<'src> {
     let mut lexer = Lexer::new(source, syms);
+    lexer.file = file;
     std::iter::from_fn(move || {
         let token = lexer.next_token();
         if token.kind != TokensKind::Eof {
@@ -32,7 +47,7 @@ pub fn tokenize<'src>(
 impl<'src> Lexer<'src> {
     pub fn new(
         source: &'src str,
-        syms: MutexGuard<'src, SymbolTable<'static>>,
+        syms: &'src mut SymbolTable<'static>,
     ) -> Self {
         Self {
             source,
@@ -40,67 +55,130 @@ impl<'src> Lexer<'src> {
             start: 0,
             syms,
             line: 1,
+            line_start: 0,
+            file: 0,
+            macro_line: false,
         }
     }
 
     fn next_token(&mut self) -> Token {
         self.advance_while(|c| matches!(c, '\t' | '\r' | ' '));
         self.start = self.pos();
+        let column = self.start - self.line_start + 1;
 
         let char = self.advance();
         // print!("'{char}' ");
 
         let kind = match char {
-            '\n' => Newline,
-            '#' => {
+            '\n' => {
+                self.macro_line = false;
+                Newline
+            }
+            // a constant as an immediate
+            '#' if self.peek().is_ascii_alphabetic()
+                || self.peek() == '_' =>
+            {
                 self.start = self.pos();
-                // consume number
-                self.advance_while(|c| {
-                    c.is_ascii_hexdigit() || c == 'x'
+                self.advance_while(|x| {
+                    x.is_ascii_alphanumeric() || x == '_'
                 });
-                let content = self.content().trim_start_matches("0x");
-                match content.parse::<i8>() {
-                    Ok(imm) => Imm(imm as i32),
-                    Err(_) => match content.parse::<i16>() {
-                        Ok(imm) => Imm(imm as i32),
-                        Err(_) => match content.parse::<i32>() {
-                            Ok(imm) => Imm(imm),
-                            Err(_) => self.make_error(),
-                        },
-                    },
+                let s = self.content();
+                Label(self.syms.insert(
+                    s,
+                    SymbolKind::Label,
+                    None,
+                    self.line,
+                ))
+            }
+            '#' => {
+                self.start = self.pos();
+                if self.peek() == '-' {
+                    self.advance();
+                }
+                self.number()
+            }
+            // bare numbers for directive arguments and anonymous
+            // labels, `1b` and `1f` referring to them
+            x if x.is_ascii_digit() => {
+                self.advance_while(|c| c.is_ascii_digit());
+                let mut ahead = self.chars.clone();
+                match (ahead.next(), ahead.next()) {
+                    (Some(dir @ ('b' | 'f')), next)
+                        if !next.is_some_and(|c| {
+                            c.is_ascii_alphanumeric() || c == '_'
+                        }) =>
+                    {
+                        let n = self.content().parse().unwrap_or(0);
+                        self.advance();
+                        match dir {
+                            'b' => AnonBack(n),
+                            _ => AnonFwd(n),
+                        }
+                    }
+                    _ => self.number(),
                 }
             }
-            x if x.is_ascii_alphabetic() || x == '_' => {
+            // `\name` and `\@` are substituted in macro bodies
+            x if x.is_ascii_alphabetic() || x == '_' || x == '\\' => {
                 self.advance_while(|x| {
-                    x.is_ascii_alphanumeric() || x == '_'
+                    x.is_ascii_alphanumeric()
+                        || matches!(x, '_' | '\\' | '@')
                 });
                 let content = self.content().to_lowercase();
 
-                match content.parse::<Register>() {
-                    Ok(r) => Register(r),
-                    Err(_) => match content.parse::<Op>() {
-                        Ok(o) => Mnemonic(o),
-                        Err(_) => {
-                            let s = self.content();
-                            Label(self.syms.insert(
-                                s,
-                                SymbolKind::Label,
-                                None,
-                                self.line,
-                            ))
-                        }
-                    },
+                if self.macro_line {
+                    let s = self.content();
+                    Label(self.syms.insert(
+                        s,
+                        SymbolKind::Label,
+                        None,
+                        self.line,
+                    ))
+                } else if let Ok(r) = content.parse::<Register>() {
+                    Register(r)
+                } else if let Ok(s) =
+                    content.parse::<SpecialRegister>()
+                {
+                    Special(s)
+                } else if let Ok(o) = content.parse::<Op>() {
+                    Mnemonic(o)
+                } else if let Ok(c) = content.parse::<Cond>() {
+                    Branch(c)
+                } else if let Ok(p) = content.parse::<Pseudo>() {
+                    Pseudo(p)
+                } else {
+                    let s = self.content();
+                    Label(self.syms.insert(
+                        s,
+                        SymbolKind::Label,
+                        None,
+                        self.line,
+                    ))
                 }
             }
+            // `.name\@` in a macro body too
             '.' => {
-                self.advance_while(|c| c.is_alphanumeric());
+                self.advance_while(|c| {
+                    c.is_alphanumeric()
+                        || matches!(c, '_' | '\\' | '@')
+                });
                 let s = self.content();
-                Directive(self.syms.insert(
-                    s,
-                    SymbolKind::Directive,
-                    None,
-                    self.line,
-                ))
+                self.macro_line = s == ".macro";
+                if s.parse::<Directives>().is_ok() {
+                    Directive(self.syms.insert(
+                        s,
+                        SymbolKind::Directive,
+                        None,
+                        self.line,
+                    ))
+                } else {
+                    Local(self.syms.insert(
+                        s,
+                        SymbolKind::None,
+                        None,
+                        self.line,
+                    ))
+                }
             }
             '%' => {
                 self.advance_while(|c| c.is_alphanumeric());
@@ -113,6 +191,37 @@ impl<'src> Lexer<'src> {
                 )
             }
 
+            '"' => {
+                self.advance_while(|c| c != '"' && c != '\n');
+                if self.advance() != '"' {
+                    self.make_error()
+                } else {
+                    // quoted so it can't collide with a label
+                    let s = self.content();
+                    Str(self.syms.insert(
+                        s,
+                        SymbolKind::None,
+                        None,
+                        self.line,
+                    ))
+                }
+            }
+            '=' | '!' | '<' | '>' => {
+                let eq = self.peek() == '=';
+                if eq {
+                    self.advance();
+                }
+                match (char, eq) {
+                    ('=', false) => Equals,
+                    ('=', true) => Relation(Rel::Eq),
+                    ('!', true) => Relation(Rel::Ne),
+                    ('<', false) => Relation(Rel::Lt),
+                    ('<', true) => Relation(Rel::Le),
+                    ('>', false) => Relation(Rel::Gt),
+                    ('>', true) => Relation(Rel::Ge),
+                    _ => self.make_error(),
+                }
+            }
             ',' => Comma,
             ';' => {
                 self.advance_while(|c| c != '\n');
@@ -131,6 +240,31 @@ impl<'src> Lexer<'src> {
         Token {
             kind,
             line: self.line,
+            column,
+            file: self.file,
+            expanded_from: None,
+            depth: 0,
+        }
+    }
+
+    /// Anything 32 bits hold, decimal or `0x` hex, negatives as
+    /// two's complement.
+    fn number(&mut self) -> TokensKind {
+        // consume number
+        self.advance_while(|c| c.is_ascii_hexdigit() || c == 'x');
+        let content = self.content();
+        let (negative, digits) = match content.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, content),
+        };
+        let value = match digits.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => digits.parse::<u32>(),
+        };
+        match value {
+            Ok(n) if !negative => Imm(n as i32),
+            Ok(n) if n <= 1 << 31 => Imm((n as i32).wrapping_neg()),
+            _ => self.make_error(),
         }
     }
 
@@ -140,6 +274,7 @@ impl<'src> Lexer<'src> {
             .inspect(|&x| {
                 if x == '\n' {
                     self.line += 1;
+                    self.line_start = self.pos();
                 }
             })
             .unwrap_or('\0')
@@ -168,7 +303,7 @@ impl<'src> Lexer<'src> {
     }
 
     fn make_error(&mut self) -> TokensKind {
-        self.advance_while(|x| !x.is_whitespace() || x != ',');
+        self.advance_while(|x| !x.is_whitespace() && x != ',');
         let s = self.content();
         Error(self.syms.insert(s, SymbolKind::None, None, self.line))
     }
@@ -179,21 +314,67 @@ impl<'src> Lexer<'src> {
 )]
 pub enum TokensKind {
     Mnemonic(Op),
+    Branch(Cond),
     Register(Register),
+    Special(SpecialRegister),
     Imm(i32),
     Label(SymbolId),
     Directive(SymbolId),
     Error(SymbolId),
     Param(usize),
+    /// A quoted string, the symbol's name keeps the quotes.
+    Str(SymbolId),
+    /// A raw word from `.incbin`.
+    Data(u32),
+    /// A comparison in an `.if` condition.
+    Relation(Rel),
+    /// `.name`, scoped to the label before it, or an unknown
+    /// directive.
+    Local(SymbolId),
+    /// `1b`, the nearest `1:` before it.
+    AnonBack(u32),
+    /// `1f`, the nearest `1:` after it.
+    AnonFwd(u32),
+    /// An instruction the first pass expands into real ones.
+    Pseudo(Pseudo),
+    /// The upper 19 bits of the next label's address, from `la`.
+    Hi,
+    /// The low 13 bits of the next label's address, from `la`.
+    Lo,
 
     Comment,
     Comma,
+    /// Gives a macro parameter its default.
+    Equals,
     Semi,
     Newline,
     #[default]
     Eof,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rel {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Rel {
+    pub fn holds(self, a: i64, b: i64) -> bool {
+        match self {
+            Rel::Eq => a == b,
+            Rel::Ne => a != b,
+            Rel::Lt => a < b,
+            Rel::Le => a <= b,
+            Rel::Gt => a > b,
+            Rel::Ge => a >= b,
+        }
+    }
+}
+
 impl TokensKind {
     pub fn get_reg(
         &self,
@@ -205,6 +386,16 @@ impl TokensKind {
         }
     }
 
+    pub fn get_special(
+        &self,
+    ) -> Result<SpecialRegister, Box<dyn std::error::Error>> {
+        if let Special(s) = self {
+            Ok(*s)
+        } else {
+            Err("not a special register symbol".to_string().into())
+        }
+    }
+
     pub fn get_op(&self) -> Result<Op, Box<dyn std::error::Error>> {
         if let Mnemonic(i) = self {
             Ok(*i)
@@ -225,7 +416,7 @@ impl TokensKind {
         &self,
     ) -> Result<SymbolId, Box<dyn std::error::Error>> {
         match *self {
-            Label(s) | Error(s) | Directive(s) => Ok(s),
+            Label(s) | Error(s) | Directive(s) | Str(s) => Ok(s),
             _ => Err(format!("unexpected symbol {:?}", self,).into()),
         }
     }
@@ -245,10 +436,26 @@ impl TokensKind {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct Token {
     pub kind: TokensKind,
     pub line: usize,
+    pub column: usize,
+    pub file: u32,
+    /// The outermost macro call this came out of.
+    pub expanded_from: Option<Loc>,
+    /// Macro calls it's nested in.
+    pub depth: u32,
+}
+
+impl Token {
+    pub fn loc(&self) -> Loc {
+        Loc {
+            file: self.file,
+            line: self.line as u32,
+            column: self.column as u32,
+        }
+    }
 }
 
 /*
@@ -257,8 +464,14 @@ pub struct Token {
 
    .entry _main
    .section data
-   .macro name %a1 %b2
-       Add %a1, %a1, %b2
+   .macro name a, b=#1
+       Add \a, \a, \b
    .endmacro
+   .rept 4
+       Add r0, r0, #1
+   .endr
+   .irp reg, r1, r2
+       Push \reg
+   .endr
 
 */
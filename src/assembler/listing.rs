@@ -0,0 +1,248 @@
+//! Listing and map files, `jasm -l` and `jasm -m`.
+
+use std::{collections::BTreeMap, fmt::Write, fs};
+
+use super::{
+    symbols::{Symbol, SymbolKind},
+    Assembly,
+};
+use crate::{debug::LineEntry, disasm::disassemble};
+
+/// A label, section or constant in the assembled program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapSymbol {
+    pub name: Box<str>,
+    pub kind: SymbolKind,
+    pub addr: u32,
+    /// Bytes up to the next symbol of the same kind, or the end of
+    /// the code.
+    pub size: u32,
+    /// Line it's defined on.
+    pub line: usize,
+}
+
+/// Placed `symbols` by address, sized against each other.
+pub(super) fn layout<'s>(
+    symbols: impl Iterator<Item = Symbol<'s>>,
+    end: u32,
+) -> Vec<MapSymbol> {
+    let mut placed = symbols
+        .filter_map(|s| {
+            Some(MapSymbol {
+                name: s.name.into(),
+                kind: s.r#type,
+                addr: s.value?,
+                size: 0,
+                line: s.line,
+            })
+        })
+        .collect::<Vec<_>>();
+    // sections ahead of the labels they start with
+    placed.sort_by_key(|s| {
+        (s.addr, s.kind != SymbolKind::Section, s.name.clone())
+    });
+
+    for i in 0..placed.len() {
+        let (addr, kind) = (placed[i].addr, placed[i].kind);
+        // a value rather than an address
+        if kind == SymbolKind::Constant {
+            continue;
+        }
+        let next = placed[i + 1..]
+            .iter()
+            .find(|s| s.kind == kind && s.addr > addr)
+            .map_or(end, |s| s.addr);
+        placed[i].size = next.saturating_sub(addr);
+    }
+    placed
+}
+
+impl Assembly {
+    /// Every line of `source`, the assembled file, next to the
+    /// address and encoding of its instruction. What macros and
+    /// pseudo-instructions expand to follows them, disassembled and
+    /// marked with `+`. Included files are read from disk and listed
+    /// after it.
+    pub fn listing(&self, source: &str) -> String {
+        let texts = self
+            .debug
+            .files()
+            .enumerate()
+            .map(|(i, path)| match i {
+                0 => source.to_string(),
+                _ => fs::read_to_string(path).unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+
+        // by (file, line) of the source or the macro call
+        let mut own = BTreeMap::<(u32, u32), Vec<&LineEntry>>::new();
+        let mut expanded =
+            BTreeMap::<(u32, u32), Vec<&LineEntry>>::new();
+        for entry in self.debug.lines() {
+            let (map, at) = match entry.expanded_from {
+                Some(call) => (&mut expanded, call),
+                None => (&mut own, entry.loc),
+            };
+            map.entry((at.file, at.line)).or_default().push(entry);
+        }
+        let word = |addr: u32| {
+            let bytes = &self.code[addr as usize..][..4];
+            u32::from_le_bytes(bytes.try_into().unwrap())
+        };
+
+        let mut out = String::new();
+        for (file, path) in self.debug.files().enumerate() {
+            let file = file as u32;
+            if file != 0 {
+                writeln!(out, "\n; {path}").unwrap();
+            }
+            writeln!(out, " line  address  word      source")
+                .unwrap();
+            for (i, src) in texts[file as usize].lines().enumerate() {
+                let at = (file, i as u32 + 1);
+                let entries = own.get(&at).map_or(&[][..], |e| e);
+                match entries.first() {
+                    Some(e) => writeln!(
+                        out,
+                        "{:>5}  {:08x} {:08x}  {src}",
+                        at.1,
+                        e.addr,
+                        word(e.addr)
+                    ),
+                    None => {
+                        writeln!(out, "{:>5}  {:17}  {src}", at.1, "")
+                    }
+                }
+                .unwrap();
+                for e in entries.iter().skip(1) {
+                    writeln!(
+                        out,
+                        "{:>5}  {:08x} {:08x}",
+                        "",
+                        e.addr,
+                        word(e.addr)
+                    )
+                    .unwrap();
+                }
+
+                for e in expanded.get(&at).into_iter().flatten() {
+                    writeln!(
+                        out,
+                        "{:>5}+ {:08x} {:08x}      {}",
+                        "",
+                        e.addr,
+                        word(e.addr),
+                        disassemble(word(e.addr))
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        out
+    }
+
+    /// Every label, section and constant with its address (a
+    /// constant's value) and size, sorted by
+    /// address and then again by name.
+    pub fn map(&self) -> String {
+        let mut by_name = self.symbols.iter().collect::<Vec<_>>();
+        by_name.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut out = String::new();
+        for (title, symbols) in [
+            ("by address", self.symbols.iter().collect()),
+            ("by name", by_name),
+        ] {
+            writeln!(out, "; {title}").unwrap();
+            writeln!(
+                out,
+                "{:<8} {:<8} {:<8} {:>5}  name",
+                "address", "size", "kind", "line"
+            )
+            .unwrap();
+            for s in symbols {
+                let kind = match s.kind {
+                    SymbolKind::Section => "section",
+                    SymbolKind::Constant => "constant",
+                    _ => "label",
+                };
+                writeln!(
+                    out,
+                    "{:08x} {:08x} {kind:<8} {:>5}  {}",
+                    s.addr, s.size, s.line, s.name
+                )
+                .unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+
+    const PROGRAM: &str = "\
+.equ COUNT, 3
+.macro twice reg
+    inc \\reg
+    inc \\reg
+.endmacro
+start:
+    ldr r1, #COUNT
+loop:
+    twice r2
+    dec r1
+    bnez r1, loop
+    halt
+";
+
+    #[test]
+    fn listing() {
+        let asm = assemble("test.jasm", PROGRAM);
+        assert_eq!(
+            asm.listing(PROGRAM),
+            " line  address  word      source
+    1                     .equ COUNT, 3
+    2                     .macro twice reg
+    3                         inc \\reg
+    4                         inc \\reg
+    5                     .endmacro
+    6                     start:
+    7  00000000 b0080003      ldr r1, #COUNT
+    8                     loop:
+    9                         twice r2
+     + 00000004 90108001      add r2, r2, #1
+     + 00000008 90108001      add r2, r2, #1
+   10                         dec r1
+     + 0000000c 91084001      sub r1, r1, #1
+   11                         bnez r1, loop
+     + 00000010 94080000      cmp r1, #0
+     + 00000014 502ffff0      bne -16
+   12  00000018 66000000      halt
+"
+        );
+    }
+
+    #[test]
+    fn map() {
+        let asm = assemble("test.jasm", PROGRAM);
+        assert_eq!(
+            asm.map(),
+            "; by address
+address  size     kind      line  name
+00000000 00000004 label        6  start
+00000003 00000000 constant     1  COUNT
+00000004 00000018 label        8  loop
+
+; by name
+address  size     kind      line  name
+00000003 00000000 constant     1  COUNT
+00000004 00000018 label        8  loop
+00000000 00000004 label        6  start
+
+"
+        );
+    }
+}
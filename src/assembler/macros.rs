@@ -0,0 +1,250 @@
+//! Macro expansion for the first pass.
+//!
+//! ```text
+//! .macro adder dst, src=#1
+//!     add \dst, \dst, \src
+//! .endmacro
+//! ```
+//!
+//! Parameters are named and referenced as `\name`, or positional
+//! `%1 %2` as before. `\@` in a label, local ones too, is replaced by a
+//! number unique to each expansion. `.rept n` and `.irp name, a, b`
+//! repeat what's up to their `.endr`.
+
+use std::collections::{HashMap, VecDeque};
+
+use super::{
+    directives::Directives,
+    lexer::{Token, TokensKind},
+    symbols::{SymbolId, SymbolKind, SymbolTable},
+    DirectiveBody, Macros,
+};
+
+/// Macro calls nest at most this deep, which stops runaway recursion.
+pub const MAX_DEPTH: u32 = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MacroParam {
+    /// Referenced as `\name`, or only by position for `%N`.
+    pub name: Option<SymbolId>,
+    /// Used when the call leaves the argument out.
+    pub default: Option<Token>,
+}
+
+/// The latest definition of the macro `name`.
+pub(super) fn find<'d>(
+    directives: &'d HashMap<SymbolId, Vec<Macros>>,
+    symbol_table: &SymbolTable,
+    name: SymbolId,
+) -> Option<(&'d [MacroParam], &'d [Token])> {
+    let defs = directives.get(&symbol_table.get_id(".macro")?)?;
+    defs.iter().rev().find_map(|d| match &d.body {
+        DirectiveBody::Macro {
+            name: n,
+            parameters,
+            body,
+        } if n.kind == TokensKind::Label(name) => {
+            Some((parameters.as_slice(), body.as_slice()))
+        }
+        _ => None,
+    })
+}
+
+/// Tokens up to the end of the line, not including it.
+pub(super) fn take_line(tokens: &mut VecDeque<Token>) -> Vec<Token> {
+    let mut line = Vec::new();
+    while let Some(tok) = tokens.pop_front() {
+        if matches!(
+            tok.kind,
+            TokensKind::Newline | TokensKind::Comment
+        ) {
+            tokens.push_front(tok);
+            break;
+        }
+        line.push(tok);
+    }
+    line
+}
+
+/// Tokens up to the `end` closing the block just opened, which is
+/// consumed. `.rept` and `.irp` inside nest.
+pub(super) fn take_block(
+    tokens: &mut VecDeque<Token>,
+    symbol_table: &SymbolTable,
+    end: Directives,
+) -> Option<Vec<Token>> {
+    let mut body = Vec::new();
+    let mut depth = 0;
+    while let Some(tok) = tokens.pop_front() {
+        let directive = match tok.kind {
+            TokensKind::Directive(id) => symbol_table
+                .get_symbol(&id)
+                .and_then(|s| s.name.parse::<Directives>().ok()),
+            _ => None,
+        };
+        match directive {
+            Some(Directives::Rept | Directives::Irp)
+                if end == Directives::Endr =>
+            {
+                depth += 1
+            }
+            Some(d) if d == end && depth == 0 => return Some(body),
+            Some(d) if d == end => depth -= 1,
+            _ => {}
+        }
+        body.push(tok);
+    }
+    None
+}
+
+/// `name a, b=#1, %3`
+pub(super) fn params(
+    tokens: &[Token],
+) -> Result<Vec<MacroParam>, String> {
+    let mut params = Vec::new();
+    let mut tokens = tokens.iter().peekable();
+    while let Some(tok) = tokens.next() {
+        let name = match tok.kind {
+            TokensKind::Label(id) => Some(id),
+            TokensKind::Param(_) => None,
+            TokensKind::Comma => continue,
+            kind => {
+                return Err(format!(
+                    "unexpected {kind:?} in parameters"
+                ))
+            }
+        };
+        let default = match tokens
+            .next_if(|t| t.kind == TokensKind::Equals)
+        {
+            Some(_) => Some(*tokens.next().ok_or("missing default")?),
+            None => None,
+        };
+        params.push(MacroParam { name, default });
+    }
+    Ok(params)
+}
+
+/// Arguments of a call, split at commas or, for calls written
+/// without them, between tokens. `None` for one left empty.
+pub(super) fn args(tokens: &[Token]) -> Vec<Option<Token>> {
+    let mut args = Vec::new();
+    let mut pending = None;
+    for tok in tokens {
+        match tok.kind {
+            TokensKind::Comma => args.push(pending.take()),
+            _ => {
+                if let Some(prev) = pending.replace(*tok) {
+                    args.push(Some(prev));
+                }
+            }
+        }
+    }
+    let trailing_comma =
+        tokens.last().is_some_and(|t| t.kind == TokensKind::Comma);
+    if pending.is_some() || trailing_comma {
+        args.push(pending);
+    }
+    args
+}
+
+/// The value of each of `params` for a call with `args`.
+pub(super) fn bind(
+    params: &[MacroParam],
+    args: Vec<Option<Token>>,
+    symbol_table: &SymbolTable,
+) -> Result<Vec<Token>, String> {
+    if args.len() > params.len() {
+        return Err(format!(
+            "takes {} arguments, got {}",
+            params.len(),
+            args.len()
+        ));
+    }
+    params
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            args.get(i).copied().flatten().or(p.default).ok_or_else(
+                || match p.name {
+                    Some(id) => format!(
+                        "missing argument '{}'",
+                        symbol_table.get_symbol(&id).unwrap().name
+                    ),
+                    None => format!("missing argument %{}", i + 1),
+                },
+            )
+        })
+        .collect()
+}
+
+/// `body` with parameters replaced by `values` and, given `unique`,
+/// `\@` in labels and local labels by it.
+pub(super) fn substitute(
+    body: &[Token],
+    params: &[MacroParam],
+    values: &[Token],
+    unique: Option<u32>,
+    symbol_table: &mut SymbolTable,
+) -> Result<Vec<Token>, String> {
+    let mut out = Vec::with_capacity(body.len());
+    for tok in body {
+        let kind = match tok.kind {
+            TokensKind::Param(i) => {
+                values
+                    .get(i)
+                    .ok_or_else(|| {
+                        format!("no parameter %{}", i + 1)
+                    })?
+                    .kind
+            }
+            TokensKind::Label(id) => {
+                let name = symbol_table
+                    .get_symbol(&id)
+                    .unwrap()
+                    .name
+                    .to_string();
+                let param = name.strip_prefix('\\').and_then(|n| {
+                    params.iter().position(|p| {
+                        p.name.is_some_and(|id| {
+                            symbol_table.get_symbol(&id).unwrap().name
+                                == n
+                        })
+                    })
+                });
+                match (param, unique) {
+                    (Some(p), _) => values[p].kind,
+                    (None, Some(n)) if name.contains("\\@") => {
+                        let name =
+                            name.replace("\\@", &n.to_string());
+                        TokensKind::Label(symbol_table.insert(
+                            &name,
+                            SymbolKind::Label,
+                            None,
+                            tok.line,
+                        ))
+                    }
+                    // an enclosing .irp's, or a mistake caught later
+                    _ => tok.kind,
+                }
+            }
+            TokensKind::Local(id) => match unique {
+                Some(n) => {
+                    let name =
+                        symbol_table.get_symbol(&id).unwrap().name;
+                    let name = name.replace("\\@", &n.to_string());
+                    TokensKind::Local(symbol_table.insert(
+                        &name,
+                        SymbolKind::None,
+                        None,
+                        tok.line,
+                    ))
+                }
+                None => tok.kind,
+            },
+            kind => kind,
+        };
+        out.push(Token { kind, ..*tok });
+    }
+    Ok(out)
+}
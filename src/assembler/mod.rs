@@ -1,71 +1,363 @@
+mod conditional;
 mod directives;
+mod labels;
 pub mod lexer;
+mod listing;
+mod macros;
+mod pseudo;
 pub mod symbols;
 
 use std::{
-    borrow::BorrowMut,
-    collections::HashMap,
-    io::{stdout, Write},
+    collections::{HashMap, VecDeque},
+    fs,
+    iter::Peekable,
+    path::{Path, PathBuf},
     process::exit,
-    sync::Mutex,
 };
 
+use conditional::Conditions;
 use directives::Directives;
+use labels::Labels;
 use lexer::{tokenize, Token, TokensKind};
-use symbols::{SymbolId, SymbolTable};
+pub use listing::MapSymbol;
+use macros::MacroParam;
+use symbols::{SymbolId, SymbolKind, SymbolTable};
 
-use crate::opcode::{Instruction, Op, Operand};
+use crate::{
+    debug::{DebugInfo, LineEntry, Loc},
+    object::{Reloc, RelocKind},
+    opcode::{Instruction, Op, Operand},
+    register::{Register, SpecialRegister},
+};
+
+/// Output of `assemble`.
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    pub code: Vec<u8>,
+    pub debug: DebugInfo,
+    /// Labels and sections by address.
+    pub symbols: Vec<MapSymbol>,
+    /// Label named by `.entry`.
+    pub entry: Option<Box<str>>,
+    /// Labels exported with `.global`.
+    pub globals: Vec<Box<str>>,
+    /// Symbols declared `.extern` and not defined here.
+    pub externs: Vec<Box<str>>,
+    /// Every label reference, for the linker to redo.
+    pub relocs: Vec<Reloc>,
+}
+
+/// Settings for `assemble_with`.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Searched in order for `.include` and `.incbin` files not
+    /// found next to the file including them.
+    pub include_dirs: Vec<PathBuf>,
+    /// Constants defined ahead of the source, as `jasm -D` does.
+    pub defines: Vec<(String, i32)>,
+}
+
+pub fn assemble(filename: &str, source: &str) -> Assembly {
+    assemble_with(filename, source, &Options::default())
+}
+
+pub fn assemble_with(
+    filename: &str,
+    source: &str,
+    options: &Options,
+) -> Assembly {
+    try_assemble_with(filename, source, options).unwrap_or_else(
+        |errors| {
+            for e in errors {
+                eprintln!("error: {e}");
+            }
+            exit(1);
+        },
+    )
+}
 
-pub fn assemble(_filename: &str, source: &str) {
-    let symbol_table = Mutex::new(SymbolTable::default());
+/// `assemble_with`, giving back what's wrong with the source
+/// instead of exiting.
+pub fn try_assemble_with(
+    filename: &str,
+    source: &str,
+    options: &Options,
+) -> Result<Assembly, Vec<String>> {
+    let mut binding = SymbolTable::default();
+    let main = binding.add_file(filename);
+    for (name, value) in &options.defines {
+        let id = binding.insert(name, SymbolKind::Constant, None, 0);
+        binding.update(id, |s| {
+            s.r#type = SymbolKind::Constant;
+            s.value = Some(*value as u32);
+        });
+    }
 
     // tokenization
-    let binding: std::sync::MutexGuard<'_, SymbolTable<'_>> =
-        symbol_table.lock().unwrap();
-    let raw_tokens = tokenize(source, binding).collect::<Vec<_>>();
+    let raw_tokens =
+        tokenize(source, &mut binding, main).collect::<Vec<_>>();
 
-    // first pass: resolve macro defs, labels
-    let mut binding = symbol_table.lock().unwrap();
+    // first pass: resolve macro defs, labels, includes
     let mut directives = HashMap::new();
     let tokens = first_pass(
         raw_tokens.into_iter(),
-        binding.borrow_mut(),
+        &mut binding,
         &mut directives,
-    )
-    .map_err(|x| {
-        eprintln!("error occured: ");
-        for e in x {
-            let c = if let TokensKind::Error(i) = e.kind {
-                binding.get_symbol(&i).unwrap().name.to_string()
-            } else {
-                " ".to_string()
-            };
-            eprintln!(
-                "unexpected symbol: '{c}' found at line: {}",
-                e.line
-            );
-        }
-        exit(1);
-    })
-    .unwrap();
+        options,
+    )?;
 
     // second pass
     let ins =
         second_pass(tokens.into_iter(), &directives, &mut binding)
-            .unwrap();
+            .map_err(|e| vec![e.to_string()])?;
 
     // println!("entry: {}", ins.entry);
     // for i in ins.instructions {
     // println!("{:?}", i);
     // }
     let encoded = ins
-        .instructions
+        .words
         .iter()
-        .map(|x| u32::try_from(*x).unwrap().to_le_bytes())
+        .map(|w| match *w {
+            Word::Ins(x) => u32::try_from(x).unwrap().to_le_bytes(),
+            Word::Data(x) => x.to_le_bytes(),
+        })
         .collect::<Vec<_>>()
         .concat();
-    let mut stdout = stdout().lock();
-    stdout.write_all(&encoded).unwrap();
+
+    let mut debug = DebugInfo::new(filename);
+    for file in &binding.files()[1..] {
+        debug.add_file(file);
+    }
+    for &(addr, loc, expanded_from) in &ins.locs {
+        debug.add_line(LineEntry {
+            addr,
+            loc,
+            expanded_from,
+        });
+    }
+    for sym in binding.symbols() {
+        if let (SymbolKind::Label, Some(addr)) =
+            (sym.r#type, sym.value)
+        {
+            debug.add_symbol(addr, sym.name);
+        }
+    }
+    let symbols = listing::layout(
+        binding.symbols().filter(|s| {
+            matches!(
+                s.r#type,
+                SymbolKind::Label
+                    | SymbolKind::Section
+                    | SymbolKind::Constant
+            )
+        }),
+        encoded.len() as u32,
+    );
+
+    let names = |directive: &str| {
+        let bodies = binding
+            .get_id(directive)
+            .and_then(|id| directives.get(&id))
+            .into_iter()
+            .flatten();
+        bodies
+            .flat_map(|d| match &d.body {
+                DirectiveBody::Generic { body } => body.as_slice(),
+                DirectiveBody::Macro { .. } => &[],
+            })
+            .filter_map(|t| match t.kind {
+                TokensKind::Label(i) => binding.get_symbol(&i),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let entry = names(".entry").first().map(|s| s.name.into());
+    let globals = names(".global");
+    if let Some(s) = globals.iter().find(|s| s.value.is_none()) {
+        return Err(vec![format!(
+            "global '{}' at line {} is never defined",
+            s.name, s.line
+        )]);
+    }
+    let globals = globals.iter().map(|s| s.name.into()).collect();
+    let externs = binding
+        .symbols()
+        .filter(|s| {
+            s.r#type == SymbolKind::Extern && s.value.is_none()
+        })
+        .map(|s| s.name.into())
+        .collect();
+
+    Ok(Assembly {
+        code: encoded,
+        debug,
+        symbols,
+        entry,
+        globals,
+        externs,
+        relocs: ins.relocs,
+    })
+}
+
+/// The tokens `.include` or `.incbin` at `tok` stands for, given
+/// `args` after it: the file's, or its bytes as data words.
+/// `included_by` has the file including each one, to catch cycles.
+fn include(
+    directive: Directives,
+    tok: &Token,
+    args: &[Token],
+    symbol_table: &mut SymbolTable<'static>,
+    options: &Options,
+    included_by: &mut HashMap<u32, u32>,
+) -> Result<Vec<Token>, String> {
+    let (name, args) = match args.split_first() {
+        Some((
+            Token {
+                kind: TokensKind::Str(id),
+                ..
+            },
+            args,
+        )) => {
+            let name = symbol_table.get_symbol(id).unwrap().name;
+            (name.trim_matches('"').to_string(), args)
+        }
+        _ => return Err("expected a quoted file name".to_string()),
+    };
+    let including = Path::new(symbol_table.file(tok.file)).parent();
+    let path = find_include(&name, including, options)
+        .ok_or_else(|| format!("can't find '{name}'"))?;
+
+    if directive == Directives::Incbin {
+        let bytes = fs::read(&path)
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        // optional `, offset[, len]`, numbers or constants
+        let mut values = Vec::new();
+        for pair in args.chunks(2) {
+            let value = match pair {
+                [Token {
+                    kind: TokensKind::Comma,
+                    ..
+                }, value] => match value.kind {
+                    TokensKind::Imm(n) => Some(n),
+                    _ => constant(symbol_table, value),
+                },
+                _ => None,
+            };
+            match value.and_then(|n| usize::try_from(n).ok()) {
+                Some(n) if values.len() < 2 => values.push(n),
+                _ => {
+                    return Err(
+                        "expected an offset or length".to_string()
+                    )
+                }
+            }
+        }
+        let offset = values.first().copied().unwrap_or(0);
+        let len = values
+            .get(1)
+            .copied()
+            .unwrap_or(bytes.len().saturating_sub(offset));
+        let Some(blob) = offset
+            .checked_add(len)
+            .and_then(|end| bytes.get(offset..end))
+        else {
+            return Err(format!(
+                "{} has {} bytes, wanted {len} at {offset}",
+                path.display(),
+                bytes.len()
+            ));
+        };
+        // padded out to whole words
+        return Ok(blob
+            .chunks(4)
+            .map(|chunk| {
+                let mut word = [0; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                Token {
+                    kind: TokensKind::Data(u32::from_le_bytes(word)),
+                    ..*tok
+                }
+            })
+            .collect());
+    }
+    if !args.is_empty() {
+        return Err("expected only a file name".to_string());
+    }
+
+    // the files open around `tok`, outermost first
+    let mut open = vec![tok.file];
+    while let Some(&by) = included_by.get(open.last().unwrap()) {
+        open.push(by);
+    }
+    open.reverse();
+    let canonical = |p: &Path| {
+        fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf())
+    };
+    let target = canonical(&path);
+    if open.iter().any(|&f| {
+        canonical(Path::new(symbol_table.file(f))) == target
+    }) {
+        return Err(format!(
+            "'{name}' includes itself through {}",
+            open.iter()
+                .map(|&f| symbol_table.file(f))
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        ));
+    }
+    let source = fs::read_to_string(&path)
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    let file = symbol_table.add_file(&path.to_string_lossy());
+    included_by.insert(file, tok.file);
+    let mut out =
+        tokenize(&source, symbol_table, file).collect::<Vec<_>>();
+    // keep the last included line apart from what follows
+    out.push(Token {
+        kind: TokensKind::Newline,
+        ..*tok
+    });
+    Ok(out)
+}
+
+/// `name` next to the including file, or in the first include
+/// directory having it.
+fn find_include(
+    name: &str,
+    including: Option<&Path>,
+    options: &Options,
+) -> Option<PathBuf> {
+    let name = Path::new(name);
+    if name.is_absolute() {
+        return name.exists().then(|| name.to_path_buf());
+    }
+    including
+        .into_iter()
+        .chain(options.include_dirs.iter().map(|d| d.as_path()))
+        .map(|dir| dir.join(name))
+        .find(|p| p.exists())
+}
+
+/// `file:line:column` of `tok`, and of the macro call it came from.
+fn at(symbol_table: &SymbolTable, tok: &Token) -> String {
+    let loc = |loc: Loc| {
+        format!(
+            "{}:{}:{}",
+            symbol_table.file(loc.file),
+            loc.line,
+            loc.column
+        )
+    };
+    match tok.expanded_from.filter(|call| *call != tok.loc()) {
+        Some(call) => {
+            format!(
+                "{} (expanded from {})",
+                loc(tok.loc()),
+                loc(call)
+            )
+        }
+        None => loc(tok.loc()),
+    }
 }
 
 fn second_pass(
@@ -74,65 +366,95 @@ fn second_pass(
     symbol_table: &mut SymbolTable,
 ) -> Result<ResolvedTokens, Box<dyn std::error::Error>> {
     let mut tokens = tokens.peekable();
-    let entry = if let DirectiveBody::Generic { body } = &directives
-        .get(&symbol_table.get_id(".entry").unwrap())
-        .unwrap()[0]
-        .body
-    {
-        if let TokensKind::Label(i) = body[0].kind {
-            symbol_table
-                .get_symbol(&i)
-                .unwrap()
-                .value
-                .unwrap_or_default()
+    let entry_body = symbol_table
+        .get_id(".entry")
+        .and_then(|id| directives.get(&id))
+        .map(|d| &d[0].body);
+    let entry =
+        if let Some(DirectiveBody::Generic { body }) = entry_body {
+            if let TokensKind::Label(i) = body[0].kind {
+                symbol_table
+                    .get_symbol(&i)
+                    .unwrap()
+                    .value
+                    .unwrap_or_default()
+            } else {
+                0
+            }
         } else {
             0
-        }
-    } else {
-        0
-    } as i32;
-    let mut ins_vec = Vec::new();
-
-    let mut index = 0 - entry;
-
-    while tokens.peek().is_some() {
-        let cur = tokens.next().unwrap();
+        } as i32;
+    let mut ins_vec = Vec::<Word>::new();
+    // source of each instruction and the macro call it came from
+    let mut locs = Vec::new();
+    let mut relocs = Vec::new();
 
+    while let Some(cur) = tokens.next() {
         use TokensKind::*;
+        let t = &mut tokens;
         match cur.kind {
             Mnemonic(i) => {
-                index += 1;
                 let ins = match i {
                     Op::Nop => Instruction::Nop,
+                    Op::Eret => Instruction::Eret,
+                    Op::Cli => Instruction::Cli,
+                    Op::Sti => Instruction::Sti,
+                    Op::TlbFlush => Instruction::TlbFlush,
+                    Op::Halt => Instruction::Halt,
 
-                    Op::Add | Op::Sub | Op::Mul | Op::Div => {
-                        let o1 =
-                            tokens.next().unwrap().kind.get_reg()?;
-
-                        assert_eq!(
-                            tokens.next().map(|t| t.kind),
-                            Some(TokensKind::Comma),
-                            "expected a fucking comma",
-                        );
-
-                        let o2 =
-                            tokens.next().unwrap().kind.get_reg()?;
-
-                        assert_eq!(
-                            tokens.next().map(|t| t.kind),
-                            Some(TokensKind::Comma),
-                            "expected a fucking comma",
-                        );
-
-                        let o3 = {
-                            let tok = tokens.next().unwrap();
-                            match tok.kind.get_reg() {
-                                Ok(r) => Operand::Reg(r),
-                                Err(_) => match tok.kind.get_imm() {
-                                    Ok(i) => Operand::Imm(i as u32),
-                                    Err(i) => return Err(i),
-                                },
+                    Op::Mrs | Op::Msr => {
+                        let o1 = operand(t, &cur, symbol_table)?;
+                        comma(t, &cur, symbol_table)?;
+                        let o2 = operand(t, &cur, symbol_table)?;
+                        match i {
+                            Op::Mrs => Instruction::Mrs(
+                                register(&o1, symbol_table)?,
+                                special(&o2, symbol_table)?,
+                            ),
+                            _ => Instruction::Msr(
+                                special(&o1, symbol_table)?,
+                                register(&o2, symbol_table)?,
+                            ),
+                        }
+                    }
+
+                    Op::Svc => {
+                        let tok = operand(t, &cur, symbol_table)?;
+                        match tok.kind {
+                            Imm(n) if (n as u32) < 1 << 24 => {
+                                Instruction::Svc(n as u32)
                             }
+                            _ => {
+                                return Err(format!(
+                                    "{}: expected a 24 bit immediate",
+                                    at(symbol_table, &tok)
+                                )
+                                .into())
+                            }
+                        }
+                    }
+
+                    Op::Add | Op::Sub | Op::Mul | Op::Div => {
+                        let o1 = operand(t, &cur, symbol_table)?;
+                        let o1 = register(&o1, symbol_table)?;
+                        comma(t, &cur, symbol_table)?;
+                        let o2 = operand(t, &cur, symbol_table)?;
+                        let o2 = register(&o2, symbol_table)?;
+                        comma(t, &cur, symbol_table)?;
+
+                        let tok = operand(t, &cur, symbol_table)?;
+                        let o3 = match tok.kind {
+                            // the end of an `la`
+                            Lo => Operand::Imm(
+                                label_offset(
+                                    symbol_table,
+                                    operand(t, &cur, symbol_table)?,
+                                    ins_vec.len() as u32 * 4,
+                                    RelocKind::Lo13,
+                                    &mut relocs,
+                                )? & 0x1fff,
+                            ),
+                            _ => reg_or_imm(&tok, 14, symbol_table)?,
                         };
                         match i {
                             Op::Add => Instruction::Add(o1, o2, o3),
@@ -143,168 +465,471 @@ fn second_pass(
                         }
                     }
 
-                    Op::Ldr => {
-                        let o1 =
-                            tokens.next().unwrap().kind.get_reg()?;
-
-                        assert_eq!(
-                            tokens.next().map(|t| t.kind),
-                            Some(TokensKind::Comma),
-                            "expected a fucking comma",
-                        );
-
-                        let o2 = {
-                            let tok = tokens.next().unwrap();
-                            match tok.kind.get_reg() {
-                                Ok(r) => Operand::Reg(r),
-                                Err(_) => match tok.kind.get_imm() {
-                                    Ok(i) => Operand::Imm(i as u32),
-                                    Err(i) => return Err(i),
-                                },
-                            }
+                    Op::Ldr | Op::Cmp => {
+                        let o1 = operand(t, &cur, symbol_table)?;
+                        let o1 = register(&o1, symbol_table)?;
+                        comma(t, &cur, symbol_table)?;
+
+                        let tok = operand(t, &cur, symbol_table)?;
+                        let o2 = match tok.kind {
+                            // the start of an `la`
+                            Hi => Operand::Imm(
+                                label_offset(
+                                    symbol_table,
+                                    operand(t, &cur, symbol_table)?,
+                                    ins_vec.len() as u32 * 4,
+                                    RelocKind::Hi19,
+                                    &mut relocs,
+                                )? >> 13,
+                            ),
+                            _ => reg_or_imm(&tok, 19, symbol_table)?,
                         };
-                        Instruction::Ldr(o1, o2)
+                        match i {
+                            Op::Ldr => Instruction::Ldr(o1, o2),
+                            _ => Instruction::Cmp(o1, o2),
+                        }
                     }
 
-                    Op::Push | Op::Pop => {
-                        let o = {
-                            let tok = tokens.next().unwrap();
-                            match tok.kind.get_reg() {
-                                Ok(r) => Operand::Reg(r),
-                                Err(_) => match tok.kind.get_imm() {
-                                    Ok(i) => Operand::Imm(i as u32),
-                                    Err(i) => return Err(i),
-                                },
-                            }
+                    Op::B => unreachable!("lexed as Branch"),
+                    Op::Ret => Instruction::Ret,
+                    Op::Call => {
+                        let tok = operand(t, &cur, symbol_table)?;
+                        let o = match tok.kind {
+                            Label(_) => Operand::Imm(pc_offset(
+                                label_offset(
+                                    symbol_table,
+                                    tok,
+                                    ins_vec.len() as u32 * 4,
+                                    RelocKind::Call24,
+                                    &mut relocs,
+                                )?,
+                                24,
+                                &tok,
+                                symbol_table,
+                            )?),
+                            Imm(i) => Operand::Imm(pc_offset(
+                                i as u32,
+                                24,
+                                &tok,
+                                symbol_table,
+                            )?),
+                            _ => reg_or_imm(&tok, 24, symbol_table)?,
                         };
+                        Instruction::Call(o)
+                    }
+
+                    Op::Push | Op::Pop | Op::Exit => {
+                        let tok = operand(t, &cur, symbol_table)?;
+                        let o = reg_or_imm(&tok, 24, symbol_table)?;
                         match i {
                             Op::Push => Instruction::Push(o),
                             Op::Pop => Instruction::Pop(o),
+                            Op::Exit => Instruction::Exit(o),
                             _ => unreachable!(),
                         }
                     }
                 };
-                ins_vec.push(ins);
+                locs.push((
+                    ins_vec.len() as u32 * 4,
+                    cur.loc(),
+                    cur.expanded_from,
+                ));
+                ins_vec.push(Word::Ins(ins));
+            }
+            Branch(cond) => {
+                let tok = operand(t, &cur, symbol_table)?;
+                let pc = ins_vec.len() as u32 * 4;
+                let offset = match tok.kind {
+                    Imm(i) => i as u32,
+                    _ => label_offset(
+                        symbol_table,
+                        tok,
+                        pc,
+                        RelocKind::Branch20,
+                        &mut relocs,
+                    )?,
+                };
+                let offset =
+                    pc_offset(offset, 20, &tok, symbol_table)?;
+                locs.push((pc, cur.loc(), cur.expanded_from));
+                ins_vec.push(Word::Ins(Instruction::B(cond, offset)));
             }
             Label(i) => {
                 let is_decl = tokens
                     .peek()
                     .is_some_and(|t| t.kind == TokensKind::Semi);
                 if is_decl {
-                    symbol_table.update(i, |s| {
-                        s.value = Some(index as u32);
-                    });
-
+                    // address already assigned in the first pass
                     // consume semi
                     tokens.next();
                 } else {
-                    let macro_decl = directives
-                        .get(&symbol_table.get_id(".macro").unwrap())
-                        .unwrap()
-                        .iter()
-                        .find(|x| match x.body {
-                            DirectiveBody::Macro { name, .. } => {
-                                name.kind.get_sym().unwrap() == i
-                            }
-                            _ => false,
-                        })
-                        .unwrap();
-
-                    if let DirectiveBody::Macro {
-                        parameters,
-                        body,
-                        ..
-                    } = &macro_decl.body
-                    {
-                        let mut callees = vec![];
-                        (0..parameters.len()).for_each(|_| {
-                            if tokens.peek().is_some() {
-                                callees.push(tokens.next().unwrap());
-                            }
-                        });
-                        let mut part_resolved = Vec::new();
-
-                        for tok in body {
-                            if tok.kind.is_param() {
-                                let idx = tok.kind.get_param()?;
-                                part_resolved.push(callees[idx]);
-                            } else {
-                                part_resolved.push(*tok);
-                            }
-                        }
-
-                        let inner_ins = second_pass(
-                            part_resolved.into_iter(),
-                            directives,
-                            symbol_table,
-                        )?;
-                        ins_vec.extend_from_slice(
-                            &inner_ins.instructions,
-                        );
-                    }
+                    // macro calls are expanded in the first pass
+                    let name =
+                        symbol_table.get_symbol(&i).unwrap().name;
+                    return Err(format!(
+                        "{}: unknown instruction '{name}'",
+                        at(symbol_table, &cur)
+                    )
+                    .into());
                 }
             }
-            Directive(_i) => {
-                // let macro_ = &directives.get(&i).unwrap()[0];
-                // let sym = symbol_table.get_symbol(&i).unwrap();
-                // let kind = sym.name.parse::<Directives>()?;
-
-                todo!()
-            }
+            Data(word) => ins_vec.push(Word::Data(word)),
             Newline | Comment | Semi => continue,
-            x => todo!("{x:?}"),
+            x => {
+                return Err(format!(
+                    "{}: unexpected {x:?}",
+                    at(symbol_table, &cur)
+                )
+                .into())
+            }
         }
     }
 
     Ok(ResolvedTokens {
         entry,
-        instructions: ins_vec,
+        words: ins_vec,
+        locs,
+        relocs,
+    })
+}
+
+/// The next operand of the instruction at `cur`.
+fn operand(
+    tokens: &mut Peekable<impl Iterator<Item = Token>>,
+    cur: &Token,
+    symbol_table: &SymbolTable,
+) -> Result<Token, Box<dyn std::error::Error>> {
+    tokens
+        .next_if(|t| {
+            !matches!(
+                t.kind,
+                TokensKind::Newline | TokensKind::Comment
+            )
+        })
+        .ok_or_else(|| {
+            format!("{}: missing operand", at(symbol_table, cur))
+                .into()
+        })
+}
+
+/// The `,` between two operands of the instruction at `cur`.
+fn comma(
+    tokens: &mut Peekable<impl Iterator<Item = Token>>,
+    cur: &Token,
+    symbol_table: &SymbolTable,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match tokens.next_if(|t| t.kind == TokensKind::Comma) {
+        Some(_) => Ok(()),
+        None => {
+            Err(format!("{}: expected ','", at(symbol_table, cur))
+                .into())
+        }
+    }
+}
+
+fn register(
+    tok: &Token,
+    symbol_table: &SymbolTable,
+) -> Result<Register, Box<dyn std::error::Error>> {
+    tok.kind.get_reg().map_err(|_| {
+        format!("{}: expected a register", at(symbol_table, tok))
+            .into()
+    })
+}
+
+fn special(
+    tok: &Token,
+    symbol_table: &SymbolTable,
+) -> Result<SpecialRegister, Box<dyn std::error::Error>> {
+    tok.kind.get_special().map_err(|_| {
+        format!(
+            "{}: expected a special register",
+            at(symbol_table, tok)
+        )
+        .into()
     })
 }
 
+/// A register, or an immediate that fits the instruction's `bits`
+/// wide field.
+fn reg_or_imm(
+    tok: &Token,
+    bits: u32,
+    symbol_table: &SymbolTable,
+) -> Result<Operand, Box<dyn std::error::Error>> {
+    match tok.kind {
+        TokensKind::Register(r) => Ok(Operand::Reg(r)),
+        TokensKind::Imm(i) if (i as u32) < 1 << bits => {
+            Ok(Operand::Imm(i as u32))
+        }
+        TokensKind::Imm(_) => Err(format!(
+            "{}: immediate doesn't fit in {bits} bits, load it into \
+             a register with li",
+            at(symbol_table, tok)
+        )
+        .into()),
+        _ => Err(format!(
+            "{}: expected a register or an immediate",
+            at(symbol_table, tok)
+        )
+        .into()),
+    }
+}
+
+/// `offset`, a pc relative branch or call to `tok`, if it fits in
+/// `bits` signed bits.
+fn pc_offset(
+    offset: u32,
+    bits: u32,
+    tok: &Token,
+    symbol_table: &SymbolTable,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let reach = 1 << (bits - 1);
+    if (-reach..reach).contains(&(offset as i32)) {
+        Ok(offset)
+    } else {
+        Err(format!(
+            "{}: target out of range, {} bytes away",
+            at(symbol_table, tok),
+            offset as i32
+        )
+        .into())
+    }
+}
+
+/// Byte offset from `pc` to the label in `tok`, as encoded by
+/// pc relative branches, or its address for an absolute `kind`. 0
+/// for an `.extern` the linker fills in. Either way the reference
+/// goes into `relocs`.
+fn label_offset(
+    symbol_table: &SymbolTable,
+    tok: Token,
+    pc: u32,
+    kind: RelocKind,
+    relocs: &mut Vec<Reloc>,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let sym =
+        symbol_table.get_symbol(&tok.kind.get_sym()?).ok_or_else(
+            || format!("{}: unknown label", at(symbol_table, &tok)),
+        )?;
+    relocs.push(Reloc {
+        offset: pc,
+        kind,
+        symbol: sym.name.into(),
+    });
+    match (sym.value, sym.r#type) {
+        (Some(target), _) if kind.is_absolute() => Ok(target),
+        (Some(target), _) => Ok(target.wrapping_sub(pc)),
+        (None, SymbolKind::Extern) => Ok(0),
+        _ => Err(format!(
+            "{}: undefined label '{}'",
+            at(symbol_table, &tok),
+            sym.name
+        )
+        .into()),
+    }
+}
+
 fn first_pass(
     tokens: impl Iterator<Item = Token>,
-    symbol_table: &mut SymbolTable,
+    symbol_table: &mut SymbolTable<'static>,
     directives: &mut HashMap<SymbolId, Vec<Macros>>,
-) -> Result<Vec<Token>, Vec<Token>> {
-    let mut tokens = tokens.peekable();
+    options: &Options,
+) -> Result<Vec<Token>, Vec<String>> {
+    // macro expansions go back on the front
+    let mut tokens = tokens.collect::<VecDeque<_>>();
 
     let mut resolved_tokens = Vec::<Token>::new();
-    let mut errors = Vec::<Token>::new();
+    let mut errors = Vec::<String>::new();
+    let mut conditions = Conditions::default();
 
     // index from start of file
     let mut index = 0;
+    // numbers `\@`
+    let mut expansions = 0;
+    let mut labels = Labels::default();
+    let mut line_start = true;
+    // the file each included one came from
+    let mut included_by = HashMap::new();
 
-    while tokens.peek().is_some() {
-        let cur = tokens.next().unwrap();
+    while let Some(cur) = tokens.pop_front() {
+        let starts_statement = line_start;
+        line_start = matches!(
+            cur.kind,
+            TokensKind::Newline | TokensKind::Semi
+        );
+        let directive = match cur.kind {
+            TokensKind::Directive(e) => symbol_table
+                .get_symbol(&e)
+                .and_then(|s| s.name.parse::<Directives>().ok()),
+            _ => None,
+        };
+        if let Some(
+            d @ (Directives::If
+            | Directives::Ifdef
+            | Directives::Ifndef
+            | Directives::Else
+            | Directives::Endif),
+        ) = directive
+        {
+            let args = macros::take_line(&mut tokens);
+            if let Err(e) =
+                conditions.directive(d, cur, &args, symbol_table)
+            {
+                errors.push(e);
+            }
+            continue;
+        }
+        if !conditions.active() {
+            continue;
+        }
 
         match cur.kind {
-            TokensKind::Mnemonic(_) => {
+            TokensKind::Mnemonic(_)
+            | TokensKind::Branch(_)
+            | TokensKind::Data(_) => {
                 index += 4;
                 resolved_tokens.push(cur);
             }
             TokensKind::Label(i) => {
-                symbol_table.update(i, |s| s.value = Some(index));
-                resolved_tokens.push(cur);
+                let is_decl = tokens
+                    .front()
+                    .is_some_and(|t| t.kind == TokensKind::Semi);
+                if is_decl {
+                    // first seen might have been a use
+                    symbol_table.update(i, |s| {
+                        s.r#type = SymbolKind::Label;
+                        s.value = Some(index);
+                        s.line = cur.line;
+                    });
+                    // a macro's labels don't take over the locals of
+                    // the code calling it
+                    if cur.expanded_from.is_none() {
+                        labels.global(i, symbol_table);
+                    }
+                    resolved_tokens.push(cur);
+                    continue;
+                }
+                if let Some((params, body)) =
+                    macros::find(directives, symbol_table, i)
+                {
+                    let args = macros::take_line(&mut tokens);
+                    let at = at(symbol_table, &cur);
+                    if cur.depth >= macros::MAX_DEPTH {
+                        errors.push(format!(
+                            "{at}: macros nested more than {} deep",
+                            macros::MAX_DEPTH
+                        ));
+                        continue;
+                    }
+                    expansions += 1;
+                    let expanded = macros::bind(
+                        params,
+                        macros::args(&args),
+                        symbol_table,
+                    )
+                    .and_then(|values| {
+                        macros::substitute(
+                            body,
+                            params,
+                            &values,
+                            Some(expansions),
+                            symbol_table,
+                        )
+                    });
+                    let name =
+                        symbol_table.get_symbol(&i).unwrap().name;
+                    match expanded {
+                        Ok(body) => {
+                            // the outermost call is the one in the
+                            // source
+                            let from =
+                                cur.expanded_from.or(Some(cur.loc()));
+                            for tok in body.into_iter().rev() {
+                                tokens.push_front(Token {
+                                    expanded_from: from,
+                                    depth: cur.depth + 1,
+                                    ..tok
+                                });
+                            }
+                        }
+                        Err(e) => errors.push(format!(
+                            "{at}: macro '{name}' {e}"
+                        )),
+                    }
+                    continue;
+                }
+                let name = symbol_table.get_symbol(&i).unwrap().name;
+                if name.starts_with('\\') {
+                    errors.push(format!(
+                        "{}: '{name}' isn't a parameter here",
+                        at(symbol_table, &cur)
+                    ));
+                    continue;
+                }
+                // constants stand in for their value
+                match constant(symbol_table, &cur) {
+                    Some(value) => resolved_tokens.push(Token {
+                        kind: TokensKind::Imm(value),
+                        ..cur
+                    }),
+                    None => resolved_tokens.push(cur),
+                }
             }
             TokensKind::Directive(e) => {
-                let macro_ = symbol_table
-                    .get_symbol(&e)
-                    .map(|s| {
-                        s.name
-                            .parse::<directives::Directives>()
-                            .expect("assemble error")
-                    })
-                    .unwrap();
+                let Some(macro_) = directive else {
+                    let name =
+                        symbol_table.get_symbol(&e).unwrap().name;
+                    errors.push(format!(
+                        "{}: unknown directive '{name}'",
+                        at(symbol_table, &cur)
+                    ));
+                    macros::take_line(&mut tokens);
+                    continue;
+                };
                 match macro_ {
-                    Directives::Entry | Directives::Section => {
-                        let mut body = Vec::new();
-                        while tokens.peek().is_some_and(|t| {
-                            t.kind != TokensKind::Newline
-                        }) {
-                            body.push(tokens.next().unwrap());
+                    Directives::Equ => {
+                        if let Err(e) = define(
+                            &mut tokens,
+                            symbol_table,
+                            cur.line,
+                        ) {
+                            errors.push(format!(
+                                "{}: {e}",
+                                at(symbol_table, &cur)
+                            ));
+                        }
+                    }
+                    Directives::Entry
+                    | Directives::Section
+                    | Directives::Global
+                    | Directives::Extern => {
+                        let mut body = macros::take_line(&mut tokens);
+                        body.extend(tokens.pop_front());
+                        if let (Directives::Section, Some(name)) =
+                            (macro_, body.first())
+                        {
+                            if let TokensKind::Label(id) = name.kind {
+                                symbol_table.update(id, |s| {
+                                    s.r#type = SymbolKind::Section;
+                                    s.value = Some(index);
+                                    s.line = name.line;
+                                });
+                            }
+                        }
+                        if macro_ == Directives::Extern {
+                            for t in &body {
+                                if let TokensKind::Label(id) = t.kind
+                                {
+                                    symbol_table.update(id, |s| {
+                                        if s.value.is_none() {
+                                            s.r#type =
+                                                SymbolKind::Extern
+                                        }
+                                    });
+                                }
+                            }
                         }
-                        body.push(tokens.next().unwrap());
                         let dot_macro = Macros {
                             name: e,
                             body: DirectiveBody::Generic { body },
@@ -319,65 +944,239 @@ fn first_pass(
                         }
                     }
                     Directives::MacroStart => {
-                        let name = tokens.next().unwrap();
-                        let mut params = Vec::new();
-                        let mut body = Vec::new();
-                        while tokens.peek().is_some_and(|t| {
-                            t.kind != TokensKind::Newline
-                        }) {
-                            params.push(tokens.next().unwrap());
-                        }
-                        // skip newline
-                        assert_eq!(
-                            tokens.next().map(|t| t.kind),
-                            Some(TokensKind::Newline)
-                        );
-                        while tokens.peek().is_some_and(|t| {
-                            if let TokensKind::Directive(i) = t.kind {
-                                symbol_table.get_symbol(&i).map(|x| {
-                                    x.name.parse::<Directives>().map(
-                                        |x| x != Directives::MacroEnd,
-                                    ).unwrap()
-                                }).unwrap()
-                            } else {
-                                true
-                            }
-                        }) {
-                            body.push(tokens.next().unwrap());
-                        }
-                        tokens.next();
-                        while tokens.peek().is_some_and(|x| {
+                        let at = at(symbol_table, &cur);
+                        let line = macros::take_line(&mut tokens);
+                        let Some(body) = macros::take_block(
+                            &mut tokens,
+                            symbol_table,
+                            Directives::MacroEnd,
+                        ) else {
+                            errors.push(format!(
+                                "{at}: .macro without .endmacro"
+                            ));
+                            continue;
+                        };
+                        while tokens.front().is_some_and(|x| {
                             x.kind == TokensKind::Newline
                         }) {
-                            tokens.next();
+                            tokens.pop_front();
                         }
+                        let (name, parameters) = match line
+                            .split_first()
+                        {
+                            Some((
+                                name @ Token {
+                                    kind: TokensKind::Label(_),
+                                    ..
+                                },
+                                params,
+                            )) => (*name, macros::params(params)),
+                            _ => {
+                                (cur, Err("needs a name".to_string()))
+                            }
+                        };
+                        let parameters = match parameters {
+                            Ok(p) => p,
+                            Err(e) => {
+                                errors.push(format!(
+                                    "{at}: .macro {e}"
+                                ));
+                                continue;
+                            }
+                        };
                         let dot_macro = Macros {
                             name: e,
                             body: DirectiveBody::Macro {
                                 name,
-                                parameters: params,
+                                parameters,
                                 body,
                             },
                         };
-                        if let std::collections::hash_map::Entry::Vacant(e) = directives.entry(e) {
-                            e.insert(vec![dot_macro]);
-                        } else {
-                            directives
-                                .get_mut(&e)
-                                .unwrap()
-                                .push(dot_macro);
+                        directives
+                            .entry(e)
+                            .or_default()
+                            .push(dot_macro);
+                    }
+                    Directives::Include | Directives::Incbin => {
+                        let args = macros::take_line(&mut tokens);
+                        match include(
+                            macro_,
+                            &cur,
+                            &args,
+                            symbol_table,
+                            options,
+                            &mut included_by,
+                        ) {
+                            Ok(included) => {
+                                for tok in included.into_iter().rev()
+                                {
+                                    tokens.push_front(tok);
+                                }
+                            }
+                            Err(e) => errors.push(format!(
+                                "{}: {e}",
+                                at(symbol_table, &cur)
+                            )),
                         }
                     }
-                    _ => unreachable!(),
+                    Directives::Rept | Directives::Irp => {
+                        let at = at(symbol_table, &cur);
+                        let args = macros::take_line(&mut tokens);
+                        let Some(body) = macros::take_block(
+                            &mut tokens,
+                            symbol_table,
+                            Directives::Endr,
+                        ) else {
+                            errors.push(format!(
+                                "{at}: {} without .endr",
+                                symbol_table
+                                    .get_symbol(&e)
+                                    .unwrap()
+                                    .name
+                            ));
+                            continue;
+                        };
+                        let copies = match macro_ {
+                            Directives::Rept => {
+                                repeat(&args, &body, symbol_table)
+                            }
+                            _ => iterate(&args, &body, symbol_table),
+                        };
+                        match copies {
+                            Ok(copies) => {
+                                for tok in copies.into_iter().rev() {
+                                    tokens.push_front(tok);
+                                }
+                            }
+                            Err(e) => {
+                                errors.push(format!("{at}: {e}"))
+                            }
+                        }
+                    }
+                    _ => {
+                        let name =
+                            symbol_table.get_symbol(&e).unwrap().name;
+                        errors.push(format!(
+                            "{}: unexpected {name}",
+                            at(symbol_table, &cur)
+                        ));
+                    }
+                }
+            }
+            TokensKind::Local(i) => {
+                let is_decl = tokens
+                    .front()
+                    .is_some_and(|t| t.kind == TokensKind::Semi);
+                let name = symbol_table
+                    .get_symbol(&i)
+                    .unwrap()
+                    .name
+                    .to_string();
+                if !is_decl && starts_statement {
+                    errors.push(format!(
+                        "{}: unknown directive '{name}'",
+                        at(symbol_table, &cur)
+                    ));
+                    macros::take_line(&mut tokens);
+                    continue;
+                }
+                let addr = is_decl.then_some(index);
+                match labels.local(&cur, &name, addr, symbol_table) {
+                    Ok(id) => resolved_tokens.push(Token {
+                        kind: TokensKind::Label(id),
+                        ..cur
+                    }),
+                    Err(e) => errors.push(format!(
+                        "{}: {e}",
+                        at(symbol_table, &cur)
+                    )),
+                }
+            }
+            TokensKind::Imm(n)
+                if starts_statement
+                    && tokens.front().is_some_and(|t| {
+                        t.kind == TokensKind::Semi
+                    }) =>
+            {
+                let id = labels.anonymous(
+                    &cur,
+                    n as u32,
+                    index,
+                    symbol_table,
+                );
+                resolved_tokens.push(Token {
+                    kind: TokensKind::Label(id),
+                    ..cur
+                });
+            }
+            TokensKind::AnonBack(n) | TokensKind::AnonFwd(n) => {
+                let forward =
+                    matches!(cur.kind, TokensKind::AnonFwd(_));
+                match labels.reference(&cur, n, forward, symbol_table)
+                {
+                    Ok(id) => resolved_tokens.push(Token {
+                        kind: TokensKind::Label(id),
+                        ..cur
+                    }),
+                    Err(e) => errors.push(format!(
+                        "{}: {e}",
+                        at(symbol_table, &cur)
+                    )),
+                }
+            }
+            TokensKind::Pseudo(op) => {
+                let args = macros::take_line(&mut tokens);
+                match pseudo::expand(op, &args, symbol_table) {
+                    Ok(kinds) => {
+                        // listed like a macro call
+                        let from =
+                            cur.expanded_from.or(Some(cur.loc()));
+                        for kind in kinds.into_iter().rev() {
+                            tokens.push_front(Token {
+                                kind,
+                                expanded_from: from,
+                                ..cur
+                            });
+                        }
+                    }
+                    Err(e) => errors.push(format!(
+                        "{}: {e}",
+                        at(symbol_table, &cur)
+                    )),
                 }
             }
-            TokensKind::Error(_) => {
+            TokensKind::Error(i) => {
                 index += 4;
-                errors.push(cur);
+                let name = symbol_table.get_symbol(&i).unwrap().name;
+                errors.push(format!(
+                    "{}: unexpected symbol '{name}'",
+                    at(symbol_table, &cur)
+                ));
+            }
+            // only arguments of directives and macro definitions
+            TokensKind::Str(_)
+            | TokensKind::Relation(_)
+            | TokensKind::Equals => {
+                let what = match cur.kind {
+                    TokensKind::Str(id) => symbol_table
+                        .get_symbol(&id)
+                        .unwrap()
+                        .name
+                        .to_string(),
+                    TokensKind::Equals => "'='".to_string(),
+                    _ => "comparison outside .if".to_string(),
+                };
+                errors.push(format!(
+                    "{}: unexpected {what}",
+                    at(symbol_table, &cur)
+                ));
+                macros::take_line(&mut tokens);
             }
             _ => resolved_tokens.push(cur),
         }
     }
+    errors.extend(conditions.finish(symbol_table));
+    errors.extend(labels.finish(symbol_table));
 
     if errors.is_empty() {
         Ok(resolved_tokens)
@@ -386,6 +1185,117 @@ fn first_pass(
     }
 }
 
+/// `.rept count`: `body` `count` times over.
+fn repeat(
+    args: &[Token],
+    body: &[Token],
+    symbol_table: &SymbolTable,
+) -> Result<Vec<Token>, String> {
+    let count = match args {
+        [tok] => match tok.kind {
+            TokensKind::Imm(n) => Some(n),
+            _ => constant(symbol_table, tok),
+        },
+        _ => None,
+    };
+    match count {
+        Some(n) if n >= 0 => Ok(body.repeat(n as usize)),
+        _ => Err("expected `.rept count`".to_string()),
+    }
+}
+
+/// `.irp name, a, b`: `body` once for each value, `\name` standing
+/// in for it.
+fn iterate(
+    args: &[Token],
+    body: &[Token],
+    symbol_table: &mut SymbolTable,
+) -> Result<Vec<Token>, String> {
+    let Some((
+        Token {
+            kind: TokensKind::Label(name),
+            ..
+        },
+        values,
+    )) = args.split_first()
+    else {
+        return Err("expected `.irp name, values`".to_string());
+    };
+    let param = [macros::MacroParam {
+        name: Some(*name),
+        default: None,
+    }];
+    let mut out = Vec::new();
+    for value in macros::args(values).into_iter().flatten() {
+        out.extend(macros::substitute(
+            body,
+            &param,
+            &[value],
+            None,
+            symbol_table,
+        )?);
+    }
+    Ok(out)
+}
+
+/// Value of `tok` if it names a constant.
+fn constant(symbol_table: &SymbolTable, tok: &Token) -> Option<i32> {
+    let TokensKind::Label(id) = tok.kind else {
+        return None;
+    };
+    let sym = symbol_table.get_symbol(&id)?;
+    match sym.r#type {
+        SymbolKind::Constant => sym.value.map(|v| v as i32),
+        _ => None,
+    }
+}
+
+/// `.equ NAME[,] value`, the value a number or another constant.
+fn define(
+    tokens: &mut VecDeque<Token>,
+    symbol_table: &mut SymbolTable,
+    line: usize,
+) -> Result<(), String> {
+    let args = macros::take_line(tokens)
+        .iter()
+        .map(|t| t.kind)
+        .collect::<Vec<_>>();
+    let (name, value) = match args[..] {
+        [TokensKind::Label(name), value]
+        | [TokensKind::Label(name), TokensKind::Comma, value] => {
+            (name, value)
+        }
+        _ => return Err("expected `.equ NAME, value`".to_string()),
+    };
+    let value = match value {
+        TokensKind::Imm(n) => n as u32,
+        TokensKind::Label(id) => {
+            match symbol_table.get_symbol(&id).unwrap() {
+                s if s.r#type == SymbolKind::Constant => {
+                    s.value.unwrap_or_default()
+                }
+                s => {
+                    return Err(format!(
+                        "'{}' isn't a constant",
+                        s.name
+                    ))
+                }
+            }
+        }
+        _ => return Err("expected a number".to_string()),
+    };
+    let sym = symbol_table.get_symbol(&name).unwrap();
+    if sym.r#type == SymbolKind::Label && sym.value.is_some() {
+        return Err(format!("'{}' is already a label", sym.name));
+    }
+    symbol_table.update(name, |s| {
+        s.r#type = SymbolKind::Constant;
+        s.value = Some(value);
+        s.line = line;
+    });
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct Macros {
     pub name: SymbolId,
@@ -396,7 +1306,7 @@ pub struct Macros {
 pub enum DirectiveBody {
     Macro {
         name: Token,
-        parameters: Vec<Token>,
+        parameters: Vec<MacroParam>,
         body: Vec<Token>,
     },
     Generic {
@@ -404,8 +1314,464 @@ pub enum DirectiveBody {
     },
 }
 
+/// One word of output.
+#[derive(Debug, Clone, Copy)]
+pub enum Word {
+    Ins(Instruction),
+    /// Raw data from `.incbin`.
+    Data(u32),
+}
+
 #[derive(Debug, Clone)]
 pub struct ResolvedTokens {
     pub entry: i32,
-    pub instructions: Vec<Instruction>,
+    pub words: Vec<Word>,
+    /// `(address, source, macro call)` of each instruction.
+    pub locs: Vec<(u32, Loc, Option<Loc>)>,
+    pub relocs: Vec<Reloc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory holding `files`, for includes.
+    fn dir(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("jasm-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    fn build(
+        dir: &Path,
+        source: &str,
+        options: &Options,
+    ) -> Result<Assembly, Vec<String>> {
+        let main = dir.join("main.jasm");
+        try_assemble_with(&main.to_string_lossy(), source, options)
+    }
+
+    fn words(asm: &Assembly) -> Vec<u32> {
+        asm.code
+            .chunks(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn include_cycles() {
+        let d = dir(
+            "cycle",
+            &[
+                ("a.jasm", b".include \"b.jasm\"\n"),
+                ("b.jasm", b"nop\n.include \"a.jasm\"\n"),
+                ("c.jasm", b".include \"d.jasm\"\n"),
+                ("d.jasm", b"nop\n"),
+            ],
+        );
+        let errors =
+            build(&d, ".include \"a.jasm\"\n", &Options::default())
+                .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].contains("'a.jasm' includes itself through"),
+            "{errors:?}"
+        );
+
+        // the same file twice is fine, only not inside itself
+        let asm = build(
+            &d,
+            ".include \"c.jasm\"\n.include \"d.jasm\"\n",
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(words(&asm).len(), 2);
+
+        let errors =
+            build(&d, ".include \"e.jasm\"\n", &Options::default())
+                .unwrap_err();
+        assert!(errors[0].ends_with("can't find 'e.jasm'"));
+    }
+
+    #[test]
+    fn include_dirs() {
+        let lib = dir("lib", &[("lib.jasm", b"halt\n")]);
+        let d = dir("search", &[]);
+        let options = Options {
+            include_dirs: vec![lib],
+            ..Default::default()
+        };
+        let asm = build(&d, ".include \"lib.jasm\"\nnop\n", &options)
+            .unwrap();
+        assert_eq!(
+            words(&asm),
+            [Instruction::Halt, Instruction::Nop]
+                .map(|i| u32::try_from(i).unwrap())
+        );
+    }
+
+    #[test]
+    fn incbin_offset_len() {
+        let d =
+            dir("incbin", &[("font.bin", &[1, 2, 3, 4, 5, 6, 7])]);
+        let options = Options {
+            defines: vec![("FONT_LEN".into(), 3)],
+            ..Default::default()
+        };
+        let incbin = |args: &str| {
+            build(
+                &d,
+                &format!(".equ OFF, 2\n.incbin \"font.bin\"{args}\n"),
+                &options,
+            )
+            .map(|asm| words(&asm))
+        };
+        assert_eq!(incbin(""), Ok(vec![0x04030201, 0x070605]));
+        assert_eq!(incbin(", 5"), Ok(vec![0x0706]));
+        assert_eq!(incbin(", 1, 4"), Ok(vec![0x05040302]));
+        assert_eq!(incbin(", OFF, FONT_LEN"), Ok(vec![0x050403]));
+        assert_eq!(incbin(", 7"), Ok(vec![]));
+
+        for bad in [", 8", ", 2, 6", ", NOPE", ", -1", ", 1, 2, 3"] {
+            assert!(incbin(bad).is_err(), "{bad}");
+        }
+    }
+
+    /// Words of `source`, assembled with `defines`.
+    fn code(
+        source: &str,
+        defines: &[(&str, i32)],
+    ) -> Result<Vec<u32>, Vec<String>> {
+        let options = Options {
+            defines: defines
+                .iter()
+                .map(|&(name, value)| (name.into(), value))
+                .collect(),
+            ..Default::default()
+        };
+        try_assemble_with("test.jasm", source, &options)
+            .map(|asm| words(&asm))
+    }
+
+    /// The one error `source` has.
+    fn error(source: &str) -> String {
+        match code(source, &[]) {
+            Err(errors) if errors.len() == 1 => errors[0].clone(),
+            x => panic!("{source:?} gave {x:?}"),
+        }
+    }
+
+    #[test]
+    fn conditionals_nest() {
+        let source = "\
+.equ LEVEL, 2
+.ifdef DEBUG
+    .if LEVEL > 1
+        ldr r1, #1
+    .else
+        ldr r1, #2
+    .endif
+.else
+    .ifndef DEBUG
+        ldr r1, #3
+    .else
+        ldr r1, #4
+    .endif
+.endif
+";
+        let want = |n: u32| code(&format!("ldr r1, #{n}\n"), &[]);
+        assert_eq!(code(source, &[]), want(3));
+        assert_eq!(code(source, &[("DEBUG", 1)]), want(1));
+        let lower = source.replace("LEVEL, 2", "LEVEL, 1");
+        assert_eq!(code(&lower, &[("DEBUG", 1)]), want(2));
+
+        // a skipped branch's conditions aren't evaluated
+        let skipped = ".if 0\n.if NOPE\nnop\n.endif\n.endif\nhalt\n";
+        assert_eq!(code(skipped, &[]), code("halt\n", &[]));
+    }
+
+    #[test]
+    fn conditionals_unbalanced() {
+        assert!(error(".if 1\nnop\n").ends_with(".if without .endif"));
+        assert!(
+            error("nop\n.endif\n").ends_with(".endif without .if")
+        );
+        assert!(error(".else\n").ends_with(".else without .if"));
+        let twice = error(".if 1\n.else\n.else\n.endif\n");
+        assert!(
+            twice.contains("second .else for the .if at"),
+            "{twice}"
+        );
+        assert!(error(".if NOPE\n.endif\n")
+            .ends_with("'NOPE' isn't defined"));
+    }
+
+    #[test]
+    fn skipped_includes() {
+        let source = "\
+.ifdef DEBUG
+.include \"debug.jasm\"
+.incbin \"debug.bin\"
+.endif
+halt
+";
+        assert_eq!(code(source, &[]), code("halt\n", &[]));
+        assert!(code(source, &[("DEBUG", 1)]).is_err());
+    }
+
+    #[test]
+    fn unexpected_tokens() {
+        let cases = [
+            (
+                "ldr r0, #1 == 2\n",
+                "unexpected comparison outside .if",
+            ),
+            ("\"abc\"\nnop\n", "unexpected \"abc\""),
+            ("nop\n= 1\n", "unexpected '='"),
+        ];
+        for (source, want) in cases {
+            assert!(error(source).ends_with(want), "{source:?}");
+        }
+        assert!(
+            error("ldr r0, #1 #2\n").ends_with("unexpected Imm(2)")
+        );
+    }
+
+    const ADDER: &str = "\
+.macro adder dst, src=#1
+    add \\dst, \\dst, \\src
+.endmacro
+";
+
+    #[test]
+    fn macro_arguments() {
+        let calls =
+            "adder r1\nadder r2, #5\nadder r3,\nadder r4 r5\n";
+        assert_eq!(
+            code(&format!("{ADDER}{calls}"), &[]),
+            code(
+                "add r1, r1, #1\nadd r2, r2, #5\n\
+                 add r3, r3, #1\nadd r4, r4, r5\n",
+                &[]
+            )
+        );
+
+        let call =
+            |args: &str| error(&format!("{ADDER}adder {args}\n"));
+        assert!(
+            call("r1, #1, #2").ends_with("takes 2 arguments, got 3")
+        );
+        assert!(call("")
+            .ends_with("macro 'adder' missing argument 'dst'"));
+        assert!(call(", #2").ends_with("missing argument 'dst'"));
+    }
+
+    #[test]
+    fn macro_depth() {
+        let e =
+            error(".macro deeper\n    deeper\n.endmacro\ndeeper\n");
+        assert!(
+            e.ends_with(&format!(
+                "macros nested more than {} deep",
+                macros::MAX_DEPTH
+            )),
+            "{e}"
+        );
+        // the call in the source is where it went wrong
+        assert!(e.contains("(expanded from test.jasm:4:1)"), "{e}");
+    }
+
+    #[test]
+    fn macro_unique_labels() {
+        let source = "\
+.macro spin n
+    ldr r5, \\n
+.l\\@:
+    sub r5, r5, #1
+    bne .l\\@
+done\\@:
+    b done\\@
+.endmacro
+    spin #2
+    spin #3
+";
+        assert_eq!(
+            code(source, &[]),
+            code(
+                "\
+    ldr r5, #2
+l1: sub r5, r5, #1
+    bne l1
+d1: b d1
+    ldr r5, #3
+l2: sub r5, r5, #1
+    bne l2
+d2: b d2
+",
+                &[]
+            )
+        );
+    }
+
+    #[test]
+    fn local_labels() {
+        let source = "\
+copy:
+.loop:
+    bne .loop
+    b .done
+.done:
+fill:
+.loop:
+    bne .loop
+";
+        let plain = "\
+x:  bne x
+    b y
+y:
+z:  bne z
+";
+        assert_eq!(code(source, &[]), code(plain, &[]));
+
+        assert!(
+            error("f:\n    b .nope\n").ends_with("no '.nope' in 'f'")
+        );
+        let twice = error("f:\n.l:\n.l:\n    nop\n");
+        assert!(twice
+            .ends_with("'.l' in 'f' is already defined at line 2"));
+    }
+
+    #[test]
+    fn macro_labels_keep_scope() {
+        let source = "\
+.macro mark
+done\\@:
+    nop
+.endmacro
+copy:
+.loop:
+    mark
+    bne .loop
+";
+        assert_eq!(code(source, &[]), code("a:\nnop\nbne a\n", &[]));
+    }
+
+    #[test]
+    fn anonymous_labels() {
+        let source = "\
+1:  b 1f
+1:  b 1b
+    b 2f
+2:  bne 1b
+";
+        let plain = "\
+x:  b y
+y:  b y
+    b z
+z:  bne y
+";
+        assert_eq!(code(source, &[]), code(plain, &[]));
+
+        assert!(error("    b 1b\n").ends_with("no '1:' before '1b'"));
+        assert!(error("1:  b 1f\n").ends_with("no '1:' after '1f'"));
+    }
+
+    #[test]
+    fn missing_operands() {
+        let cases = [
+            ("svc", "1:1: missing operand"),
+            ("add r0, r1\n", "1:1: expected ','"),
+            ("ldr r0\n", "1:1: expected ','"),
+            ("nop\nbne\n", "2:1: missing operand"),
+            ("push ; what\n", "1:1: missing operand"),
+            ("mrs r0, r1\n", "1:9: expected a special register"),
+            ("add r0, #1, r2\n", "1:9: expected a register"),
+            ("svc r1\n", "1:5: expected a 24 bit immediate"),
+        ];
+        for (source, want) in cases {
+            let e = error(source);
+            assert!(e.ends_with(want), "{source:?} gave {e}");
+        }
+    }
+
+    #[test]
+    fn li_full_range() {
+        // the upper 19 bits, shifted by 13, plus the lower 13
+        assert_eq!(
+            code("li r0, #-5\n", &[]),
+            code(
+                "ldr r0, #0x7ffff\nmul r0, r0, #0x2000\n\
+                 add r0, r0, #0x1ffb\n",
+                &[]
+            )
+        );
+        for (imm, want) in [
+            ("#0xffffffff", u32::MAX),
+            ("#3000000000", 3_000_000_000),
+            ("#-5", -5i32 as u32),
+            ("#-0x80000000", 1 << 31),
+            ("#0x7ffff", 0x7ffff),
+        ] {
+            let asm = try_assemble_with(
+                "test.jasm",
+                &format!("li r1, {imm}\nhalt\n"),
+                &Options::default(),
+            )
+            .unwrap();
+            let mut vm = crate::vm::Machine::builder()
+                .program(&asm.code)
+                .build()
+                .unwrap();
+            vm.run(false).unwrap();
+            assert_eq!(vm[crate::register::R1], want, "{imm}");
+        }
+    }
+
+    #[test]
+    fn immediate_ranges() {
+        // the widest each field takes
+        assert!(code(
+            "add r0, r0, #0x3fff\nldr r0, #0x7ffff\n\
+             push #0xffffff\nsvc #0xffffff\n\
+             b #-0x80000\ncall #0x7ffffc\n",
+            &[]
+        )
+        .is_ok());
+
+        let cases = [
+            ("ldr r0, #99999999\n", "1:9", 19),
+            ("add r0, r0, #99999\n", "1:13", 14),
+            ("sub r0, r0, #-1\n", "1:13", 14),
+            ("cmp r0, #0x80000\n", "1:9", 19),
+            ("push #0x1000000\n", "1:6", 24),
+        ];
+        for (source, at, bits) in cases {
+            let e = error(source);
+            let want = format!(
+                "{at}: immediate doesn't fit in {bits} bits, load it \
+                 into a register with li"
+            );
+            assert!(e.ends_with(&want), "{source:?} gave {e}");
+        }
+
+        let cases = [
+            ("svc #0x1000000\n", "1:5: expected a 24 bit immediate"),
+            (
+                "b #0x80000\n",
+                "1:3: target out of range, 524288 bytes",
+            ),
+            ("call #-0x800004\n", "out of range, -8388612 bytes"),
+            ("ldr r0, #4294967296\n", "unexpected symbol"),
+            ("ldr r0, #-0x80000001\n", "unexpected symbol"),
+        ];
+        for (source, want) in cases {
+            let e = error(source);
+            assert!(e.contains(want), "{source:?} gave {e}");
+        }
+    }
 }
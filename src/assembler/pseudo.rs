@@ -0,0 +1,292 @@
+//! Pseudo-instructions, expanded by the first pass into the real
+//! instructions they stand for:
+//!
+//! ```text
+//! mov rd, rs      add rd, rs, #0
+//! neg rd[, rs]    sub rd, rd, rd; sub rd, rd, rs, or three `mul`s
+//!                 when rd is rs
+//! not rd[, rs]    neg rd, rs; sub rd, rd, #1
+//! inc rd          add rd, rd, #1
+//! dec rd          sub rd, rd, #1
+//! clr rd          sub rd, rd, rd
+//! li rd, #imm     ldr rd, #imm, or three instructions past 19 bits
+//! la rd, label    ldr rd, #hi; mul rd, rd, #8192; add rd, rd, #lo
+//! beqz rs, label  cmp rs, #0; beq label
+//! bnez rs, label  cmp rs, #0; bne label
+//! swap ra, rb     add ra, ra, rb; sub rb, ra, rb; sub ra, ra, rb
+//! ```
+//!
+//! `ret` is a real instruction.
+
+use std::str::FromStr;
+
+use super::{
+    constant,
+    lexer::{Token, TokensKind},
+    symbols::SymbolTable,
+};
+use crate::opcode::{Cond, Op};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pseudo {
+    Mov,
+    Neg,
+    Not,
+    Inc,
+    Dec,
+    Clr,
+    Li,
+    La,
+    Beqz,
+    Bnez,
+    Swap,
+}
+
+impl Pseudo {
+    fn usage(self) -> &'static str {
+        match self {
+            Pseudo::Mov => "mov rd, rs",
+            Pseudo::Neg => "neg rd[, rs]",
+            Pseudo::Not => "not rd[, rs]",
+            Pseudo::Inc => "inc rd",
+            Pseudo::Dec => "dec rd",
+            Pseudo::Clr => "clr rd",
+            Pseudo::Li => "li rd, #imm",
+            Pseudo::La => "la rd, label",
+            Pseudo::Beqz => "beqz rs, label",
+            Pseudo::Bnez => "bnez rs, label",
+            Pseudo::Swap => "swap ra, rb",
+        }
+    }
+}
+
+impl FromStr for Pseudo {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "mov" => Self::Mov,
+            "neg" => Self::Neg,
+            "not" => Self::Not,
+            "inc" => Self::Inc,
+            "dec" => Self::Dec,
+            "clr" => Self::Clr,
+            "li" => Self::Li,
+            "la" => Self::La,
+            "beqz" => Self::Beqz,
+            "bnez" => Self::Bnez,
+            "swap" => Self::Swap,
+            _ => return Err(format!("unknown pseudo {s}").into()),
+        })
+    }
+}
+
+/// The instructions `op` with operands `args` stands for, one per
+/// line.
+pub(super) fn expand(
+    op: Pseudo,
+    args: &[Token],
+    symbol_table: &SymbolTable,
+) -> Result<Vec<TokensKind>, String> {
+    use TokensKind::{
+        Comma, Imm, Label, Mnemonic, Newline, Register as R,
+    };
+
+    let operands =
+        args.iter().filter(|t| t.kind != Comma).collect::<Vec<_>>();
+    let kinds = operands.iter().map(|t| t.kind).collect::<Vec<_>>();
+
+    let mut out = Vec::new();
+    let mut ins = |head: TokensKind, operands: &[TokensKind]| {
+        out.push(head);
+        for (i, o) in operands.iter().enumerate() {
+            let after_half = i != 0
+                && matches!(
+                    operands[i - 1],
+                    TokensKind::Hi | TokensKind::Lo
+                );
+            if i != 0 && !after_half {
+                out.push(Comma);
+            }
+            out.push(*o);
+        }
+        out.push(Newline);
+    };
+    let usage = || format!("expected `{}`", op.usage());
+
+    match (op, &kinds[..]) {
+        (Pseudo::Mov, &[R(d), R(s)]) => {
+            ins(Mnemonic(Op::Add), &[R(d), R(s), Imm(0)])
+        }
+        (Pseudo::Neg | Pseudo::Not, &[R(d)] | &[R(d), R(_)]) => {
+            let s = match kinds[..] {
+                [_, R(s)] => s,
+                _ => d,
+            };
+            if d != s {
+                ins(Mnemonic(Op::Sub), &[R(d), R(d), R(d)]);
+                ins(Mnemonic(Op::Sub), &[R(d), R(d), R(s)]);
+            } else {
+                // no scratch register, so multiply by factors whose
+                // product is -1 mod 2^32
+                ins(Mnemonic(Op::Mul), &[R(d), R(s), Imm(143)]);
+                ins(Mnemonic(Op::Mul), &[R(d), R(d), Imm(7319)]);
+                ins(Mnemonic(Op::Mul), &[R(d), R(d), Imm(12311)]);
+            }
+            if op == Pseudo::Not {
+                ins(Mnemonic(Op::Sub), &[R(d), R(d), Imm(1)]);
+            }
+        }
+        (Pseudo::Inc, &[R(d)]) => {
+            ins(Mnemonic(Op::Add), &[R(d), R(d), Imm(1)])
+        }
+        (Pseudo::Dec, &[R(d)]) => {
+            ins(Mnemonic(Op::Sub), &[R(d), R(d), Imm(1)])
+        }
+        (Pseudo::Clr, &[R(d)]) => {
+            ins(Mnemonic(Op::Sub), &[R(d), R(d), R(d)])
+        }
+        (Pseudo::Li, &[R(d), value]) => {
+            let value = match value {
+                Imm(n) => n as u32,
+                Label(_) => constant(symbol_table, operands[1])
+                    .ok_or("li takes a number, la an address")?
+                    as u32,
+                _ => return Err(usage()),
+            };
+            if value < 1 << 19 {
+                ins(Mnemonic(Op::Ldr), &[R(d), Imm(value as i32)]);
+            } else {
+                let (hi, lo) = (value >> 13, value & 0x1fff);
+                ins(Mnemonic(Op::Ldr), &[R(d), Imm(hi as i32)]);
+                ins(Mnemonic(Op::Mul), &[R(d), R(d), Imm(0x2000)]);
+                ins(Mnemonic(Op::Add), &[R(d), R(d), Imm(lo as i32)]);
+            }
+        }
+        (
+            Pseudo::La,
+            &[R(d), label @ (Label(_)
+            | TokensKind::Local(_)
+            | TokensKind::AnonBack(_)
+            | TokensKind::AnonFwd(_))],
+        ) => {
+            ins(Mnemonic(Op::Ldr), &[R(d), TokensKind::Hi, label]);
+            ins(Mnemonic(Op::Mul), &[R(d), R(d), Imm(0x2000)]);
+            ins(
+                Mnemonic(Op::Add),
+                &[R(d), R(d), TokensKind::Lo, label],
+            );
+        }
+        (Pseudo::Beqz | Pseudo::Bnez, &[R(s), target]) => {
+            let cond = match op {
+                Pseudo::Beqz => Cond::Eq,
+                _ => Cond::Ne,
+            };
+            ins(Mnemonic(Op::Cmp), &[R(s), Imm(0)]);
+            ins(TokensKind::Branch(cond), &[target]);
+        }
+        (Pseudo::Swap, &[R(a), R(b)]) => {
+            if a != b {
+                ins(Mnemonic(Op::Add), &[R(a), R(a), R(b)]);
+                ins(Mnemonic(Op::Sub), &[R(b), R(a), R(b)]);
+                ins(Mnemonic(Op::Sub), &[R(a), R(a), R(b)]);
+            }
+        }
+        _ => return Err(usage()),
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::assemble,
+        register::*,
+        vm::{Machine, MachineBuilder},
+    };
+
+    /// The machine after running `source` up to its `halt`.
+    fn run(source: &str) -> Machine {
+        let code = assemble("test.jasm", source).code;
+        let mut m = MachineBuilder::new()
+            .ram(0x10000)
+            .program(&code)
+            .build()
+            .unwrap();
+        m.run(false).unwrap();
+        m
+    }
+
+    #[test]
+    fn arithmetic() {
+        let m = run("
+    li r1, #5
+    mov r2, r1
+    neg r1
+    neg r3, r2
+    not r4, r2
+    not r5
+    inc r2
+    dec r6
+    ldr r7, #9
+    clr r7
+    ldr r8, #1
+    swap r2, r8
+    swap r8, r8
+    halt
+");
+        assert_eq!(m[R1] as i32, -5);
+        assert_eq!(m[R3] as i32, -5);
+        assert_eq!(m[R4] as i32, -6);
+        assert_eq!(m[R5] as i32, -1);
+        assert_eq!(m[R6] as i32, -1);
+        assert_eq!(m[R7], 0);
+        assert_eq!((m[R2], m[R8]), (1, 6));
+    }
+
+    #[test]
+    fn wide_values() {
+        let m = run("
+.equ BIG, 305419896
+    li r1, #2147483647
+    li r2, #600000
+    li r3, BIG
+    li r4, #524287
+    la r5, far
+    la r6, farther
+    halt
+.rept 1115
+    nop
+.endr
+far:
+.rept 2000
+    nop
+.endr
+farther:
+    nop
+");
+        assert_eq!(m[R1], 2147483647);
+        assert_eq!(m[R2], 600000);
+        assert_eq!(m[R3], 305419896);
+        assert_eq!(m[R4], 524287);
+        assert_eq!(m[R5], 0x11b0);
+        assert_eq!(m[R6], 0x11b0 + 8000);
+    }
+
+    #[test]
+    fn branches() {
+        let m = run("
+    li r2, #3
+.loop:
+    inc r1
+    dec r2
+    bnez r2, .loop
+    beqz r2, 1f
+    halt
+1:
+    ldr r3, #1
+    halt
+");
+        assert_eq!((m[R1], m[R2], m[R3]), (3, 0, 1));
+    }
+}
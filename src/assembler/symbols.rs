@@ -11,6 +11,8 @@ pub struct SymbolTable<'s> {
     sym_: HashMap<SymbolId, Symbol<'s>>,
     next_id: usize,
     string_interner: HashMap<String, &'s str>,
+    /// Source files by the index tokens carry.
+    files: Vec<&'s str>,
 }
 
 impl<'s> SymbolTable<'s> {
@@ -80,9 +82,33 @@ impl<'s> SymbolTable<'s> {
         self.name_.get(n).copied()
     }
 
-    pub fn get_symbol(&self, n: &SymbolId) -> Option<Symbol> {
+    pub fn get_symbol(&self, n: &SymbolId) -> Option<Symbol<'_>> {
         self.sym_.get(n).copied()
     }
+
+    pub fn symbols(&self) -> impl Iterator<Item = Symbol<'_>> {
+        self.sym_.values().copied()
+    }
+
+    /// Index tokens from `path` carry.
+    pub fn add_file(&mut self, path: &str) -> u32 {
+        match self.files.iter().position(|f| *f == path) {
+            Some(idx) => idx as u32,
+            None => {
+                let path = self.intern_str(path);
+                self.files.push(path);
+                self.files.len() as u32 - 1
+            }
+        }
+    }
+
+    pub fn file(&self, idx: u32) -> &str {
+        self.files.get(idx as usize).copied().unwrap_or("?")
+    }
+
+    pub fn files(&self) -> &[&'s str] {
+        &self.files
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy)]
@@ -93,9 +119,15 @@ pub struct Symbol<'s> {
     pub line: usize,
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum SymbolKind {
     Label,
+    /// A `.section` name, valued at where the section starts.
+    Section,
+    /// Declared `.extern`, defined in another object.
+    Extern,
+    /// Set by `.equ` or `jasm -D`.
+    Constant,
     Directive,
     Parameter,
     #[default]
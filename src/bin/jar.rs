@@ -0,0 +1,87 @@
+use std::{env, fs, path::Path, process};
+
+use jcore::{archive::Archive, object::Object};
+
+fn read(path: &str) -> Archive {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{path}: {e}");
+        process::exit(1);
+    });
+    text.parse().unwrap_or_else(|e| {
+        eprintln!("{path}: {e}");
+        process::exit(1);
+    })
+}
+
+fn main() {
+    let mut args = env::args();
+    let program = args.next().unwrap();
+    let args = args.collect::<Vec<_>>();
+
+    let (Some(cmd), Some(path)) = (args.first(), args.get(1)) else {
+        eprintln!(
+            "USAGE: {program} c|r|d|t|x|s <archive> [members]...\n  \
+             c: create from objects    r: add or replace objects\n  \
+             d: delete members         t: list members\n  \
+             x: extract members, all by default\n  \
+             s: print the symbol index"
+        );
+        process::exit(2);
+    };
+    let members = &args[2..];
+
+    match cmd.as_str() {
+        "c" | "r" => {
+            let mut archive = match cmd.as_str() {
+                "r" if Path::new(path).exists() => read(path),
+                _ => Archive::new(),
+            };
+            for file in members {
+                let obj = fs::read_to_string(file)
+                    .unwrap()
+                    .parse::<Object>()
+                    .unwrap_or_else(|e| {
+                        eprintln!("{file}: {e}");
+                        process::exit(1);
+                    });
+                // members go by file name, like ar
+                let name = Path::new(file).file_name().unwrap();
+                archive.add(&name.to_string_lossy(), obj);
+            }
+            fs::write(path, archive.to_string()).unwrap();
+        }
+        "d" => {
+            let mut archive = read(path);
+            for name in members {
+                if archive.remove(name).is_none() {
+                    eprintln!("no member '{name}' in {path}");
+                }
+            }
+            fs::write(path, archive.to_string()).unwrap();
+        }
+        "t" => {
+            for (name, _) in read(path).members() {
+                println!("{name}");
+            }
+        }
+        "x" => {
+            let archive = read(path);
+            for (name, obj) in archive.members() {
+                if members.is_empty()
+                    || members.iter().any(|m| m == name)
+                {
+                    fs::write(name, obj.to_string()).unwrap();
+                }
+            }
+        }
+        "s" => {
+            for (sym, member) in read(path).index() {
+                println!("{sym} in {member}");
+            }
+        }
+        _ => {
+            eprintln!("unknown command '{cmd}'");
+            process::exit(2);
+        }
+    }
+}
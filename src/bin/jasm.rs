@@ -1,17 +1,67 @@
 use std::{
     env, fs,
-    io::{self, Read},
+    io::{self, Read, Write},
+    process,
 };
 
-use jcore::assembler;
+use jcore::{assembler, object::Object};
 
 fn main() {
     let mut args = env::args();
     let program = args.next().unwrap();
-    let args = args.collect::<Vec<_>>();
+    let mut args = args.collect::<Vec<_>>();
+
+    let mut take_opt = |opt: &str| {
+        let idx = args.iter().position(|a| a == opt)?;
+        args.remove(idx);
+        if idx == args.len() {
+            eprintln!("error: {opt} needs a value");
+            process::exit(2);
+        }
+        Some(args.remove(idx))
+    };
+    // -g file: write debug info next to the program
+    let debug = take_opt("-g");
+    // -l file: listing, -m file: symbol map
+    let listing = take_opt("-l");
+    let map = take_opt("-m");
+    // -I dir: search path for .include and .incbin, repeatable
+    let mut options = assembler::Options::default();
+    while let Some(dir) = take_opt("-I") {
+        options.include_dirs.push(dir.into());
+    }
+    // -D NAME[=value]: a constant for .if and operands, 1 by default
+    while let Some(define) = take_opt("-D") {
+        let (name, value) =
+            define.split_once('=').unwrap_or((&define, "1"));
+        let value = match value.strip_prefix("0x") {
+            Some(hex) => {
+                u32::from_str_radix(hex, 16).map(|v| v as i32)
+            }
+            None => value.parse(),
+        };
+        let Ok(value) = value else {
+            eprintln!("error: bad value in -D {define}");
+            process::exit(1);
+        };
+        options.defines.push((name.to_string(), value));
+    }
+    // -c: write a relocatable object for jld instead
+    let object = match args.iter().position(|a| a == "-c") {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        }
+        None => false,
+    };
 
     if args.is_empty() {
-        eprintln!("USAGE: {program} - (stdin) | <filename>");
+        eprintln!(
+            "USAGE: {program} [-c] [-I dir]... [-D name[=value]]... \
+             [-g debug] [-l listing] [-m map] - \
+             (stdin) | <filename>"
+        );
+        process::exit(2);
     }
 
     let filename = &args[0];
@@ -25,5 +75,32 @@ fn main() {
         file.read_to_string(&mut buffer).unwrap();
     }
 
-    assembler::assemble(filename, &buffer);
+    let out = assembler::assemble_with(filename, &buffer, &options);
+    if object {
+        let obj = Object::from(&out);
+        io::stdout().write_all(obj.to_string().as_bytes()).unwrap();
+    } else {
+        let undefined = out
+            .relocs
+            .iter()
+            .find(|r| out.externs.contains(&r.symbol));
+        if let Some(r) = undefined {
+            eprintln!(
+                "undefined symbol '{}', assemble with -c and link \
+                 with jld",
+                r.symbol
+            );
+            process::exit(1);
+        }
+        io::stdout().lock().write_all(&out.code).unwrap();
+    }
+    if let Some(path) = debug {
+        fs::write(path, out.debug.to_string()).unwrap();
+    }
+    if let Some(path) = listing {
+        fs::write(path, out.listing(&buffer)).unwrap();
+    }
+    if let Some(path) = map {
+        fs::write(path, out.map()).unwrap();
+    }
 }
@@ -0,0 +1,54 @@
+use std::{
+    env, fs,
+    io::{self, Read},
+    process,
+};
+
+use jcore::{debug::DebugInfo, disasm};
+
+fn main() {
+    let mut args = env::args();
+    let program = args.next().unwrap();
+    let mut args = args.collect::<Vec<_>>();
+
+    // -g file: debug info from jasm -g, for labels and source lines
+    let debug = match args.iter().position(|a| a == "-g") {
+        Some(idx) if idx + 1 < args.len() => {
+            args.remove(idx);
+            let path = args.remove(idx);
+            let text =
+                fs::read_to_string(&path).unwrap_or_else(|e| {
+                    eprintln!("error: {path}: {e}");
+                    process::exit(1);
+                });
+            text.parse::<DebugInfo>().unwrap_or_else(|e| {
+                eprintln!("error: {path}: {e}");
+                process::exit(1);
+            })
+        }
+        _ => DebugInfo::default(),
+    };
+
+    let Some(filename) = args.first() else {
+        eprintln!(
+            "USAGE: {program} [-g debug] - (stdin) | <filename>"
+        );
+        process::exit(2);
+    };
+    let mut code = Vec::new();
+    let read = match filename.as_str() {
+        "-" => io::stdin().read_to_end(&mut code).map(|_| ()),
+        path => fs::read(path).map(|bytes| code = bytes),
+    };
+    if let Err(e) = read {
+        eprintln!("error: {filename}: {e}");
+        process::exit(1);
+    }
+
+    // sources are read from where jasm found them
+    let sources = debug
+        .files()
+        .map(|path| fs::read_to_string(path).unwrap_or_default())
+        .collect::<Vec<_>>();
+    print!("{}", disasm::listing(&code, &debug, &sources));
+}
@@ -0,0 +1,97 @@
+use std::{
+    env, fmt, fs,
+    io::{self, Write},
+    process,
+};
+
+use jcore::{
+    archive::Archive,
+    linker::{link, pull_members, Script},
+    object::Object,
+};
+
+/// The value, or `error: path: e` and exit 1.
+fn or_exit<T, E: fmt::Display>(
+    result: Result<T, E>,
+    path: &str,
+) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("error: {path}: {e}");
+        process::exit(1);
+    })
+}
+
+fn main() {
+    let mut args = env::args();
+    let program = args.next().unwrap();
+    let mut args = args.collect::<Vec<_>>();
+
+    let mut take_opt = |opt: &str| {
+        let idx = args.iter().position(|a| a == opt)?;
+        args.remove(idx);
+        if idx == args.len() {
+            eprintln!("error: {opt} needs a value");
+            process::exit(2);
+        }
+        Some(args.remove(idx))
+    };
+    // -o file: image, stdout by default
+    let output = take_opt("-o");
+    // -T file: linker script
+    let script = take_opt("-T");
+    // -g file: merged debug info, -m file: link map, with the load
+    // address and entry `vm --map` runs the image at
+    let debug = take_opt("-g");
+    let map = take_opt("-m");
+
+    if args.is_empty() {
+        eprintln!(
+            "USAGE: {program} [-o out] [-T script] [-g debug] \
+             [-m map] <objects and archives>..."
+        );
+        process::exit(2);
+    }
+
+    let script = script
+        .map(|path| {
+            let text = or_exit(fs::read_to_string(&path), &path);
+            or_exit(text.parse::<Script>(), &path)
+        })
+        .unwrap_or_default();
+    // archives only supply the members something needs
+    let mut objects = Vec::new();
+    let mut archives = Vec::new();
+    for path in args {
+        let text = or_exit(fs::read_to_string(&path), &path);
+        if Archive::is_archive(&text) {
+            let archive = or_exit(text.parse::<Archive>(), &path);
+            archives.push((path, archive));
+        } else {
+            let obj = or_exit(text.parse::<Object>(), &path);
+            objects.push((path, obj));
+        }
+    }
+    let members = pull_members(&objects, &archives);
+    objects.extend(members);
+
+    let linked = link(&objects, &script).unwrap_or_else(|errors| {
+        for e in errors {
+            eprintln!("error: {e}");
+        }
+        process::exit(1);
+    });
+
+    match output {
+        Some(path) => or_exit(fs::write(&path, &linked.image), &path),
+        None => or_exit(
+            io::stdout().lock().write_all(&linked.image),
+            "stdout",
+        ),
+    }
+    if let Some(path) = debug {
+        or_exit(fs::write(&path, linked.debug.to_string()), &path);
+    }
+    if let Some(path) = map {
+        or_exit(fs::write(&path, linked.map()), &path);
+    }
+}
@@ -1,12 +1,77 @@
 use std::{
     env, fs,
-    io::{stdin, Read},
+    io::{self, stdin, Read, Write},
+    process,
+    time::{Duration, Instant},
 };
 
-use jcore::vm::Machine;
+use jcore::{
+    coverage::Coverage,
+    debug::DebugInfo,
+    error::Exception,
+    linker::map_addresses,
+    mpu::{PERM_R, PERM_W, PERM_X},
+    opcode::Instruction,
+    profile::Profiler,
+    register::{PC, R0},
+    vm::{
+        lockstep::lockstep, Engine, Hook, Machine, MachineBuilder,
+        StopReason, TrapMode, SYS_EXIT,
+    },
+};
+
+// most bytes the read and write host calls copy at once
+const HOST_IO_CHUNK: u32 = 4096;
+
+fn parse_num(s: &str) -> u32 {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .unwrap_or_else(|_| panic!("invalid number '{s}'"))
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let idx = args.iter().position(|a| a == flag);
+    idx.map(|idx| args.remove(idx)).is_some()
+}
+
+fn take_opt(args: &mut Vec<String>, opt: &str) -> Option<String> {
+    let idx = args.iter().position(|a| a == opt)?;
+    args.remove(idx);
+    if idx == args.len() {
+        eprintln!("error: {opt} needs a value");
+        process::exit(2);
+    }
+    Some(args.remove(idx))
+}
+
+/// Prints every instruction, with its source line when the debug
+/// info has one.
+struct Trace(DebugInfo);
+
+impl Hook for Trace {
+    fn before(&mut self, _: &Machine, pc: u32, ins: &Instruction) {
+        match self.0.source(pc) {
+            Some(src) => eprintln!("{pc:08x}: {ins}  ; {src}"),
+            None => eprintln!("{pc:08x}: {ins}"),
+        }
+    }
+
+    fn mem_read(&mut self, addr: u32, value: u32) {
+        eprintln!("          [{addr:08x}] -> {value:#x}");
+    }
+
+    fn mem_write(&mut self, addr: u32, value: u32) {
+        eprintln!("          [{addr:08x}] <- {value:#x}");
+    }
+
+    fn exception(&mut self, pc: u32, e: &Exception) {
+        eprintln!("{pc:08x}: raised {e:?}");
+    }
+}
 
 fn main() {
-    let mut machine = Machine::new();
     /*
         abi: 32bit instructions
         |---- ----|------------------------|
@@ -35,7 +100,7 @@ fn main() {
             op              imm
 
         misc instructions: 0110
-        NOP is misc 0b_0110_1111 | 0x6f
+        NOP is misc 0b_0110_1111 | 0x6f, HALT 0x66, EXIT 0x67
 
         |---- ----|------------------------|
             op
@@ -55,10 +120,109 @@ fn main() {
        push r0
     */
 
-    let args = env::args().collect::<Vec<_>>();
+    let mut args = env::args().collect::<Vec<_>>();
+
+    // --trap: deliver cpu exceptions to the guest vector table
+    let trap = take_flag(&mut args, "--trap");
+    // --ram len | --sparse
+    let ram = take_opt(&mut args, "--ram").map(|n| parse_num(&n));
+    let sparse = take_flag(&mut args, "--sparse");
+    let sp = take_opt(&mut args, "--sp").map(|n| parse_num(&n));
+    let bp = take_opt(&mut args, "--bp").map(|n| parse_num(&n));
+    let load = take_opt(&mut args, "--load").map(|n| parse_num(&n));
+    let entry = take_opt(&mut args, "--entry").map(|n| parse_num(&n));
+    // --map file: load address and entry from a jld map, unless
+    // --load and --entry are given
+    let (load, entry) = match take_opt(&mut args, "--map") {
+        Some(path) => {
+            let map = fs::read_to_string(&path).unwrap_or_else(|e| {
+                eprintln!("error: {path}: {e}");
+                process::exit(1);
+            });
+            let (base, start) = map_addresses(&map);
+            (load.or(base), entry.or(start))
+        }
+        None => (load, entry),
+    };
+    let icache = !take_flag(&mut args, "--no-icache");
+    // --engine interp | block | jit
+    let engine = match take_opt(&mut args, "--engine").as_deref() {
+        None | Some("interp") => Engine::Interpreter,
+        Some("block") => Engine::Block,
+        Some("jit") => Engine::Jit,
+        Some(e) => panic!("unknown engine '{e}'"),
+    };
+    // --bench n: time n instructions on each engine
+    let bench = take_opt(&mut args, "--bench").map(|n| parse_num(&n));
+    // --lockstep n: check --engine, or the block engine by default,
+    // against the interpreter
+    let check =
+        take_opt(&mut args, "--lockstep").map(|n| parse_num(&n));
+    // --trace: print every instruction and memory access
+    let trace = take_flag(&mut args, "--trace");
+    // --debug file: debug info from jasm -g, for label names
+    let debug = take_opt(&mut args, "--debug")
+        .map(|path| {
+            fs::read_to_string(path)
+                .unwrap()
+                .parse::<DebugInfo>()
+                .unwrap()
+        })
+        .unwrap_or_default();
+    // --profile: print hot spots, --folded file: write stacks for
+    // flamegraph tools
+    let folded = take_opt(&mut args, "--folded");
+    let profile =
+        take_flag(&mut args, "--profile") || folded.is_some();
+    // --lcov file | --annotate file: coverage against the source
+    // named in --debug
+    let lcov = take_opt(&mut args, "--lcov");
+    let annotate = take_opt(&mut args, "--annotate");
+    let coverage = lcov.is_some() || annotate.is_some();
+    // --fuel n | --timeout ms | --break addr|label: bounded run,
+    // prints only the final state and why it stopped
+    let fuel = take_opt(&mut args, "--fuel").map(|n| parse_num(&n));
+    let timeout =
+        take_opt(&mut args, "--timeout").map(|n| parse_num(&n));
+    let mut breakpoints = Vec::new();
+    while let Some(addr) = take_opt(&mut args, "--break") {
+        breakpoints.push(
+            debug
+                .symbol_addr(&addr)
+                .unwrap_or_else(|| parse_num(&addr)),
+        );
+    }
+
+    // --mpu start:len:rwx, repeatable, enables the mpu
+    let mut regions = Vec::new();
+    while let Some(spec) = take_opt(&mut args, "--mpu") {
+        let mut parts = spec.split(':');
+        let (Some(start), Some(len), Some(perm)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            panic!("expected --mpu start:len:rwx, got '{spec}'");
+        };
+        let perm = perm.chars().fold(0, |acc, c| match c {
+            'r' => acc | PERM_R,
+            'w' => acc | PERM_W,
+            'x' => acc | PERM_X,
+            _ => acc,
+        });
+        regions.push((parse_num(start), parse_num(len), perm));
+    }
 
     if args.len() < 2 {
-        println!("Usage: {} <input>", &args[0]);
+        println!(
+            "Usage: {} [--trap] [--ram len | --sparse] [--sp addr] \
+             [--bp addr] [--load addr] [--entry addr] [--map file] \
+             [--mpu start:len:rwx]... [--no-icache] \
+             [--engine interp|block|jit] [--bench n] [--lockstep n] \
+             [--trace] [--debug file] [--profile] [--folded file] \
+             [--lcov file] [--annotate file] \
+             [--fuel n] [--timeout ms] [--break addr]... <input>",
+            &args[0]
+        );
+        process::exit(2);
     }
 
     let file = &args[1];
@@ -74,23 +238,207 @@ fn main() {
             .unwrap();
     }
 
-    buffer
-        // .chunks_exact(4)
-        // .map(|x| {
-        //     u32::from_le_bytes(unsafe {
-        //         *(x.as_ptr() as *const [u8; 4])
-        //     })
-        // })
-        .iter()
-        .enumerate()
-        .try_for_each(|(idx, &word)| {
-            machine.mem.write(idx as u32, word)
-        })
-        .unwrap();
+    let build = |icache: bool, engine: Engine| -> Machine {
+        let mut builder = MachineBuilder::new().program(&buffer);
+        if trap {
+            builder = builder.trap_mode(TrapMode::Vector);
+        }
+        if let Some(len) = ram {
+            builder = builder.ram(len as usize);
+        }
+        if sparse {
+            builder = builder.sparse();
+        }
+        if let Some(sp) = sp {
+            builder = builder.stack(sp);
+        }
+        if let Some(bp) = bp {
+            builder = builder.bp(bp);
+        }
+        if let Some(addr) = load {
+            builder = builder.load_address(addr);
+        }
+        if let Some(addr) = entry {
+            builder = builder.entry(addr);
+        }
+
+        let mut machine = builder.build().unwrap();
+        machine.set_icache(icache);
+        machine.set_engine(engine);
+        for &(start, len, perm) in &regions {
+            machine.mpu.add_region(start, len, perm);
+            machine.mpu.enable(true);
+        }
+
+        // svc #1: write r1 bytes at r0 to stdout
+        machine.host.bind(1, "write", |m| {
+            let (addr, len) = (m.arg(0)?, m.arg(1)?);
+            // a piece at a time, whatever r1 asks for
+            let mut stdout = io::stdout().lock();
+            for off in (0..len).step_by(HOST_IO_CHUNK as usize) {
+                let n = (len - off).min(HOST_IO_CHUNK);
+                let bytes =
+                    m.read_bytes(addr.wrapping_add(off), n)?;
+                stdout.write_all(&bytes).unwrap();
+            }
+            m.set_ret(len);
+            Ok(())
+        });
+        // svc #2: read up to r1 bytes from stdin to r0, 0 at the end
+        machine.host.bind(2, "read", |m| {
+            let (addr, len) = (m.arg(0)?, m.arg(1)?);
+            // a short read past one piece
+            let mut buf = [0; HOST_IO_CHUNK as usize];
+            let len = len.min(HOST_IO_CHUNK) as usize;
+            let n = io::stdin().read(&mut buf[..len]).unwrap_or(0);
+            m.write_bytes(addr, &buf[..n])?;
+            m.set_ret(n as u32);
+            Ok(())
+        });
+        // svc #3: print the NUL terminated string at r0
+        machine.host.bind(3, "print", |m| {
+            let text = m.read_cstr(m.arg(0)?, 4096)?;
+            io::stdout().write_all(text.as_bytes()).unwrap();
+            m.set_ret(text.len() as u32);
+            Ok(())
+        });
+        if trace {
+            machine.add_hook(Box::new(Trace(debug.clone())));
+        }
+        machine
+    };
+
+    if let Some(n) = check {
+        let mut reference = build(icache, Engine::Interpreter);
+        let mut subject = match engine {
+            Engine::Interpreter => build(icache, Engine::Block),
+            engine => build(icache, engine),
+        };
+        let len = ram.unwrap_or(10 * 1024);
+        match lockstep(&mut reference, &mut subject, n as u64, 0..len)
+        {
+            Ok(steps) => {
+                println!("lockstep ok: {steps} instructions")
+            }
+            Err(e) => {
+                println!("lockstep failed, {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(n) = bench {
+        let runs = [
+            ("interp, no icache", false, Engine::Interpreter),
+            ("interp", true, Engine::Interpreter),
+            ("block", true, Engine::Block),
+            ("jit", true, Engine::Jit),
+        ];
+        for (name, icache, engine) in runs {
+            let mut machine = build(icache, engine);
+            let start = Instant::now();
+            while machine.steps() < n as u64 {
+                machine.dispatch().unwrap();
+                if machine.is_halted() {
+                    break;
+                }
+            }
+            let secs = start.elapsed().as_secs_f64();
+            println!(
+                "{name:<17}: {} instructions in {:.3}s, {:.0} ips",
+                machine.steps(),
+                secs,
+                machine.steps() as f64 / secs,
+            );
+        }
+        return;
+    }
+
+    let mut machine = build(icache, engine);
+    let profiler =
+        profile.then(|| machine.add_hook(Box::new(Profiler::new())));
+    let covered = coverage.then(|| {
+        let base = load.unwrap_or(0);
+        machine
+            .add_hook(Box::new(Coverage::with_program(&buffer, base)))
+    });
+    let report = |machine: &Machine| {
+        if let Some(p) =
+            profiler.and_then(|id| machine.hook::<Profiler>(id))
+        {
+            eprint!("{}", p.report(&debug, 20));
+            if let Some(path) = &folded {
+                fs::write(path, p.folded(&debug)).unwrap();
+            }
+        }
+        if let Some(c) =
+            covered.and_then(|id| machine.hook::<Coverage>(id))
+        {
+            if let Some(path) = &lcov {
+                fs::write(path, c.lcov(&debug)).unwrap();
+            }
+            if let Some(path) = &annotate {
+                let source =
+                    fs::read_to_string(debug.file()).unwrap();
+                fs::write(path, c.annotate(&debug, &source)).unwrap();
+            }
+        }
+    };
+
+    if fuel.is_some()
+        || timeout.is_some()
+        || !breakpoints.is_empty()
+        || profile
+        || coverage
+    {
+        for &addr in &breakpoints {
+            machine.add_breakpoint(addr);
+        }
+        let deadline = timeout.map(|ms| {
+            Instant::now() + Duration::from_millis(ms as u64)
+        });
+        let reason =
+            machine
+                .run_with(fuel.map(u64::from), deadline, |_| false);
+        machine.state();
+        println!(
+            "stopped after {} steps: {reason:?}",
+            machine.steps()
+        );
+        let stopped_early = !matches!(
+            reason,
+            StopReason::Halted | StopReason::Exit(_)
+        );
+        if let Some(src) =
+            debug.source(machine[PC]).filter(|_| stopped_early)
+        {
+            println!("at {src}");
+        }
+        report(&machine);
+        process::exit(match reason {
+            StopReason::Exit(code) => code as i32,
+            StopReason::Exception(_) => 1,
+            StopReason::FuelExhausted | StopReason::Deadline => 124,
+            _ => 0,
+        });
+    }
 
     machine.state();
     println!("{}", "-".repeat(20));
-    machine.run(true).unwrap();
+    // the guest's exit code becomes ours
+    let code = match machine.run(true) {
+        Ok(()) => machine.exit_code().unwrap_or(0),
+        Err(Exception::Syscall(SYS_EXIT)) => machine[R0],
+        Err(e) => {
+            eprintln!("error: {e:?}");
+            if let Some(src) = debug.source(machine[PC]) {
+                eprintln!("at {src}");
+            }
+            process::exit(1);
+        }
+    };
+    process::exit(code as i32);
 
     // use jcore::opcode::{Instruction::*, Operand::*};
     // use jcore::register::*;
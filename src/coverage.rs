@@ -0,0 +1,238 @@
+//! Instruction and branch coverage, installed on a machine as a hook
+//! and reported against the source lines in the debug info.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use crate::{
+    debug::DebugInfo,
+    opcode::{Cond, Instruction},
+    register::*,
+    vm::{Hook, Machine},
+};
+
+/// Per `(file, line)`, the count of its most executed instruction
+/// and the `(taken, not taken)` of its conditional branches.
+type LineCounts = BTreeMap<(u32, u32), (u64, Vec<(u64, u64)>)>;
+
+/// Executions per pc, plus taken / not taken counts for every
+/// conditional branch that ran.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    hits: HashMap<u32, u64>,
+    branches: HashMap<u32, (u64, u64)>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Coverage that also knows the conditional branches in `code`,
+    /// loaded at `base`, so the ones that never ran are reported too.
+    pub fn with_program(code: &[u8], base: u32) -> Self {
+        let mut cov = Self::default();
+        for (i, word) in code.chunks_exact(4).enumerate() {
+            let word = u32::from_le_bytes(word.try_into().unwrap());
+            if let Ok(Instruction::B(cond, _)) = word.try_into() {
+                if cond != Cond::Al {
+                    let pc = base.wrapping_add(i as u32 * 4);
+                    cov.branches.insert(pc, (0, 0));
+                }
+            }
+        }
+        cov
+    }
+
+    /// Times the instruction at `pc` ran.
+    pub fn hits(&self, pc: u32) -> u64 {
+        self.hits.get(&pc).copied().unwrap_or(0)
+    }
+
+    /// `(taken, not taken)` for the conditional branch at `pc`.
+    pub fn branch(&self, pc: u32) -> Option<(u64, u64)> {
+        self.branches.get(&pc).copied()
+    }
+
+    fn per_line(&self, debug: &DebugInfo) -> LineCounts {
+        let mut lines = LineCounts::new();
+        for entry in debug.lines() {
+            let key = (entry.loc.file, entry.loc.line);
+            let line = lines.entry(key).or_default();
+            line.0 = line.0.max(self.hits(entry.addr));
+            if let Some(b) = self.branch(entry.addr) {
+                line.1.push(b);
+            }
+        }
+        lines
+    }
+
+    /// An lcov tracefile, one record per source file.
+    pub fn lcov(&self, debug: &DebugInfo) -> String {
+        let lines = self.per_line(debug);
+        let mut out = String::new();
+        writeln!(out, "TN:").unwrap();
+        for (file, path) in debug.files().enumerate() {
+            let file = file as u32;
+            let lines = lines
+                .range((file, 0)..=(file, u32::MAX))
+                .map(|(&(_, line), counts)| (line, counts))
+                .collect::<Vec<_>>();
+            writeln!(out, "SF:{path}").unwrap();
+
+            let (mut found, mut hit) = (0, 0);
+            for &(line, (_, branches)) in &lines {
+                for (block, &(taken, not_taken)) in
+                    branches.iter().enumerate()
+                {
+                    for (branch, n) in
+                        [not_taken, taken].iter().enumerate()
+                    {
+                        // `-` is lcov for a branch whose block never
+                        // ran
+                        let n = if taken + not_taken == 0 {
+                            "-".to_string()
+                        } else {
+                            n.to_string()
+                        };
+                        writeln!(
+                            out,
+                            "BRDA:{line},{block},{branch},{n}"
+                        )
+                        .unwrap();
+                    }
+                    found += 2;
+                    hit +=
+                        (taken != 0) as u32 + (not_taken != 0) as u32;
+                }
+            }
+            writeln!(out, "BRF:{found}").unwrap();
+            writeln!(out, "BRH:{hit}").unwrap();
+
+            for &(line, (n, _)) in &lines {
+                writeln!(out, "DA:{line},{n}").unwrap();
+            }
+            writeln!(out, "LF:{}", lines.len()).unwrap();
+            writeln!(
+                out,
+                "LH:{}",
+                lines.iter().filter(|l| l.1 .0 != 0).count()
+            )
+            .unwrap();
+            writeln!(out, "end_of_record").unwrap();
+        }
+        out
+    }
+
+    /// `source`, the assembled file, with gcov style counts in front
+    /// of every line: `-` for lines without code and `#####` for
+    /// code that never ran. Conditional branches get their outcomes
+    /// after the line.
+    pub fn annotate(
+        &self,
+        debug: &DebugInfo,
+        source: &str,
+    ) -> String {
+        let lines = self.per_line(debug);
+        let mut out = String::new();
+        for (i, text) in source.lines().enumerate() {
+            let line = i as u32 + 1;
+            let count = match lines.get(&(0, line)) {
+                None => "-".to_string(),
+                Some((0, _)) => "#####".to_string(),
+                Some((n, _)) => n.to_string(),
+            };
+            writeln!(out, "{count:>9}:{line:>5}:{text}").unwrap();
+            let Some((_, branches)) = lines.get(&(0, line)) else {
+                continue;
+            };
+            for &(taken, not_taken) in branches {
+                if taken + not_taken == 0 {
+                    writeln!(out, "{:>9} branch never executed", "")
+                } else {
+                    writeln!(
+                        out,
+                        "{:>9} branch taken {taken}, not taken \
+                         {not_taken}",
+                        ""
+                    )
+                }
+                .unwrap();
+            }
+        }
+        out
+    }
+}
+
+impl Hook for Coverage {
+    fn before(&mut self, m: &Machine, pc: u32, ins: &Instruction) {
+        *self.hits.entry(pc).or_default() += 1;
+        // decided by the flags going in, a branch to the next
+        // instruction is still taken
+        if let Instruction::B(cond, _) = ins {
+            if *cond == Cond::Al {
+                return;
+            }
+            let b = self.branches.entry(pc).or_default();
+            if cond.holds(m[FLAGS]) {
+                b.0 += 1;
+            } else {
+                b.1 += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    // bne is taken twice and falls through once, bgt never runs
+    const PROGRAM: &str = "
+    ldr r1, #3
+loop:
+    sub r1, r1, #1
+    cmp r1, #0
+    bne loop
+    halt
+    bgt loop
+";
+
+    #[test]
+    fn lcov() {
+        let asm = assemble("test.jasm", PROGRAM);
+        let mut vm =
+            Machine::builder().program(&asm.code).build().unwrap();
+        let id = vm
+            .add_hook(Box::new(Coverage::with_program(&asm.code, 0)));
+        vm.run(false).unwrap();
+        let cov = vm.hook::<Coverage>(id).unwrap();
+        assert_eq!(cov.branch(12), Some((2, 1)));
+        assert_eq!(cov.branch(20), Some((0, 0)));
+
+        assert_eq!(
+            cov.lcov(&asm.debug),
+            "TN:
+SF:test.jasm
+BRDA:6,0,0,1
+BRDA:6,0,1,2
+BRDA:8,0,0,-
+BRDA:8,0,1,-
+BRF:4
+BRH:2
+DA:2,1
+DA:4,3
+DA:5,3
+DA:6,3
+DA:7,1
+DA:8,0
+LF:6
+LH:5
+end_of_record
+"
+        );
+    }
+}
@@ -0,0 +1,341 @@
+//! Debug info `jasm -g` writes next to a program, one record per
+//! line. Files are numbered in the order they're listed, line records
+//! give an instruction's `file:line:column` and, for macro and
+//! pseudo-instruction expansions, the line it was expanded from:
+//!
+//! ```text
+//! file scripts/jump.jasm
+//! sym 0000001c loop
+//! line 0000001c 0:7:2
+//! line 00000020 0:3:5 0:9:2
+//! ```
+
+use std::{fmt, str::FromStr};
+
+/// A position in one of the debug info's files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Loc {
+    pub file: u32,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+impl FromStr for Loc {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let (Some(file), Some(line), Some(column), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!(
+                "expected file:line:column, got '{s}'"
+            )
+            .into());
+        };
+        Ok(Loc {
+            file: file.parse()?,
+            line: line.parse()?,
+            column: column.parse()?,
+        })
+    }
+}
+
+/// Where the instruction at `addr` came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineEntry {
+    pub addr: u32,
+    pub loc: Loc,
+    /// The macro call in the source, if `loc` is in a macro body.
+    pub expanded_from: Option<Loc>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    /// Source files, the first one the one that was assembled.
+    files: Vec<Box<str>>,
+    /// Labels by address.
+    symbols: Vec<(u32, Box<str>)>,
+    /// Source of each instruction, by address.
+    lines: Vec<LineEntry>,
+}
+
+impl DebugInfo {
+    pub fn new(file: &str) -> Self {
+        Self {
+            files: vec![file.into()],
+            ..Default::default()
+        }
+    }
+
+    /// The assembled file, empty without one.
+    pub fn file(&self) -> &str {
+        self.files.first().map_or("", |f| f)
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|f| &**f)
+    }
+
+    /// Number the file `path` goes by in `Loc`s.
+    pub fn add_file(&mut self, path: &str) -> u32 {
+        match self.files.iter().position(|f| **f == *path) {
+            Some(idx) => idx as u32,
+            None => {
+                self.files.push(path.into());
+                self.files.len() as u32 - 1
+            }
+        }
+    }
+
+    pub fn add_line(&mut self, entry: LineEntry) {
+        let idx =
+            self.lines.partition_point(|l| l.addr <= entry.addr);
+        self.lines.insert(idx, entry);
+    }
+
+    /// Every instruction's source by address.
+    pub fn lines(&self) -> &[LineEntry] {
+        &self.lines
+    }
+
+    /// Source of the instruction at `addr`.
+    pub fn line(&self, addr: u32) -> Option<&LineEntry> {
+        let idx = self
+            .lines
+            .binary_search_by_key(&addr, |l| l.addr)
+            .ok()?;
+        Some(&self.lines[idx])
+    }
+
+    /// `path:line:column` of `loc`.
+    pub fn format_loc(&self, loc: Loc) -> String {
+        let file =
+            self.files.get(loc.file as usize).map_or("?", |f| f);
+        format!("{file}:{}:{}", loc.line, loc.column)
+    }
+
+    /// `path:line:column` of the instruction at `addr`, followed by
+    /// the macro call it was expanded from.
+    pub fn source(&self, addr: u32) -> Option<String> {
+        let entry = self.line(addr)?;
+        let mut out = self.format_loc(entry.loc);
+        // a pseudo-instruction expands in place
+        if let Some(call) =
+            entry.expanded_from.filter(|call| *call != entry.loc)
+        {
+            out += &format!(
+                " (expanded from {})",
+                self.format_loc(call)
+            );
+        }
+        Some(out)
+    }
+
+    pub fn add_symbol(&mut self, addr: u32, name: &str) {
+        let idx = self.symbols.partition_point(|s| s.0 <= addr);
+        self.symbols.insert(idx, (addr, name.into()));
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = (u32, &str)> {
+        self.symbols.iter().map(|(addr, name)| (*addr, &**name))
+    }
+
+    pub fn symbol_addr(&self, name: &str) -> Option<u32> {
+        self.symbols().find(|s| s.1 == name).map(|s| s.0)
+    }
+
+    /// Closest label at or before `addr` and the offset from it.
+    pub fn symbolize(&self, addr: u32) -> Option<(&str, u32)> {
+        let idx = self.symbols.partition_point(|s| s.0 <= addr);
+        let (start, name) = self.symbols.get(idx.checked_sub(1)?)?;
+        Some((name, addr - start))
+    }
+
+    /// `label+0x4` or the plain address without a label.
+    pub fn describe(&self, addr: u32) -> String {
+        match self.symbolize(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, off)) => format!("{name}+{off:#x}"),
+            None => format!("{addr:#010x}"),
+        }
+    }
+}
+
+impl fmt::Display for DebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            writeln!(f, "file {file}")?;
+        }
+        for (addr, name) in &self.symbols {
+            writeln!(f, "sym {addr:08x} {name}")?;
+        }
+        for entry in &self.lines {
+            write!(f, "line {:08x} {}", entry.addr, entry.loc)?;
+            if let Some(call) = entry.expanded_from {
+                write!(f, " {call}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DebugInfo {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut info = DebugInfo::default();
+        for (i, line) in s.lines().enumerate() {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                None => continue,
+                Some("file") => {
+                    info.files
+                        .push(line["file".len()..].trim().into());
+                }
+                Some("sym") => {
+                    let (Some(addr), Some(name)) =
+                        (fields.next(), fields.next())
+                    else {
+                        return Err(format!(
+                            "truncated symbol at line {}",
+                            i + 1
+                        )
+                        .into());
+                    };
+                    let addr = u32::from_str_radix(addr, 16)?;
+                    info.add_symbol(addr, name);
+                }
+                Some("line") => {
+                    let (Some(addr), Some(loc)) =
+                        (fields.next(), fields.next())
+                    else {
+                        return Err(format!(
+                            "truncated line record at line {}",
+                            i + 1
+                        )
+                        .into());
+                    };
+                    info.add_line(LineEntry {
+                        addr: u32::from_str_radix(addr, 16)?,
+                        loc: loc.parse()?,
+                        expanded_from: fields
+                            .next()
+                            .map(str::parse)
+                            .transpose()?,
+                    });
+                }
+                Some(kind) => {
+                    return Err(format!(
+                        "unknown record '{kind}' at line {}",
+                        i + 1
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(file: u32, line: u32, column: u32) -> Loc {
+        Loc { file, line, column }
+    }
+
+    fn sample() -> DebugInfo {
+        let mut info = DebugInfo::new("main.jasm");
+        let lib = info.add_file("lib/macros.jasm");
+        info.add_symbol(0x1c, "loop");
+        info.add_symbol(0, "start");
+        info.add_line(LineEntry {
+            addr: 0x20,
+            loc: loc(lib, 3, 5),
+            expanded_from: Some(loc(0, 9, 2)),
+        });
+        info.add_line(LineEntry {
+            addr: 0x1c,
+            loc: loc(0, 7, 2),
+            expanded_from: None,
+        });
+        // a pseudo-instruction, expanded where it's written
+        info.add_line(LineEntry {
+            addr: 0x24,
+            loc: loc(0, 10, 2),
+            expanded_from: Some(loc(0, 10, 2)),
+        });
+        info
+    }
+
+    #[test]
+    fn round_trip() {
+        let info = sample();
+        let text = info.to_string();
+        assert_eq!(
+            text,
+            "file main.jasm
+file lib/macros.jasm
+sym 00000000 start
+sym 0000001c loop
+line 0000001c 0:7:2
+line 00000020 1:3:5 0:9:2
+line 00000024 0:10:2 0:10:2
+"
+        );
+        assert_eq!(text.parse::<DebugInfo>().unwrap(), info);
+        assert_eq!(
+            DebugInfo::default()
+                .to_string()
+                .parse::<DebugInfo>()
+                .unwrap(),
+            DebugInfo::default()
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        for text in [
+            "sym 0000001c",
+            "line 0000001c",
+            "line 0000001c 0:7",
+            "line 0000001c 0:7:2:1",
+            "line xyz 0:7:2",
+            "bogus 1",
+        ] {
+            assert!(text.parse::<DebugInfo>().is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn source() {
+        let info = sample();
+        assert_eq!(info.source(0x1c).unwrap(), "main.jasm:7:2");
+        assert_eq!(
+            info.source(0x20).unwrap(),
+            "lib/macros.jasm:3:5 (expanded from main.jasm:9:2)"
+        );
+        assert_eq!(info.source(0x24).unwrap(), "main.jasm:10:2");
+        assert_eq!(info.source(0x28), None);
+        assert_eq!(info.format_loc(loc(5, 1, 1)), "?:1:1");
+    }
+
+    #[test]
+    fn symbols() {
+        let info = sample();
+        assert_eq!(info.symbol_addr("loop"), Some(0x1c));
+        assert_eq!(info.symbolize(0x24), Some(("loop", 8)));
+        assert_eq!(info.describe(0x1c), "loop");
+        assert_eq!(info.describe(0x4), "start+0x4");
+        assert_eq!(DebugInfo::new("x").describe(0x4), "0x00000004");
+    }
+}
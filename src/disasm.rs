@@ -0,0 +1,88 @@
+//! Code back to jasm, for listings and `jdis`.
+//!
+//! ```text
+//! loop:
+//! 0000001c 90108001  add r2, r2, #1      ; inc r2
+//! 00000020 502ffff8  bne -8              ; -> loop
+//! ```
+
+use std::fmt::Write;
+
+use crate::{
+    debug::DebugInfo,
+    opcode::{Instruction, Operand},
+};
+
+/// `word` in jasm syntax, or `.word` for one that isn't an
+/// instruction.
+pub fn disassemble(word: u32) -> String {
+    match Instruction::try_from(word) {
+        Ok(ins) => ins.to_string(),
+        Err(_) => format!(".word {word:#010x}"),
+    }
+}
+
+/// Every word of `code`, with the labels in `debug` ahead of it and
+/// branch targets named. Words that came from a macro or
+/// pseudo-instruction note the line of `sources`, file texts by
+/// debug file index, they were expanded from.
+pub fn listing(
+    code: &[u8],
+    debug: &DebugInfo,
+    sources: &[String],
+) -> String {
+    let mut out = String::new();
+    let mut symbols = debug.symbols().peekable();
+    for (i, bytes) in code.chunks(4).enumerate() {
+        let addr = i as u32 * 4;
+        while let Some((_, name)) =
+            symbols.next_if(|(at, _)| *at <= addr)
+        {
+            writeln!(out, "{name}:").unwrap();
+        }
+        let Ok(bytes) = <[u8; 4]>::try_from(bytes) else {
+            for (j, b) in bytes.iter().enumerate() {
+                let addr = addr + j as u32;
+                writeln!(out, "{addr:08x} {b:02x}        .byte")
+                    .unwrap();
+            }
+            break;
+        };
+        let word = u32::from_le_bytes(bytes);
+
+        let mut notes = Vec::new();
+        if let Some(call) = debug
+            .line(addr)
+            .and_then(|l| l.expanded_from)
+            .and_then(|call| {
+                let text = sources.get(call.file as usize)?;
+                text.lines().nth(call.line.checked_sub(1)? as usize)
+            })
+        {
+            notes.push(call.trim().to_string());
+        }
+        let offset = match Instruction::try_from(word) {
+            Ok(Instruction::B(_, offset)) => Some(offset),
+            Ok(Instruction::Call(Operand::Imm(offset))) => {
+                Some(offset)
+            }
+            _ => None,
+        };
+        if let Some(offset) = offset {
+            let target = addr.wrapping_add(offset);
+            notes.push(format!("-> {}", debug.describe(target)));
+        }
+
+        let text = disassemble(word);
+        match notes.is_empty() {
+            true => writeln!(out, "{addr:08x} {word:08x}  {text}"),
+            false => writeln!(
+                out,
+                "{addr:08x} {word:08x}  {text:<18}  ; {}",
+                notes.join("  ")
+            ),
+        }
+        .unwrap();
+    }
+    out
+}
@@ -1,11 +1,19 @@
+use crate::memory::Access;
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Exception {
     InvalidMemoryAccess(u32),
-    StackOverflow,
-    StackUnderflow,
+    StackOverflow(u32),
+    StackUnderflow(u32),
     InvalidOp(u8),
     InvalidReg(u8),
     DivisionByZero,
+    /// `svc n`. Delivered with `EPC` at the `svc` itself, which a
+    /// handler steps over by adding 4 to `EPC` before `eret`.
+    Syscall(u32),
+    PrivilegeViolation(u32),
+    AccessViolation(u32, Access),
+    PageFault(u32, Access),
 
     UnknownSymbol(Box<str>, usize),
 }
@@ -16,4 +24,44 @@ impl Exception {
             *i = line;
         }
     }
+
+    /// Trap cause number written to `CAUSE` when the exception is
+    /// delivered to the guest, also the slot index in the vector
+    /// table. `None` for host-only errors which always abort.
+    pub fn cause(&self) -> Option<u32> {
+        Some(match self {
+            Exception::InvalidMemoryAccess(_) => 0,
+            Exception::StackOverflow(_) => 1,
+            Exception::StackUnderflow(_) => 2,
+            Exception::InvalidOp(_) => 3,
+            Exception::InvalidReg(_) => 4,
+            Exception::DivisionByZero => 5,
+            Exception::Syscall(_) => 6,
+            Exception::PrivilegeViolation(_) => 7,
+            Exception::AccessViolation(_, _) => 8,
+            Exception::PageFault(_, _) => 9,
+            Exception::UnknownSymbol(_, _) => return None,
+        })
+    }
+
+    /// Extra fault information written to `TVAL`: the faulting
+    /// address or stack pointer, opcode, register number, syscall
+    /// number or instruction word, zero otherwise.
+    pub fn value(&self) -> u32 {
+        match *self {
+            Exception::InvalidMemoryAccess(addr)
+            | Exception::AccessViolation(addr, _)
+            | Exception::PageFault(addr, _) => addr,
+            Exception::StackOverflow(sp)
+            | Exception::StackUnderflow(sp) => sp,
+            Exception::InvalidOp(op) => op as u32,
+            Exception::InvalidReg(reg) => reg as u32,
+            Exception::Syscall(n) => n,
+            Exception::PrivilegeViolation(word) => word,
+            _ => 0,
+        }
+    }
 }
+
+/// Number of slots in the exception vector table.
+pub const VECTOR_LEN: u32 = 10;
@@ -1,6 +1,15 @@
+pub mod archive;
 pub mod assembler;
-mod error;
+pub mod coverage;
+pub mod debug;
+pub mod disasm;
+pub mod error;
+pub mod linker;
 pub mod memory;
+pub mod mmu;
+pub mod mpu;
+pub mod object;
 pub mod opcode;
+pub mod profile;
 pub mod register;
 pub mod vm;
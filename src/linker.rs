@@ -0,0 +1,691 @@
+//! Links relocatable objects into a flat image, as `jld` does.
+//!
+//! Sections of the same name are concatenated in object order and
+//! placed as the linker script says, one directive per line:
+//!
+//! ```text
+//! ; text at 0, data wherever text ends, bss at 0x2000
+//! entry _main
+//! section text 0
+//! section data
+//! section bss 0x2000
+//! ```
+//!
+//! Sections the script doesn't name follow the last one placed, in
+//! the order the objects have them.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fmt::Write,
+    str::FromStr,
+};
+
+use crate::{
+    archive::Archive, debug::DebugInfo, debug::LineEntry,
+    object::Object,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    /// Sections in placement order and their fixed addresses.
+    pub sections: Vec<(Box<str>, Option<u32>)>,
+    /// Overrides the objects' `.entry`.
+    pub entry: Option<Box<str>>,
+}
+
+impl FromStr for Script {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut script = Script::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next(), fields.next()) {
+                (None, ..) => continue,
+                (Some("entry"), Some(name), None) => {
+                    script.entry = Some(name.into());
+                }
+                (Some("section"), Some(name), addr) => {
+                    let addr = addr
+                        .map(|a| match a.strip_prefix("0x") {
+                            Some(hex) => u32::from_str_radix(hex, 16),
+                            None => a.parse(),
+                        })
+                        .transpose()?;
+                    script.sections.push((name.into(), addr));
+                }
+                _ => {
+                    return Err(format!(
+                        "bad linker script directive at line {}",
+                        i + 1
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(script)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// `name` is `.global` in both objects.
+    Duplicate {
+        name: Box<str>,
+        first: Box<str>,
+        second: Box<str>,
+    },
+    /// `object` references `name` and nothing defines it.
+    Undefined { name: Box<str>, object: Box<str> },
+    /// The reference to `name` at `addr` is too far to encode.
+    OutOfRange { name: Box<str>, addr: u32 },
+    /// Two placed sections share addresses.
+    Overlap { first: Box<str>, second: Box<str> },
+    /// The entry symbol isn't defined.
+    NoEntry(Box<str>),
+    /// A section of `object` runs past the end of its code.
+    BadSection { name: Box<str>, object: Box<str> },
+    /// A relocation of `object` patches a word outside its sections.
+    BadReloc { offset: u32, object: Box<str> },
+    /// Placing section `name` runs past the end of memory.
+    NoRoom(Box<str>),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Duplicate {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "duplicate symbol '{name}' in {first} and {second}"
+            ),
+            Self::Undefined { name, object } => {
+                write!(f, "undefined symbol '{name}' in {object}")
+            }
+            Self::OutOfRange { name, addr } => write!(
+                f,
+                "reference to '{name}' at {addr:#010x} is out of range"
+            ),
+            Self::Overlap { first, second } => {
+                write!(f, "section {first} overlaps {second}")
+            }
+            Self::NoEntry(name) => {
+                write!(f, "entry symbol '{name}' is undefined")
+            }
+            Self::BadSection { name, object } => write!(
+                f,
+                "section {name} in {object} runs past its code"
+            ),
+            Self::BadReloc { offset, object } => write!(
+                f,
+                "relocation at {offset:#010x} in {object} is outside \
+                 its sections"
+            ),
+            Self::NoRoom(name) => {
+                write!(f, "section {name} doesn't fit below 4 GiB")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// An output section and the object sections that went into it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placed {
+    pub name: Box<str>,
+    pub addr: u32,
+    pub len: u32,
+    /// `(object, addr, len)`
+    pub inputs: Vec<(Box<str>, u32, u32)>,
+}
+
+/// Output of `link`.
+#[derive(Debug, Clone)]
+pub struct Linked {
+    /// Loaded at `base`.
+    pub image: Vec<u8>,
+    pub base: u32,
+    pub entry: Option<u32>,
+    pub sections: Vec<Placed>,
+    /// Global symbols by address.
+    pub globals: Vec<(u32, Box<str>)>,
+    pub debug: DebugInfo,
+}
+
+impl Linked {
+    /// Sections with where their inputs went, then every global.
+    pub fn map(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "base  {:08x}", self.base).unwrap();
+        if let Some(entry) = self.entry {
+            writeln!(
+                out,
+                "entry {entry:08x} {}",
+                self.debug.describe(entry)
+            )
+            .unwrap();
+        }
+        writeln!(out).unwrap();
+        writeln!(out, "; sections").unwrap();
+        for s in &self.sections {
+            writeln!(out, "{:08x} {:08x} {}", s.addr, s.len, s.name)
+                .unwrap();
+            for (object, addr, len) in &s.inputs {
+                writeln!(out, "    {addr:08x} {len:08x} {object}")
+                    .unwrap();
+            }
+        }
+        writeln!(out).unwrap();
+        writeln!(out, "; globals").unwrap();
+        for (addr, name) in &self.globals {
+            writeln!(out, "{addr:08x} {name}").unwrap();
+        }
+        out
+    }
+}
+
+/// The load address and entry point a `Linked::map` records, for
+/// running the image it was written next to.
+pub fn map_addresses(map: &str) -> (Option<u32>, Option<u32>) {
+    let (mut base, mut entry) = (None, None);
+    for line in map.lines() {
+        let mut fields = line.split_whitespace();
+        let slot = match fields.next() {
+            Some("base") => &mut base,
+            Some("entry") => &mut entry,
+            _ => continue,
+        };
+        *slot = fields
+            .next()
+            .and_then(|addr| u32::from_str_radix(addr, 16).ok());
+    }
+    (base, entry)
+}
+
+/// Members of `archives` that define what `objects` leave
+/// undefined, and the members those need in turn, each named
+/// `archive(member)`. The first archive defining a symbol supplies it.
+pub fn pull_members(
+    objects: &[(String, Object)],
+    archives: &[(String, Archive)],
+) -> Vec<(String, Object)> {
+    let mut globals = HashSet::new();
+    let mut wanted = Vec::new();
+    for (_, obj) in objects {
+        needs(obj, &mut globals, &mut wanted);
+    }
+
+    let mut pulled = Vec::<(String, Object)>::new();
+    while let Some(sym) = wanted.pop() {
+        if globals.contains(&sym) {
+            continue;
+        }
+        // anything no archive defines is left for `link` to report
+        let Some((name, obj)) =
+            archives.iter().find_map(|(path, a)| {
+                let (member, obj) = a.find(&sym)?;
+                Some((format!("{path}({member})"), obj))
+            })
+        else {
+            continue;
+        };
+        if pulled.iter().any(|p| p.0 == name) {
+            continue;
+        }
+        needs(obj, &mut globals, &mut wanted);
+        pulled.push((name, obj.clone()));
+    }
+    pulled
+}
+
+/// Note the globals `obj` defines and the symbols it references from
+/// elsewhere.
+fn needs(
+    obj: &Object,
+    globals: &mut HashSet<Box<str>>,
+    wanted: &mut Vec<Box<str>>,
+) {
+    for s in obj.symbols.iter().filter(|s| s.global) {
+        globals.insert(s.name.clone());
+    }
+    for r in &obj.relocs {
+        if !obj.symbols.iter().any(|s| s.name == r.symbol) {
+            wanted.push(r.symbol.clone());
+        }
+    }
+}
+
+/// Link `objects`, named for errors, every error found at once.
+pub fn link(
+    objects: &[(String, Object)],
+    script: &Script,
+) -> Result<Linked, Vec<LinkError>> {
+    let mut errors = Vec::new();
+
+    // everything the image is built from has to be in the code
+    for (obj_name, obj) in objects {
+        let fits = |start: u32, len: u32| {
+            start
+                .checked_add(len)
+                .is_some_and(|end| end as usize <= obj.code.len())
+        };
+        for s in obj.sections.iter().filter(|s| !fits(s.start, s.len))
+        {
+            errors.push(LinkError::BadSection {
+                name: s.name.clone(),
+                object: obj_name.as_str().into(),
+            });
+        }
+        let patchable = |offset: u32| {
+            obj.section_at(offset).is_some_and(|j| {
+                let s = &obj.sections[j];
+                let left = s.len.checked_sub(offset - s.start);
+                left.is_some_and(|left| left >= 4)
+            })
+        };
+        for r in obj.relocs.iter().filter(|r| !patchable(r.offset)) {
+            errors.push(LinkError::BadReloc {
+                offset: r.offset,
+                object: obj_name.as_str().into(),
+            });
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // placement order, script first
+    let mut order = script.sections.clone();
+    for (_, obj) in objects {
+        for s in &obj.sections {
+            if !order.iter().any(|o| o.0 == s.name) {
+                order.push((s.name.clone(), None));
+            }
+        }
+    }
+
+    // where each object section starts
+    let mut bases = objects
+        .iter()
+        .map(|(_, obj)| vec![0; obj.sections.len()])
+        .collect::<Vec<_>>();
+    let mut sections = Vec::new();
+    let mut cursor = 0;
+    for (name, addr) in order {
+        let start = addr.unwrap_or(cursor);
+        let mut at = start;
+        let mut inputs = Vec::new();
+        for (i, (obj_name, obj)) in objects.iter().enumerate() {
+            for (j, s) in obj.sections.iter().enumerate() {
+                if s.name == name {
+                    bases[i][j] = at;
+                    inputs.push((
+                        obj_name.as_str().into(),
+                        at,
+                        s.len,
+                    ));
+                    at = match at.checked_add(s.len) {
+                        Some(end) => end,
+                        None => {
+                            return Err(vec![LinkError::NoRoom(name)])
+                        }
+                    };
+                }
+            }
+        }
+        sections.push(Placed {
+            name,
+            addr: start,
+            len: at - start,
+            inputs,
+        });
+        cursor = at;
+    }
+
+    let mut by_addr =
+        sections.iter().filter(|s| s.len != 0).collect::<Vec<_>>();
+    by_addr.sort_by_key(|s| s.addr);
+    for pair in by_addr.windows(2) {
+        if pair[0].addr + pair[0].len > pair[1].addr {
+            errors.push(LinkError::Overlap {
+                first: pair[0].name.clone(),
+                second: pair[1].name.clone(),
+            });
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let base = by_addr.first().map_or(0, |s| s.addr);
+    let end =
+        by_addr.iter().map(|s| s.addr + s.len).max().unwrap_or(0);
+
+    // final address of an offset into object `i`
+    let addr_of = |i: usize, offset: u32| {
+        let obj = &objects[i].1;
+        obj.section_at(offset).map_or(offset, |j| {
+            bases[i][j] + offset - obj.sections[j].start
+        })
+    };
+
+    let mut globals = HashMap::<&str, (u32, usize)>::new();
+    for (i, (name, obj)) in objects.iter().enumerate() {
+        for s in obj.symbols.iter().filter(|s| s.global) {
+            match globals.get(&*s.name) {
+                Some(&(_, first)) => {
+                    errors.push(LinkError::Duplicate {
+                        name: s.name.clone(),
+                        first: objects[first].0.as_str().into(),
+                        second: name.as_str().into(),
+                    })
+                }
+                None => {
+                    globals.insert(&s.name, (addr_of(i, s.addr), i));
+                }
+            }
+        }
+    }
+
+    let mut image = vec![0; (end - base) as usize];
+    for (i, (_, obj)) in objects.iter().enumerate() {
+        for (j, s) in obj.sections.iter().enumerate() {
+            let from =
+                &obj.code[s.start as usize..][..s.len as usize];
+            let at = (bases[i][j] - base) as usize;
+            image[at..][..from.len()].copy_from_slice(from);
+        }
+    }
+
+    // a symbol as object `i` sees it, its own labels first
+    let resolve = |i: usize, name: &str| {
+        let obj = &objects[i].1;
+        match obj.symbols.iter().find(|s| &*s.name == name) {
+            Some(s) => Some(addr_of(i, s.addr)),
+            None => globals.get(name).map(|g| g.0),
+        }
+    };
+
+    for (i, (obj_name, obj)) in objects.iter().enumerate() {
+        for r in &obj.relocs {
+            let Some(target) = resolve(i, &r.symbol) else {
+                errors.push(LinkError::Undefined {
+                    name: r.symbol.clone(),
+                    object: obj_name.as_str().into(),
+                });
+                continue;
+            };
+            let pc = addr_of(i, r.offset);
+            let at = (pc - base) as usize;
+            let word = u32::from_le_bytes(
+                image[at..][..4].try_into().unwrap(),
+            );
+            let value = match r.kind.is_absolute() {
+                true => target,
+                false => target.wrapping_sub(pc),
+            };
+            match r.kind.patch(word, value) {
+                Some(word) => image[at..][..4]
+                    .copy_from_slice(&word.to_le_bytes()),
+                None => errors.push(LinkError::OutOfRange {
+                    name: r.symbol.clone(),
+                    addr: pc,
+                }),
+            }
+        }
+    }
+
+    let entry = match &script.entry {
+        Some(name) => Some((name, globals.get(&**name).map(|g| g.0))),
+        None => {
+            objects.iter().enumerate().find_map(|(i, (_, obj))| {
+                let name = obj.entry.as_ref()?;
+                Some((name, resolve(i, name)))
+            })
+        }
+    };
+    let entry = match entry {
+        Some((_, Some(addr))) => Some(addr),
+        Some((name, None)) => {
+            errors.push(LinkError::NoEntry(name.clone()));
+            None
+        }
+        None => None,
+    };
+
+    let mut debug = DebugInfo::default();
+    for (i, (_, obj)) in objects.iter().enumerate() {
+        let files = obj
+            .debug
+            .files()
+            .map(|f| debug.add_file(f))
+            .collect::<Vec<_>>();
+        let renumber = |mut loc: crate::debug::Loc| {
+            loc.file =
+                files.get(loc.file as usize).copied().unwrap_or(0);
+            loc
+        };
+        for l in obj.debug.lines() {
+            debug.add_line(LineEntry {
+                addr: addr_of(i, l.addr),
+                loc: renumber(l.loc),
+                expanded_from: l.expanded_from.map(renumber),
+            });
+        }
+        for s in &obj.symbols {
+            debug.add_symbol(addr_of(i, s.addr), &s.name);
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut globals = globals
+        .into_iter()
+        .map(|(name, (addr, _))| (addr, name.into()))
+        .collect::<Vec<_>>();
+    globals.sort();
+    Ok(Linked {
+        image,
+        base,
+        entry,
+        sections,
+        globals,
+        debug,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assembler::assemble,
+        opcode::{Instruction, Operand},
+        register::R1,
+    };
+
+    fn object(name: &str, source: &str) -> (String, Object) {
+        (name.into(), Object::from(&assemble(name, source)))
+    }
+
+    fn caller() -> (String, Object) {
+        object(
+            "main.o",
+            "
+.extern answer
+.global _start
+_start:
+    la r1, answer
+    call r1
+",
+        )
+    }
+
+    fn callee() -> (String, Object) {
+        object(
+            "answer.o",
+            "
+.global answer
+    nop
+answer:
+    exit #42
+",
+        )
+    }
+
+    #[test]
+    fn patches_references() {
+        let linked =
+            link(&[caller(), callee()], &Script::default()).unwrap();
+        let answer = linked.debug.symbol_addr("answer").unwrap();
+        assert_eq!(answer, 20);
+        let word = |at: usize| {
+            let bytes = linked.image[at..][..4].try_into().unwrap();
+            Instruction::try_from(u32::from_le_bytes(bytes)).unwrap()
+        };
+        // la r1, answer
+        assert_eq!(word(0), Instruction::Ldr(R1, Operand::Imm(0)));
+        assert_eq!(
+            word(8),
+            Instruction::Add(R1, R1, Operand::Imm(answer))
+        );
+    }
+
+    #[test]
+    fn map_records_base_and_entry() {
+        let script = "entry answer\nsection text 0x100"
+            .parse::<Script>()
+            .unwrap();
+        let linked = link(&[caller(), callee()], &script).unwrap();
+        assert_eq!((linked.base, linked.entry), (0x100, Some(0x114)));
+        let map = linked.map();
+        assert!(map.starts_with(
+            "base  00000100\nentry 00000114 answer\n\n"
+        ));
+        assert_eq!(map_addresses(&map), (Some(0x100), Some(0x114)));
+
+        let linked = link(&[callee()], &Script::default()).unwrap();
+        assert_eq!(map_addresses(&linked.map()), (Some(0), None));
+    }
+
+    #[test]
+    fn reloc_outside_sections() {
+        for offset in [0x40, 14] {
+            let mut main = caller();
+            main.1.relocs[0].offset = offset;
+            let errors = link(&[main, callee()], &Script::default())
+                .unwrap_err();
+            assert_eq!(
+                errors,
+                [LinkError::BadReloc {
+                    offset,
+                    object: "main.o".into()
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn section_past_code() {
+        let mut main = caller();
+        main.1.sections[0].len += 16;
+        let errors =
+            link(&[main, callee()], &Script::default()).unwrap_err();
+        assert_eq!(
+            errors,
+            [LinkError::BadSection {
+                name: "text".into(),
+                object: "main.o".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn overlap() {
+        let obj = object(
+            "sections.o",
+            "
+.section text
+    nop
+    nop
+.section data
+    nop
+",
+        );
+        let script =
+            "section text 0\nsection data 4".parse().unwrap();
+        let errors = link(&[obj], &script).unwrap_err();
+        assert_eq!(
+            errors,
+            [LinkError::Overlap {
+                first: "text".into(),
+                second: "data".into()
+            }]
+        );
+
+        let script = "section text 0xfffffffc".parse().unwrap();
+        let errors = link(&[caller()], &script).unwrap_err();
+        assert_eq!(errors, [LinkError::NoRoom("text".into())]);
+    }
+
+    fn library(
+        path: &str,
+        members: &[(&str, &str)],
+    ) -> (String, Archive) {
+        let mut archive = Archive::new();
+        for (name, source) in members {
+            archive.add(name, object(name, source).1);
+        }
+        (path.into(), archive)
+    }
+
+    fn names(pulled: &[(String, Object)]) -> Vec<&str> {
+        pulled.iter().map(|p| p.0.as_str()).collect()
+    }
+
+    #[test]
+    fn pulls_needed_members() {
+        let lib = library(
+            "lib.a",
+            &[
+                ("unused.o", ".global unused\nunused:\n    ret\n"),
+                ("answer.o", ".extern helper\n.global answer\nanswer:\n    call helper\n    ret\n"),
+                ("helper.o", ".global helper\nhelper:\n    ret\n"),
+            ],
+        );
+        let pulled = pull_members(&[caller()], &[lib]);
+        assert_eq!(
+            names(&pulled),
+            ["lib.a(answer.o)", "lib.a(helper.o)"]
+        );
+
+        // nothing for what the objects define themselves
+        let lib = library(
+            "lib.a",
+            &[("answer.o", ".global answer\nanswer:\n    ret\n")],
+        );
+        let pulled = pull_members(&[caller(), callee()], &[lib]);
+        assert!(pulled.is_empty());
+    }
+
+    #[test]
+    fn first_archive_wins() {
+        let answer = ".global answer\nanswer:\n    ret\n";
+        let first = library("first.a", &[("a.o", answer)]);
+        let second = library("second.a", &[("b.o", answer)]);
+        let pulled = pull_members(&[caller()], &[first, second]);
+        assert_eq!(names(&pulled), ["first.a(a.o)"]);
+
+        // and a symbol nothing defines is left to link
+        let pulled = pull_members(
+            &[object("x.o", ".extern nowhere\n    call nowhere\n")],
+            &[],
+        );
+        assert!(pulled.is_empty());
+    }
+}
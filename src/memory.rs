@@ -1,43 +1,72 @@
-use crate::error::Exception;
-use std::fmt;
+use crate::{
+    error::Exception,
+    mmu::{PAGE_MASK, PAGE_SHIFT, PAGE_SIZE},
+};
+use std::{collections::HashMap, fmt};
 
 pub const MEMORY_LEN: usize = 10 * 1024;
 pub const STACK_LEN: usize = 1024;
 
+/// Kind of memory access, reported with protection faults.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
 pub trait Addressable: fmt::Debug {
     fn read(&self, addr: u32) -> Result<u8, Exception>;
-    fn write(&mut self, addr: u32, value: u8) -> Result<(), Exception>;
+    fn write(
+        &mut self,
+        addr: u32,
+        value: u8,
+    ) -> Result<(), Exception>;
 
     fn read_u16(&self, addr: u32) -> Result<u16, Exception> {
-        Ok(u16::from_le_bytes([self.read(addr)?, self.read(addr + 1)?]))
+        Ok(u16::from_le_bytes([
+            self.read(addr)?,
+            self.read(addr.wrapping_add(1))?,
+        ]))
     }
 
-    fn write_u16(&mut self, addr: u32, value: u16) -> Result<(), Exception> {
-        value
-            .to_le_bytes()
-            .into_iter()
-            .enumerate()
-            .try_for_each(|(i, byte)| self.write(addr + i as u32, byte))
+    fn write_u16(
+        &mut self,
+        addr: u32,
+        value: u16,
+    ) -> Result<(), Exception> {
+        value.to_le_bytes().into_iter().enumerate().try_for_each(
+            |(i, byte)| self.write(addr.wrapping_add(i as u32), byte),
+        )
     }
 
     fn read_u32(&self, addr: u32) -> Result<u32, Exception> {
         Ok(u32::from_le_bytes([
             self.read(addr)?,
-            self.read(addr + 1)?,
-            self.read(addr + 2)?,
-            self.read(addr + 3)?,
+            self.read(addr.wrapping_add(1))?,
+            self.read(addr.wrapping_add(2))?,
+            self.read(addr.wrapping_add(3))?,
         ]))
     }
 
-    fn write_u32(&mut self, addr: u32, value: u32) -> Result<(), Exception> {
-        value
-            .to_le_bytes()
-            .into_iter()
-            .enumerate()
-            .try_for_each(|(i, byte)| self.write(addr + i as u32, byte))
+    fn write_u32(
+        &mut self,
+        addr: u32,
+        value: u32,
+    ) -> Result<(), Exception> {
+        value.to_le_bytes().into_iter().enumerate().try_for_each(
+            |(i, byte)| self.write(addr.wrapping_add(i as u32), byte),
+        )
     }
 
-    fn copy(&mut self, from: u32, to: u32, n: usize) -> Result<(), Exception> {
+    fn copy(
+        &mut self,
+        from: u32,
+        to: u32,
+        n: usize,
+    ) -> Result<(), Exception> {
         (0..n).try_for_each(|idx| {
             let idx = idx as u32;
             self.write(to + idx, self.read(from + idx)?)
@@ -52,12 +81,133 @@ impl<const N: usize> Addressable for [u8; N] {
             .ok_or(Exception::InvalidMemoryAccess(addr))
     }
 
-    fn write(&mut self, addr: u32, value: u8) -> Result<(), Exception> {
-        if self.len() > MEMORY_LEN {
-            return Err(Exception::InvalidMemoryAccess(addr));
+    fn write(
+        &mut self,
+        addr: u32,
+        value: u8,
+    ) -> Result<(), Exception> {
+        let byte = self
+            .get_mut(addr as usize)
+            .ok_or(Exception::InvalidMemoryAccess(addr))?;
+        *byte = value;
+        Ok(())
+    }
+}
+
+impl Addressable for Vec<u8> {
+    fn read(&self, addr: u32) -> Result<u8, Exception> {
+        self.get(addr as usize)
+            .copied()
+            .ok_or(Exception::InvalidMemoryAccess(addr))
+    }
+
+    fn write(
+        &mut self,
+        addr: u32,
+        value: u8,
+    ) -> Result<(), Exception> {
+        let byte = self
+            .get_mut(addr as usize)
+            .ok_or(Exception::InvalidMemoryAccess(addr))?;
+        *byte = value;
+        Ok(())
+    }
+}
+
+type Page = Box<[u8; PAGE_SIZE as usize]>;
+
+/// The whole 32 bit address space, backed by 4 KiB pages allocated
+/// on first write. Unwritten memory reads as zero.
+#[derive(Default)]
+pub struct SparseMemory {
+    pages: HashMap<u32, Page>,
+}
+
+impl SparseMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of pages allocated so far.
+    pub fn pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn page(&self, addr: u32) -> Option<&Page> {
+        self.pages.get(&(addr >> PAGE_SHIFT))
+    }
+
+    fn page_mut(&mut self, addr: u32) -> &mut Page {
+        self.pages
+            .entry(addr >> PAGE_SHIFT)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE as usize]))
+    }
+}
+
+impl fmt::Debug for SparseMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SparseMemory")
+            .field("pages", &self.pages.len())
+            .finish()
+    }
+}
+
+impl Addressable for SparseMemory {
+    fn read(&self, addr: u32) -> Result<u8, Exception> {
+        Ok(self
+            .page(addr)
+            .map_or(0, |p| p[(addr & PAGE_MASK) as usize]))
+    }
+
+    fn write(
+        &mut self,
+        addr: u32,
+        value: u8,
+    ) -> Result<(), Exception> {
+        self.page_mut(addr)[(addr & PAGE_MASK) as usize] = value;
+        Ok(())
+    }
+
+    // one page lookup for words that don't straddle a page
+    fn read_u32(&self, addr: u32) -> Result<u32, Exception> {
+        let off = (addr & PAGE_MASK) as usize;
+        if off > (PAGE_MASK - 3) as usize {
+            return Ok(u32::from_le_bytes([
+                self.read(addr)?,
+                self.read(addr.wrapping_add(1))?,
+                self.read(addr.wrapping_add(2))?,
+                self.read(addr.wrapping_add(3))?,
+            ]));
+        }
+
+        Ok(self.page(addr).map_or(0, |p| {
+            u32::from_le_bytes([
+                p[off],
+                p[off + 1],
+                p[off + 2],
+                p[off + 3],
+            ])
+        }))
+    }
+
+    fn write_u32(
+        &mut self,
+        addr: u32,
+        value: u32,
+    ) -> Result<(), Exception> {
+        let off = (addr & PAGE_MASK) as usize;
+        if off > (PAGE_MASK - 3) as usize {
+            return value
+                .to_le_bytes()
+                .into_iter()
+                .enumerate()
+                .try_for_each(|(i, byte)| {
+                    self.write(addr.wrapping_add(i as u32), byte)
+                });
         }
 
-        self[addr as usize] = value;
+        self.page_mut(addr)[off..off + 4]
+            .copy_from_slice(&value.to_le_bytes());
         Ok(())
     }
 }
@@ -96,9 +246,15 @@ where
         }
     }
 
+    /// The stack pointer, counted in slots from the bottom, which
+    /// the stack exceptions carry.
+    pub fn sp(&self) -> u32 {
+        self.len as u32
+    }
+
     pub fn push(&mut self, value: T) -> Result<(), Exception> {
         if self.data.len() <= self.len {
-            return Err(Exception::StackOverflow);
+            return Err(Exception::StackOverflow(self.sp()));
         }
 
         self.data[self.len] = value;
@@ -108,7 +264,7 @@ where
 
     pub fn pop(&mut self) -> Result<T, Exception> {
         if self.len == 0 {
-            return Err(Exception::StackUnderflow);
+            return Err(Exception::StackUnderflow(self.sp()));
         }
 
         self.len -= 1;
@@ -140,3 +296,65 @@ where
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_untouched() {
+        let mem = SparseMemory::new();
+        for addr in [0, 0x1234_5678, 0xffff_fffc] {
+            assert_eq!(mem.read(addr), Ok(0));
+            assert_eq!(mem.read_u32(addr), Ok(0));
+        }
+        // straddling two missing pages
+        assert_eq!(mem.read_u32(0x0fff), Ok(0));
+        assert_eq!(mem.pages(), 0);
+    }
+
+    #[test]
+    fn sparse_page_boundary() {
+        let mut mem = SparseMemory::new();
+        for addr in 0x0ffd..=0x1000 {
+            mem.write_u32(addr, 0x4433_2211).unwrap();
+            assert_eq!(mem.read_u32(addr), Ok(0x4433_2211));
+            assert_eq!(mem.read_u16(addr), Ok(0x2211));
+            assert_eq!(mem.read(addr + 3), Ok(0x44));
+        }
+        assert_eq!(mem.pages(), 2);
+        assert_eq!(mem.read_u32(0x0ffc), Ok(0x1111_1100));
+
+        // around the top of the address space
+        mem.write_u32(0xffff_fffe, 0xddcc_bbaa).unwrap();
+        assert_eq!(mem.read_u32(0xffff_fffe), Ok(0xddcc_bbaa));
+        assert_eq!(mem.read(1), Ok(0xdd));
+        assert_eq!(mem.pages(), 3);
+    }
+
+    #[test]
+    fn flat_bounds() {
+        let mut mem = vec![0; 8];
+        assert_eq!(mem.write_u32(4, 1), Ok(()));
+        assert_eq!(
+            mem.write_u32(6, 1),
+            Err(Exception::InvalidMemoryAccess(8))
+        );
+        assert_eq!(
+            mem.read_u32(5),
+            Err(Exception::InvalidMemoryAccess(8))
+        );
+    }
+
+    #[test]
+    fn stack_bounds() {
+        let mut stack = Stack::<u32, 2>::new();
+        assert_eq!(stack.pop(), Err(Exception::StackUnderflow(0)));
+        stack.push(1).unwrap();
+        stack.push(2).unwrap();
+        assert_eq!(stack.push(3), Err(Exception::StackOverflow(2)));
+        assert_eq!(stack.peek_at(1), Some(1));
+        assert_eq!(stack.pop(), Ok(2));
+        assert_eq!(stack.sp(), 1);
+    }
+}
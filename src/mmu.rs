@@ -0,0 +1,99 @@
+use crate::{
+    error::Exception,
+    memory::{Access, Addressable},
+};
+
+pub const PAGE_SHIFT: u32 = 12;
+pub const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+pub const PAGE_MASK: u32 = PAGE_SIZE - 1;
+
+// page table entry bits, the frame address lives in bits 31:12
+pub const PTE_V: u32 = 1 << 0;
+pub const PTE_R: u32 = 1 << 1;
+pub const PTE_W: u32 = 1 << 2;
+pub const PTE_X: u32 = 1 << 3;
+pub const PTE_U: u32 = 1 << 4;
+
+pub const TLB_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct TlbEntry {
+    vpn: u32,
+    pte: u32,
+    valid: bool,
+}
+
+/// Direct mapped software TLB in front of a two level page table.
+///
+/// `PTBR` holds the physical address of a 4 KiB level one table,
+/// indexed by address bits 31:22. Its valid entries point at level
+/// two tables indexed by bits 21:12, whose entries map the page.
+#[derive(Debug, Clone, Default)]
+pub struct Tlb {
+    entries: [TlbEntry; TLB_LEN],
+}
+
+impl Tlb {
+    pub fn flush(&mut self) {
+        self.entries = Default::default();
+    }
+
+    /// Translate `vaddr` to a physical address, walking the tables
+    /// in `mem` on a miss. User mode needs `PTE_U` on the page.
+    pub fn translate(
+        &mut self,
+        mem: &dyn Addressable,
+        ptbr: u32,
+        vaddr: u32,
+        access: Access,
+        user: bool,
+    ) -> Result<u32, Exception> {
+        let vpn = vaddr >> PAGE_SHIFT;
+        let entry = &mut self.entries[vpn as usize % TLB_LEN];
+        let pte = if entry.valid && entry.vpn == vpn {
+            entry.pte
+        } else {
+            let pte = walk(mem, ptbr, vaddr, access)?;
+            *entry = TlbEntry {
+                vpn,
+                pte,
+                valid: true,
+            };
+            pte
+        };
+
+        let bit = match access {
+            Access::Read => PTE_R,
+            Access::Write => PTE_W,
+            Access::Execute => PTE_X,
+        };
+        if pte & bit == 0 || (user && pte & PTE_U == 0) {
+            return Err(Exception::PageFault(vaddr, access));
+        }
+
+        Ok(pte & !PAGE_MASK | vaddr & PAGE_MASK)
+    }
+}
+
+fn walk(
+    mem: &dyn Addressable,
+    ptbr: u32,
+    vaddr: u32,
+    access: Access,
+) -> Result<u32, Exception> {
+    let fault = Exception::PageFault(vaddr, access);
+
+    let l1 = mem.read_u32((ptbr & !PAGE_MASK) + (vaddr >> 22) * 4)?;
+    if l1 & PTE_V == 0 {
+        return Err(fault);
+    }
+
+    let l2 = mem.read_u32(
+        (l1 & !PAGE_MASK) + ((vaddr >> PAGE_SHIFT) & 0x3ff) * 4,
+    )?;
+    if l2 & PTE_V == 0 {
+        return Err(fault);
+    }
+
+    Ok(l2)
+}
@@ -0,0 +1,152 @@
+use crate::{error::Exception, memory::Access};
+
+pub const PERM_R: u8 = 1 << 0;
+pub const PERM_W: u8 = 1 << 1;
+pub const PERM_X: u8 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u32,
+    pub len: u32,
+    pub perm: u8,
+}
+
+impl Region {
+    pub fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.start) < self.len
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        let bit = match access {
+            Access::Read => PERM_R,
+            Access::Write => PERM_W,
+            Access::Execute => PERM_X,
+        };
+        self.perm & bit != 0
+    }
+}
+
+/// Memory protection unit. While enabled every instruction fetch
+/// and data access must fall inside a region granting it, where
+/// later regions take priority over earlier overlapping ones.
+/// Anything outside all regions is denied.
+#[derive(Debug, Clone, Default)]
+pub struct Mpu {
+    regions: Vec<Region>,
+    enabled: bool,
+}
+
+impl Mpu {
+    pub fn add_region(&mut self, start: u32, len: u32, perm: u8) {
+        self.regions.push(Region { start, len, perm });
+    }
+
+    pub fn clear(&mut self) {
+        self.regions.clear();
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn enable(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Check a `size` byte access at `addr`, failing at the first
+    /// byte denied.
+    #[inline]
+    pub fn check(
+        &self,
+        addr: u32,
+        size: u32,
+        access: Access,
+    ) -> Result<(), Exception> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        // every byte, as a region smaller than the access can deny
+        // just its middle, and accesses are at most a word
+        for a in (0..size.max(1)).map(|i| addr.wrapping_add(i)) {
+            let allowed = self
+                .regions
+                .iter()
+                .rev()
+                .find(|r| r.contains(a))
+                .is_some_and(|r| r.allows(access));
+            if !allowed {
+                return Err(Exception::AccessViolation(a, access));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mpu() -> Mpu {
+        let mut mpu = Mpu::default();
+        mpu.add_region(0x0000, 0x1000, PERM_R | PERM_X);
+        mpu.add_region(0x1000, 0x1000, PERM_R | PERM_W);
+        mpu.add_region(0x2000, 0x1000, PERM_W);
+        mpu.enable(true);
+        mpu
+    }
+
+    #[test]
+    fn permissions() {
+        let mpu = mpu();
+        let denied = |addr, access| {
+            mpu.check(addr, 4, access)
+                == Err(Exception::AccessViolation(addr, access))
+        };
+        for (addr, r, w, x) in [
+            (0x0000, true, false, true),
+            (0x1000, true, true, false),
+            (0x2000, false, true, false),
+            (0x3000, false, false, false),
+        ] {
+            assert_eq!(!denied(addr, Access::Read), r, "{addr:#x}");
+            assert_eq!(!denied(addr, Access::Write), w, "{addr:#x}");
+            assert_eq!(
+                !denied(addr, Access::Execute),
+                x,
+                "{addr:#x}"
+            );
+        }
+
+        let mut off = mpu.clone();
+        off.enable(false);
+        assert_eq!(off.check(0x3000, 4, Access::Write), Ok(()));
+    }
+
+    #[test]
+    fn whole_access() {
+        let mut mpu = mpu();
+        // straddling into a region without the permission
+        assert_eq!(mpu.check(0x0ffe, 4, Access::Read), Ok(()));
+        assert_eq!(
+            mpu.check(0x0ffe, 4, Access::Write),
+            Err(Exception::AccessViolation(0x0ffe, Access::Write))
+        );
+        assert_eq!(
+            mpu.check(0x1ffe, 4, Access::Read),
+            Err(Exception::AccessViolation(0x2000, Access::Read))
+        );
+
+        // a later region inside the access, neither end in it
+        mpu.add_region(0x1001, 1, 0);
+        assert_eq!(
+            mpu.check(0x1000, 4, Access::Read),
+            Err(Exception::AccessViolation(0x1001, Access::Read))
+        );
+        assert_eq!(mpu.check(0x1002, 2, Access::Read), Ok(()));
+    }
+}
@@ -0,0 +1,316 @@
+//! Relocatable objects, written by `jasm -c` and linked by `jld`,
+//! one record per line. Addresses are offsets into the object's code,
+//! which holds its sections back to back:
+//!
+//! ```text
+//! section text 00000000 00000010
+//! code b0180003 90108001 94180000 66000000
+//! sym 00000000 _main global
+//! extern helper
+//! reloc 00000008 call24 helper
+//! entry _main
+//! debug line 00000000 0:7:2
+//! ```
+//!
+//! `debug` records carry the object's debug info in its own format.
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+    assembler::{symbols::SymbolKind, Assembly},
+    debug::DebugInfo,
+    opcode::{Instruction, Operand},
+};
+
+/// How a reference to a symbol is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocKind {
+    /// Signed 20 bit pc relative offset of a `b`.
+    Branch20,
+    /// Signed 24 bit pc relative offset of a `call`.
+    Call24,
+    /// Upper 19 bits of an address, the `ldr` starting an `la`.
+    Hi19,
+    /// Low 13 bits of an address, the `add` ending an `la`.
+    Lo13,
+}
+
+impl RelocKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Branch20 => "b20",
+            Self::Call24 => "call24",
+            Self::Hi19 => "hi19",
+            Self::Lo13 => "lo13",
+        }
+    }
+
+    /// Whether it takes the symbol's address rather than an offset
+    /// from the referencing instruction.
+    pub fn is_absolute(self) -> bool {
+        matches!(self, Self::Hi19 | Self::Lo13)
+    }
+
+    /// `word` with its offset replaced by `value`, the offset or
+    /// address as `is_absolute` says, `None` if it's not an
+    /// instruction of this kind or `value` doesn't fit.
+    pub fn patch(self, word: u32, value: u32) -> Option<u32> {
+        // an address always fits its two halves
+        let bits = match self {
+            Self::Branch20 => 20,
+            Self::Call24 => 24,
+            Self::Hi19 | Self::Lo13 => 32,
+        };
+        let limit = 1i64 << (bits - 1);
+        if !(-limit..limit).contains(&(value as i32 as i64)) {
+            return None;
+        }
+        let ins = match (self, Instruction::try_from(word).ok()?) {
+            (Self::Branch20, Instruction::B(cond, _)) => {
+                Instruction::B(cond, value)
+            }
+            (Self::Call24, Instruction::Call(Operand::Imm(_))) => {
+                Instruction::Call(Operand::Imm(value))
+            }
+            (Self::Hi19, Instruction::Ldr(r, Operand::Imm(_))) => {
+                Instruction::Ldr(r, Operand::Imm(value >> 13))
+            }
+            (
+                Self::Lo13,
+                Instruction::Add(rd, rs, Operand::Imm(_)),
+            ) => {
+                Instruction::Add(rd, rs, Operand::Imm(value & 0x1fff))
+            }
+            _ => return None,
+        };
+        u32::try_from(ins).ok()
+    }
+}
+
+impl FromStr for RelocKind {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "b20" => Self::Branch20,
+            "call24" => Self::Call24,
+            "hi19" => Self::Hi19,
+            "lo13" => Self::Lo13,
+            _ => {
+                return Err(format!("unknown relocation '{s}'").into())
+            }
+        })
+    }
+}
+
+/// A reference to `symbol` by the instruction at `offset`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reloc {
+    pub offset: u32,
+    pub kind: RelocKind,
+    pub symbol: Box<str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: Box<str>,
+    pub start: u32,
+    pub len: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjSymbol {
+    pub name: Box<str>,
+    pub addr: u32,
+    /// Exported with `.global`.
+    pub global: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub code: Vec<u8>,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<ObjSymbol>,
+    pub externs: Vec<Box<str>>,
+    pub relocs: Vec<Reloc>,
+    pub entry: Option<Box<str>>,
+    pub debug: DebugInfo,
+}
+
+impl Object {
+    /// Index of the section `offset` is in, the one it ends for a
+    /// label past the last instruction.
+    pub fn section_at(&self, offset: u32) -> Option<usize> {
+        self.sections.iter().rposition(|s| s.start <= offset)
+    }
+}
+
+impl From<&Assembly> for Object {
+    fn from(asm: &Assembly) -> Self {
+        let end = asm.code.len() as u32;
+        let mut starts = asm
+            .symbols
+            .iter()
+            .filter(|s| s.kind == SymbolKind::Section)
+            .map(|s| (s.name.clone(), s.addr))
+            .collect::<Vec<_>>();
+        // code ahead of the first .section
+        if starts.first().is_none_or(|s| s.1 != 0) {
+            starts.insert(0, ("text".into(), 0));
+        }
+        let sections = starts
+            .iter()
+            .enumerate()
+            .map(|(i, (name, start))| Section {
+                name: name.clone(),
+                start: *start,
+                len: starts.get(i + 1).map_or(end, |s| s.1) - start,
+            })
+            .collect();
+
+        let symbols = asm
+            .symbols
+            .iter()
+            .filter(|s| s.kind == SymbolKind::Label)
+            .map(|s| ObjSymbol {
+                name: s.name.clone(),
+                addr: s.addr,
+                global: asm.globals.contains(&s.name),
+            })
+            .collect();
+
+        Object {
+            code: asm.code.clone(),
+            sections,
+            symbols,
+            externs: asm.externs.clone(),
+            relocs: asm.relocs.clone(),
+            entry: asm.entry.clone(),
+            debug: asm.debug.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for s in &self.sections {
+            writeln!(
+                f,
+                "section {} {:08x} {:08x}",
+                s.name, s.start, s.len
+            )?;
+        }
+        for words in self.code.chunks(32) {
+            write!(f, "code")?;
+            for word in words.chunks(4) {
+                let word =
+                    u32::from_le_bytes(word.try_into().unwrap());
+                write!(f, " {word:08x}")?;
+            }
+            writeln!(f)?;
+        }
+        for s in &self.symbols {
+            let scope = if s.global { "global" } else { "local" };
+            writeln!(f, "sym {:08x} {} {scope}", s.addr, s.name)?;
+        }
+        for name in &self.externs {
+            writeln!(f, "extern {name}")?;
+        }
+        for r in &self.relocs {
+            writeln!(
+                f,
+                "reloc {:08x} {} {}",
+                r.offset,
+                r.kind.name(),
+                r.symbol
+            )?;
+        }
+        if let Some(entry) = &self.entry {
+            writeln!(f, "entry {entry}")?;
+        }
+        for line in self.debug.to_string().lines() {
+            writeln!(f, "debug {line}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Object {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut obj = Object::default();
+        let mut debug = String::new();
+        for (i, line) in s.lines().enumerate() {
+            let truncated =
+                || format!("truncated record at line {}", i + 1);
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                None => continue,
+                Some("section") => {
+                    let (Some(name), Some(start), Some(len)) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        return Err(truncated().into());
+                    };
+                    obj.sections.push(Section {
+                        name: name.into(),
+                        start: u32::from_str_radix(start, 16)?,
+                        len: u32::from_str_radix(len, 16)?,
+                    });
+                }
+                Some("code") => {
+                    for word in fields {
+                        let word = u32::from_str_radix(word, 16)?;
+                        obj.code.extend(word.to_le_bytes());
+                    }
+                }
+                Some("sym") => {
+                    let (Some(addr), Some(name), Some(scope)) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        return Err(truncated().into());
+                    };
+                    obj.symbols.push(ObjSymbol {
+                        name: name.into(),
+                        addr: u32::from_str_radix(addr, 16)?,
+                        global: scope == "global",
+                    });
+                }
+                Some("extern") => {
+                    let name = fields.next().ok_or_else(truncated)?;
+                    obj.externs.push(name.into());
+                }
+                Some("reloc") => {
+                    let (Some(offset), Some(kind), Some(symbol)) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        return Err(truncated().into());
+                    };
+                    obj.relocs.push(Reloc {
+                        offset: u32::from_str_radix(offset, 16)?,
+                        kind: kind.parse()?,
+                        symbol: symbol.into(),
+                    });
+                }
+                Some("entry") => {
+                    let name = fields.next().ok_or_else(truncated)?;
+                    obj.entry = Some(name.into());
+                }
+                Some("debug") => {
+                    debug += line["debug".len()..].trim_start();
+                    debug.push('\n');
+                }
+                Some(kind) => {
+                    return Err(format!(
+                        "unknown record '{kind}' at line {}",
+                        i + 1
+                    )
+                    .into())
+                }
+            }
+        }
+        obj.debug = debug.parse()?;
+        Ok(obj)
+    }
+}
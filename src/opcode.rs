@@ -1,22 +1,45 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
-use crate::{error::Exception, register::Register, vm::OP_LEN};
+use crate::{
+    error::Exception,
+    register::{
+        Register, SpecialRegister, FLAG_C, FLAG_N, FLAG_V, FLAG_Z,
+    },
+    vm::OP_LEN,
+};
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub enum Op {
     // misc
     Nop = 0x6f,
+    Eret = 0x60,
+    Cli = 0x61,
+    Sti = 0x62,
+    TlbFlush = 0x63,
+    Mrs = 0x64,
+    Msr = 0x65,
+    Halt = 0x66,
+    Exit = 0x67,
 
     // arithmetic
     Add = 0x10,
     Sub = 0x11,
     Mul = 0x12,
     Div = 0x13,
+    Cmp = 0x14,
 
     // store load
     Ldr = 0x30,
     Push = 0x33,
     Pop = 0x34,
+
+    // branch
+    B = 0x50,
+    Call = 0x51,
+    Ret = 0x52,
+
+    // syscall
+    Svc = 0x70,
 }
 
 impl TryFrom<u8> for Op {
@@ -27,15 +50,30 @@ impl TryFrom<u8> for Op {
         let value = value & 0x7f;
         Ok(match value {
             0x6f => Nop,
+            0x60 => Eret,
+            0x61 => Cli,
+            0x62 => Sti,
+            0x63 => TlbFlush,
+            0x64 => Mrs,
+            0x65 => Msr,
+            0x66 => Halt,
+            0x67 => Exit,
 
             0x10 => Add,
             0x11 => Sub,
             0x12 => Mul,
             0x13 => Div,
+            0x14 => Cmp,
 
             0x30 => Ldr,
             0x33 => Push,
             0x34 => Pop,
+
+            0x50 => B,
+            0x51 => Call,
+            0x52 => Ret,
+
+            0x70 => Svc,
             _ => return Err(Exception::InvalidOp(value)),
         })
     }
@@ -52,9 +90,112 @@ impl FromStr for Op {
             "sub" => Self::Sub,
             "mul" => Self::Mul,
             "div" => Self::Div,
+            "cmp" => Self::Cmp,
             "ldr" => Self::Ldr,
             "push" => Self::Push,
             "pop" => Self::Pop,
+            "call" => Self::Call,
+            "ret" => Self::Ret,
+            "svc" => Self::Svc,
+            "eret" => Self::Eret,
+            "cli" => Self::Cli,
+            "sti" => Self::Sti,
+            "tlbflush" => Self::TlbFlush,
+            "mrs" => Self::Mrs,
+            "msr" => Self::Msr,
+            "halt" | "hlt" => Self::Halt,
+            "exit" => Self::Exit,
+            _ => {
+                return Err(Exception::UnknownSymbol(
+                    s.into_boxed_str(),
+                    0,
+                ))
+            }
+        })
+    }
+}
+
+/// Branch condition, tested against the FLAGS set by `cmp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Cond {
+    Al,
+    Eq,
+    Ne,
+    // signed
+    Lt,
+    Ge,
+    Gt,
+    Le,
+    // unsigned
+    Lo,
+    Hs,
+    Hi,
+    Ls,
+}
+
+impl Cond {
+    #[inline]
+    pub fn holds(&self, flags: u32) -> bool {
+        let z = flags & FLAG_Z != 0;
+        let n = flags & FLAG_N != 0;
+        let c = flags & FLAG_C != 0;
+        let v = flags & FLAG_V != 0;
+        match self {
+            Cond::Al => true,
+            Cond::Eq => z,
+            Cond::Ne => !z,
+            Cond::Lt => n != v,
+            Cond::Ge => n == v,
+            Cond::Gt => !z && n == v,
+            Cond::Le => z || n != v,
+            Cond::Lo => !c,
+            Cond::Hs => c,
+            Cond::Hi => c && !z,
+            Cond::Ls => !c || z,
+        }
+    }
+}
+
+impl TryFrom<u8> for Cond {
+    type Error = Exception;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use self::Cond::*;
+        Ok(match value {
+            0 => Al,
+            1 => Eq,
+            2 => Ne,
+            3 => Lt,
+            4 => Ge,
+            5 => Gt,
+            6 => Le,
+            7 => Lo,
+            8 => Hs,
+            9 => Hi,
+            10 => Ls,
+            _ => return Err(Exception::InvalidOp(Op::B as u8)),
+        })
+    }
+}
+
+/// Parses the branch mnemonics, `b` and `b<cond>`.
+impl FromStr for Cond {
+    type Err = Exception;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        Ok(match s.as_str() {
+            "b" => Self::Al,
+            "beq" => Self::Eq,
+            "bne" => Self::Ne,
+            "blt" => Self::Lt,
+            "bge" => Self::Ge,
+            "bgt" => Self::Gt,
+            "ble" => Self::Le,
+            "blo" => Self::Lo,
+            "bhs" => Self::Hs,
+            "bhi" => Self::Hi,
+            "bls" => Self::Ls,
             _ => {
                 return Err(Exception::UnknownSymbol(
                     s.into_boxed_str(),
@@ -65,24 +206,134 @@ impl FromStr for Op {
     }
 }
 
+impl Cond {
+    /// The branch mnemonic, `b` or `b<cond>`.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Cond::Al => "b",
+            Cond::Eq => "beq",
+            Cond::Ne => "bne",
+            Cond::Lt => "blt",
+            Cond::Ge => "bge",
+            Cond::Gt => "bgt",
+            Cond::Le => "ble",
+            Cond::Lo => "blo",
+            Cond::Hs => "bhs",
+            Cond::Hi => "bhi",
+            Cond::Ls => "bls",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Operand {
     Reg(Register),
     Imm(u32),
 }
 
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg(r) => write!(f, "r{}", *r as u8),
+            Operand::Imm(i) => write!(f, "#{i}"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Instruction {
     Nop,
+    Eret,
+    Cli,
+    Sti,
+    TlbFlush,
+    /// Read a special register into a general purpose one.
+    Mrs(Register, SpecialRegister),
+    /// Write a general purpose register to a special one.
+    Msr(SpecialRegister, Register),
+    /// Stop the machine.
+    Halt,
+    /// Stop the machine with an exit code for the host.
+    Exit(Operand),
 
     Add(Register, Register, Operand),
     Sub(Register, Register, Operand),
     Mul(Register, Register, Operand),
     Div(Register, Register, Operand),
+    /// Set the FLAGS condition bits from `rs - operand`.
+    Cmp(Register, Operand),
 
     Ldr(Register, Operand),
     Push(Operand),
     Pop(Operand),
+
+    /// Jump to `pc + offset` if the condition holds.
+    B(Cond, u32),
+    /// Push the return address and jump, to `pc + offset` for an
+    /// immediate or the address held in a register.
+    Call(Operand),
+    Ret,
+
+    Svc(u32),
+}
+
+impl Instruction {
+    /// Instructions which fault with `PrivilegeViolation` in user
+    /// mode, either by opcode or by touching a system register.
+    pub fn is_privileged(&self) -> bool {
+        use self::Instruction::*;
+        match self {
+            Eret | Cli | Sti | TlbFlush | Halt | Exit(_) => true,
+            Mrs(_, s) | Msr(s, _) => s.is_system(),
+            _ => false,
+        }
+    }
+}
+
+/// In jasm syntax, pc relative offsets signed.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::Instruction::*;
+        let reg = |r: &Register| Operand::Reg(*r);
+        let special =
+            |s: &SpecialRegister| format!("{s:?}").to_lowercase();
+        match self {
+            Nop => write!(f, "nop"),
+            Eret => write!(f, "eret"),
+            Cli => write!(f, "cli"),
+            Sti => write!(f, "sti"),
+            TlbFlush => write!(f, "tlbflush"),
+            Mrs(r, s) => write!(f, "mrs {}, {}", reg(r), special(s)),
+            Msr(s, r) => write!(f, "msr {}, {}", special(s), reg(r)),
+            Halt => write!(f, "halt"),
+            Exit(o) => write!(f, "exit {o}"),
+            Add(rd, rs, o) => {
+                write!(f, "add {}, {}, {o}", reg(rd), reg(rs))
+            }
+            Sub(rd, rs, o) => {
+                write!(f, "sub {}, {}, {o}", reg(rd), reg(rs))
+            }
+            Mul(rd, rs, o) => {
+                write!(f, "mul {}, {}, {o}", reg(rd), reg(rs))
+            }
+            Div(rd, rs, o) => {
+                write!(f, "div {}, {}, {o}", reg(rd), reg(rs))
+            }
+            Cmp(r, o) => write!(f, "cmp {}, {o}", reg(r)),
+            Ldr(r, o) => write!(f, "ldr {}, {o}", reg(r)),
+            Push(o) => write!(f, "push {o}"),
+            Pop(o) => write!(f, "pop {o}"),
+            B(cond, offset) => {
+                write!(f, "{} {:+}", cond.mnemonic(), *offset as i32)
+            }
+            Call(Operand::Imm(offset)) => {
+                write!(f, "call {:+}", *offset as i32)
+            }
+            Call(o) => write!(f, "call {o}"),
+            Ret => write!(f, "ret"),
+            Svc(n) => write!(f, "svc #{n}"),
+        }
+    }
 }
 
 impl From<&Instruction> for Op {
@@ -90,15 +341,30 @@ impl From<&Instruction> for Op {
         use self::Instruction::*;
         match value {
             Nop => Op::Nop,
+            Eret => Op::Eret,
+            Cli => Op::Cli,
+            Sti => Op::Sti,
+            TlbFlush => Op::TlbFlush,
+            Mrs(_, _) => Op::Mrs,
+            Msr(_, _) => Op::Msr,
+            Halt => Op::Halt,
+            Exit(_) => Op::Exit,
 
             Add(_, _, _) => Op::Add,
             Sub(_, _, _) => Op::Sub,
             Mul(_, _, _) => Op::Mul,
             Div(_, _, _) => Op::Div,
+            Cmp(_, _) => Op::Cmp,
 
             Ldr(_, _) => Op::Ldr,
             Push(_) => Op::Push,
             Pop(_) => Op::Pop,
+
+            B(_, _) => Op::B,
+            Call(_) => Op::Call,
+            Ret => Op::Ret,
+
+            Svc(_) => Op::Svc,
         }
     }
 }
@@ -118,6 +384,27 @@ impl TryFrom<u32> for Instruction {
         use self::Op::*;
         Ok(match opcode {
             Nop => Self::Nop,
+            Eret => Self::Eret,
+            Cli => Self::Cli,
+            Sti => Self::Sti,
+            TlbFlush => Self::TlbFlush,
+            Halt => Self::Halt,
+            Mrs | Msr => {
+                op_len -= 5;
+                let a = ((value >> op_len) & 0x1f) as u8;
+                op_len -= 5;
+                let b = ((value >> op_len) & 0x1f) as u8;
+                match opcode {
+                    Mrs => Self::Mrs(
+                        Register::try_from(a)?,
+                        SpecialRegister::try_from(b)?,
+                    ),
+                    _ => Self::Msr(
+                        SpecialRegister::try_from(a)?,
+                        Register::try_from(b)?,
+                    ),
+                }
+            }
 
             Add | Sub | Mul | Div => {
                 op_len -= 5;
@@ -147,7 +434,7 @@ impl TryFrom<u32> for Instruction {
                 }
             }
 
-            Ldr => {
+            Ldr | Cmp => {
                 op_len -= 5;
                 let r = Register::try_from(
                     ((value >> op_len) & 0x1f) as u8,
@@ -160,7 +447,16 @@ impl TryFrom<u32> for Instruction {
                         ((value >> op_len) & 0x1f) as u8,
                     )?)
                 };
-                Self::Ldr(r, o)
+                match opcode {
+                    Ldr => Self::Ldr(r, o),
+                    _ => Self::Cmp(r, o),
+                }
+            }
+            B => {
+                let cond =
+                    Cond::try_from(((value >> 20) & 0xf) as u8)?;
+                // signed 20 bit offset
+                Self::B(cond, (((value << 12) as i32) >> 12) as u32)
             }
             Push => Self::Push(if imm_flag {
                 Operand::Imm(value & 0xffffff)
@@ -170,6 +466,14 @@ impl TryFrom<u32> for Instruction {
                     ((value >> op_len) & 0x1f) as u8,
                 )?)
             }),
+            Exit => Self::Exit(if imm_flag {
+                Operand::Imm(value & 0xffffff)
+            } else {
+                op_len -= 5;
+                Operand::Reg(Register::try_from(
+                    ((value >> op_len) & 0x1f) as u8,
+                )?)
+            }),
             Pop => Self::Pop(if imm_flag {
                 Operand::Imm(value & 0xffffff)
             } else {
@@ -178,6 +482,19 @@ impl TryFrom<u32> for Instruction {
                     ((value >> op_len) & 0x1f) as u8,
                 )?)
             }),
+
+            Call => Self::Call(if imm_flag {
+                // signed 24 bit offset
+                Operand::Imm((((value << 8) as i32) >> 8) as u32)
+            } else {
+                op_len -= 5;
+                Operand::Reg(Register::try_from(
+                    ((value >> op_len) & 0x1f) as u8,
+                )?)
+            }),
+            Ret => Self::Ret,
+
+            Svc => Self::Svc(value & 0xffffff),
         })
     }
 }
@@ -189,7 +506,13 @@ impl TryFrom<Instruction> for u32 {
         let op = Op::from(&value);
 
         Ok(match value {
-            Instruction::Nop => (op as u32) << 24,
+            Instruction::Nop
+            | Instruction::Eret
+            | Instruction::Cli
+            | Instruction::Sti
+            | Instruction::TlbFlush
+            | Instruction::Halt
+            | Instruction::Ret => (op as u32) << 24,
 
             Instruction::Add(r1, r2, r3)
             | Instruction::Sub(r1, r2, r3)
@@ -216,7 +539,7 @@ impl TryFrom<Instruction> for u32 {
                 encoded
             }
 
-            Instruction::Ldr(r, o) => {
+            Instruction::Ldr(r, o) | Instruction::Cmp(r, o) => {
                 let mut op_len = OP_LEN * 8;
                 op_len -= 8;
                 let mut encoded = (op as u32) << op_len;
@@ -226,7 +549,7 @@ impl TryFrom<Instruction> for u32 {
                     Operand::Reg(re) => {
                         op_len -= 5;
                         encoded |= (re as u32) << op_len;
-                        encoded |= 0x7fff_ffff;
+                        encoded &= 0x7fff_ffff;
                     }
                     Operand::Imm(i) => {
                         encoded |= i;
@@ -235,7 +558,28 @@ impl TryFrom<Instruction> for u32 {
                 }
                 encoded
             }
-            Instruction::Push(o) | Instruction::Pop(o) => {
+            Instruction::B(cond, offset) => {
+                (op as u32) << 24
+                    | (cond as u32) << 20
+                    | offset & 0xfffff
+            }
+            Instruction::Mrs(r, s) => {
+                (op as u32) << 24
+                    | (r as u32) << 19
+                    | (s as u32) << 14
+            }
+            Instruction::Msr(s, r) => {
+                (op as u32) << 24
+                    | (s as u32) << 19
+                    | (r as u32) << 14
+            }
+            Instruction::Call(Operand::Imm(i)) => {
+                (op as u32) << 24 | (i & 0xffffff) | 0x8000_0000
+            }
+            Instruction::Push(o)
+            | Instruction::Pop(o)
+            | Instruction::Exit(o)
+            | Instruction::Call(o) => {
                 let mut op_len = OP_LEN * 8;
                 op_len -= 8;
                 let mut encoded = (op as u32) << op_len;
@@ -252,6 +596,10 @@ impl TryFrom<Instruction> for u32 {
                 }
                 encoded
             }
+
+            Instruction::Svc(i) => {
+                (op as u32) << 24 | (i & 0xffffff) | 0x8000_0000
+            }
         })
     }
 }
@@ -0,0 +1,237 @@
+//! Instruction count profiler, installed on a machine as a hook.
+
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{
+    debug::DebugInfo,
+    opcode::Instruction,
+    register::*,
+    vm::{Hook, Machine},
+};
+
+/// Counts executions per pc and per call stack. Frames are pushed by
+/// `call` and popped by `ret` and named after their entry address,
+/// the outermost after wherever profiling started.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    counts: HashMap<u32, u64>,
+    // entry addresses, outermost first
+    stack: Vec<u32>,
+    stacks: HashMap<Vec<u32>, u64>,
+    // instructions run since the stack last changed
+    pending: u64,
+    total: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Instructions counted.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// `(pc, count)` by descending count.
+    pub fn hot_spots(&self) -> Vec<(u32, u64)> {
+        let mut spots = self
+            .counts
+            .iter()
+            .map(|(&pc, &n)| (pc, n))
+            .collect::<Vec<_>>();
+        spots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        spots
+    }
+
+    /// Counts summed per closest preceding label, by descending
+    /// count. Addresses before any label are grouped under `?`.
+    pub fn by_label(&self, debug: &DebugInfo) -> Vec<(String, u64)> {
+        let mut labels = HashMap::<String, u64>::new();
+        for (&pc, &n) in &self.counts {
+            let name = debug.symbolize(pc).map_or("?", |s| s.0);
+            *labels.entry(name.to_string()).or_default() += n;
+        }
+        let mut labels = labels.into_iter().collect::<Vec<_>>();
+        labels.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        labels
+    }
+
+    /// Hot spot tables, at most `top` rows each.
+    pub fn report(&self, debug: &DebugInfo, top: usize) -> String {
+        let total = self.total.max(1) as f64;
+        let mut out = String::new();
+        writeln!(out, "{:>12} {:>6}  address", "count", "%").unwrap();
+        for (pc, n) in self.hot_spots().into_iter().take(top) {
+            write!(
+                out,
+                "{n:>12} {:>5.1}%  {pc:08x} {}",
+                n as f64 * 100.0 / total,
+                debug.describe(pc),
+            )
+            .unwrap();
+            match debug.source(pc) {
+                Some(src) => writeln!(out, "  {src}"),
+                None => writeln!(out),
+            }
+            .unwrap();
+        }
+        writeln!(out).unwrap();
+        writeln!(out, "{:>12} {:>6}  label", "count", "%").unwrap();
+        for (name, n) in self.by_label(debug).into_iter().take(top) {
+            writeln!(
+                out,
+                "{n:>12} {:>5.1}%  {name}",
+                n as f64 * 100.0 / total
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// Stacks in the folded format flamegraph tools read, one
+    /// `outer;inner count` line per distinct stack.
+    pub fn folded(&self, debug: &DebugInfo) -> String {
+        let mut stacks = self.stacks.clone();
+        if self.pending != 0 {
+            *stacks.entry(self.stack.clone()).or_default() +=
+                self.pending;
+        }
+
+        let mut lines = stacks
+            .into_iter()
+            .filter(|(_, n)| *n != 0)
+            .map(|(stack, n)| {
+                let names = stack
+                    .iter()
+                    .map(|&addr| debug.describe(addr))
+                    .collect::<Vec<_>>();
+                format!("{} {n}", names.join(";"))
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines.join("\n") + "\n"
+    }
+
+    /// The stack is about to change, file what ran on the old one.
+    fn flush(&mut self) {
+        if self.pending != 0 {
+            *self.stacks.entry(self.stack.clone()).or_default() +=
+                self.pending;
+            self.pending = 0;
+        }
+    }
+}
+
+impl Hook for Profiler {
+    fn before(&mut self, _: &Machine, pc: u32, _: &Instruction) {
+        if self.stack.is_empty() {
+            self.stack.push(pc);
+        }
+        *self.counts.entry(pc).or_default() += 1;
+        self.pending += 1;
+        self.total += 1;
+    }
+
+    fn after(&mut self, m: &Machine, _: u32, ins: &Instruction) {
+        match ins {
+            Instruction::Call(_) => {
+                self.flush();
+                self.stack.push(m[PC]);
+            }
+            // keep the root frame on a stray ret
+            Instruction::Ret if self.stack.len() > 1 => {
+                self.flush();
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    // a stray ret into start, then two calls into inner
+    const PROGRAM: &str = "
+main:
+    la r1, start
+    push r1
+    ret
+start:
+    call outer
+    call inner
+    halt
+outer:
+    call inner
+    ret
+inner:
+    nop
+    ret
+";
+
+    fn profile(check: impl FnOnce(&Profiler, &DebugInfo)) {
+        let asm = assemble("test.jasm", PROGRAM);
+        let mut vm =
+            Machine::builder().program(&asm.code).build().unwrap();
+        let id = vm.add_hook(Box::new(Profiler::new()));
+        vm.run(false).unwrap();
+        check(vm.hook(id).unwrap(), &asm.debug);
+    }
+
+    #[test]
+    fn hot_spots() {
+        profile(|profiler, debug| {
+            let start = debug.symbol_addr("start").unwrap();
+            let inner = debug.symbol_addr("inner").unwrap();
+            // however many words la expands to
+            let setup = start as u64 / 4;
+            assert_eq!(profiler.total(), setup + 9);
+
+            let spots = profiler.hot_spots();
+            assert_eq!(spots.len() as u64, setup + 7);
+            assert_eq!(spots[..2], [(inner, 2), (inner + 4, 2)]);
+            assert!(spots[2..].iter().all(|s| s.1 == 1));
+            assert!(spots[2..].windows(2).all(|w| w[0].0 < w[1].0));
+        });
+    }
+
+    #[test]
+    fn by_label() {
+        profile(|profiler, debug| {
+            let setup =
+                debug.symbol_addr("start").unwrap() as u64 / 4;
+            let mut expected = vec![
+                ("main".to_string(), setup),
+                ("inner".to_string(), 4),
+                ("start".to_string(), 3),
+                ("outer".to_string(), 2),
+            ];
+            expected
+                .sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            assert_eq!(profiler.by_label(debug), expected);
+
+            assert_eq!(
+                profiler.by_label(&DebugInfo::new("test.jasm")),
+                [("?".to_string(), setup + 9)]
+            );
+        });
+    }
+
+    #[test]
+    fn folded() {
+        profile(|profiler, debug| {
+            let setup = debug.symbol_addr("start").unwrap() / 4;
+            // the stray ret leaves main as the root frame
+            assert_eq!(
+                profiler.folded(debug),
+                format!(
+                    "main {}\nmain;inner 2\nmain;outer 2\nmain;outer;inner 2\n",
+                    setup + 3
+                )
+            );
+        });
+    }
+}
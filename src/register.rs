@@ -4,22 +4,86 @@ pub enum Register {
     R1,
     R2,
     R3,
-    SP,
-    PC,
-    BP,
+    R4,
+    R5,
+    R6,
+    R7,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
     // DONT create any preceding variants as this is used for max register len
+    R15,
+}
+
+/// Special registers, only reachable through `mrs`/`msr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SpecialRegister {
     FLAGS,
+    // exception state, see `Machine::set_trap_mode`
+    EPC,
+    CAUSE,
+    TVAL,
+    VBAR,
+    // stack pointer of the mode that isn't running
+    BSP,
+    // page table base, see `mmu::Tlb`
+    PTBR,
+    // stack bounds, checked while SBASE is non zero
+    SBASE,
+    // DONT create any preceding variants as this is used for max register len
+    SLIMIT,
 }
 
 use std::str::FromStr;
 
 pub use Register::*;
+pub use SpecialRegister::*;
 
 use crate::error::Exception;
-pub const REGISTER_LEN: usize = Register::FLAGS as usize + 1;
+pub const REGISTER_LEN: usize = Register::R15 as usize + 1;
+pub const SPECIAL_REGISTER_LEN: usize =
+    SpecialRegister::SLIMIT as usize + 1;
+
+pub const BP: Register = R12;
+pub const SP: Register = R13;
+pub const LR: Register = R14;
+pub const PC: Register = R15;
+
+/// Condition bits set by `cmp`: zero, negative, carry (no borrow)
+/// and signed overflow.
+pub const FLAG_Z: u32 = 1 << 0;
+pub const FLAG_N: u32 = 1 << 1;
+pub const FLAG_C: u32 = 1 << 2;
+pub const FLAG_V: u32 = 1 << 3;
+pub const COND_FLAGS: u32 = FLAG_Z | FLAG_N | FLAG_C | FLAG_V;
+
+/// FLAGS bit set while running in user mode.
+pub const FLAG_USER: u32 = 1 << 8;
+/// Mode to return to on `eret`, saved on trap entry.
+pub const FLAG_PREV_USER: u32 = 1 << 9;
+/// Interrupt enable, toggled by `cli`/`sti`.
+pub const FLAG_IE: u32 = 1 << 10;
+/// Interrupt enable to restore on `eret`, saved on trap entry.
+pub const FLAG_PREV_IE: u32 = 1 << 11;
+/// Address translation through the page tables at `PTBR`.
+pub const FLAG_MMU: u32 = 1 << 12;
+/// FLAGS bits user mode can't change.
+pub const SYSTEM_FLAGS: u32 =
+    FLAG_USER | FLAG_PREV_USER | FLAG_IE | FLAG_PREV_IE | FLAG_MMU;
 
 impl Register {}
 
+impl SpecialRegister {
+    /// Registers only accessible in supervisor mode.
+    pub fn is_system(&self) -> bool {
+        *self != FLAGS
+    }
+}
+
 impl TryFrom<u8> for Register {
     type Error = Exception;
 
@@ -29,10 +93,37 @@ impl TryFrom<u8> for Register {
             1 => R1,
             2 => R2,
             3 => R3,
-            4 => SP,
-            5 => PC,
-            6 => BP,
-            7 => FLAGS,
+            4 => R4,
+            5 => R5,
+            6 => R6,
+            7 => R7,
+            8 => R8,
+            9 => R9,
+            10 => R10,
+            11 => R11,
+            12 => R12,
+            13 => R13,
+            14 => R14,
+            15 => R15,
+            _ => return Err(Exception::InvalidReg(value)),
+        })
+    }
+}
+
+impl TryFrom<u8> for SpecialRegister {
+    type Error = Exception;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => FLAGS,
+            1 => EPC,
+            2 => CAUSE,
+            3 => TVAL,
+            4 => VBAR,
+            5 => BSP,
+            6 => PTBR,
+            7 => SBASE,
+            8 => SLIMIT,
             _ => return Err(Exception::InvalidReg(value)),
         })
     }
@@ -47,11 +138,48 @@ impl FromStr for Register {
             "r1" | "R1" => Self::R1,
             "r2" | "R2" => Self::R2,
             "r3" | "R3" => Self::R3,
-            "sp" | "SP" => Self::SP,
-            "pc" | "PC" => Self::PC,
-            "bp" | "BP" => Self::BP,
+            "r4" | "R4" => Self::R4,
+            "r5" | "R5" => Self::R5,
+            "r6" | "R6" => Self::R6,
+            "r7" | "R7" => Self::R7,
+            "r8" | "R8" => Self::R8,
+            "r9" | "R9" => Self::R9,
+            "r10" | "R10" => Self::R10,
+            "r11" | "R11" => Self::R11,
+            "r12" | "R12" | "bp" | "BP" => BP,
+            "r13" | "R13" | "sp" | "SP" => SP,
+            "r14" | "R14" | "lr" | "LR" => LR,
+            "r15" | "R15" | "pc" | "PC" => PC,
+            _ => {
+                return Err(Exception::UnknownSymbol(
+                    s.to_string().into_boxed_str(),
+                    0,
+                ))
+            }
+        })
+    }
+}
+
+impl FromStr for SpecialRegister {
+    type Err = Exception;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
             "flags" | "FLAGS" => Self::FLAGS,
-            _ => return Err(Exception::UnknownSymbol(s.to_string().into_boxed_str(), 0)),
+            "epc" | "EPC" => Self::EPC,
+            "cause" | "CAUSE" => Self::CAUSE,
+            "tval" | "TVAL" => Self::TVAL,
+            "vbar" | "VBAR" => Self::VBAR,
+            "bsp" | "BSP" => Self::BSP,
+            "ptbr" | "PTBR" => Self::PTBR,
+            "sbase" | "SBASE" => Self::SBASE,
+            "slimit" | "SLIMIT" => Self::SLIMIT,
+            _ => {
+                return Err(Exception::UnknownSymbol(
+                    s.to_string().into_boxed_str(),
+                    0,
+                ))
+            }
         })
     }
 }
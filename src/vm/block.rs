@@ -0,0 +1,339 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasherDefault, Hasher},
+    rc::Rc,
+};
+
+use super::{Engine, Machine, BIT, OP_LEN};
+use crate::{
+    error::Exception,
+    memory::Access,
+    mmu::{PAGE_MASK, PAGE_SHIFT},
+    opcode::{Cond, Instruction, Operand},
+    register::*,
+};
+
+/// Longest straight line run translated into one block.
+pub const BLOCK_LEN: usize = 32;
+
+/// Block engine micro op, either a single instruction or a fused
+/// pair executed in one dispatch.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Uop {
+    One(Instruction),
+    /// `cmp rs, operand` followed by `b<cond> offset`
+    CmpBranch(Register, Operand, Cond, BIT),
+    /// `push operand` followed by `pop rd`
+    PushPop(Operand, Register),
+}
+
+impl Uop {
+    fn len(&self) -> BIT {
+        match self {
+            Uop::One(_) => OP_LEN,
+            Uop::CmpBranch(..) | Uop::PushPop(..) => 2 * OP_LEN,
+        }
+    }
+}
+
+/// Translated straight line code, never crossing a page. Uops
+/// carry their byte offset from the start of the block.
+#[derive(Debug)]
+pub struct Block {
+    pub(super) len: BIT,
+    pub(super) uops: Box<[(BIT, Uop)]>,
+    pub(super) hits: std::cell::Cell<u32>,
+    pub(super) native:
+        std::cell::OnceCell<Option<super::jit::NativeFn>>,
+}
+
+/// Whether control may leave the block after `ins`.
+fn ends_block(ins: &Instruction) -> bool {
+    use Instruction::*;
+    match *ins {
+        Halt
+        | Exit(_)
+        | Eret
+        | TlbFlush
+        | Msr(_, _)
+        | B(_, _)
+        | Call(_)
+        | Ret
+        | Svc(_) => true,
+        Add(rd, _, _)
+        | Sub(rd, _, _)
+        | Mul(rd, _, _)
+        | Div(rd, _, _)
+        | Ldr(rd, _)
+        | Mrs(rd, _) => rd == PC,
+        Pop(o) => o == Operand::Reg(PC),
+        Nop | Cli | Sti | Cmp(_, _) | Push(_) => false,
+    }
+}
+
+/// Multiplicative hash for address keys, SipHash dominates the
+/// lookup on every store otherwise.
+#[derive(Debug, Default)]
+struct AddrHasher(u64);
+
+impl Hasher for AddrHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.write_u64(b as u64);
+        }
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0 ^ n).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+type AddrMap<V> = HashMap<BIT, V, BuildHasherDefault<AddrHasher>>;
+
+/// Translated blocks keyed by the physical address they start at,
+/// with an index by page for invalidation on stores.
+#[derive(Debug, Default)]
+pub struct BlockCache {
+    blocks: AddrMap<Rc<Block>>,
+    // first and last byte of every block, by page
+    pages: AddrMap<Vec<(BIT, BIT)>>,
+    // set when a store drops a block, the running one may be stale
+    dirty: bool,
+}
+
+impl BlockCache {
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.pages.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Drop every block overlapping `pa..pa + len`.
+    #[inline]
+    pub fn invalidate(&mut self, pa: BIT, len: BIT) {
+        if self.pages.is_empty() {
+            return;
+        }
+
+        let last = pa.wrapping_add(len - 1);
+        let (first_page, last_page) =
+            (pa >> PAGE_SHIFT, last >> PAGE_SHIFT);
+        self.invalidate_page(first_page, pa, last);
+        if last_page != first_page {
+            self.invalidate_page(last_page, pa, last);
+        }
+    }
+
+    fn invalidate_page(&mut self, page: BIT, pa: BIT, last: BIT) {
+        let Some(extents) = self.pages.get_mut(&page) else {
+            return;
+        };
+        let blocks = &mut self.blocks;
+        let dirty = &mut self.dirty;
+        extents.retain(|&(start, block_last)| {
+            let overlaps = start <= last && pa <= block_last;
+            if overlaps {
+                blocks.remove(&start);
+                *dirty = true;
+            }
+            !overlaps
+        });
+    }
+
+    fn insert(&mut self, pa: BIT, block: Block) -> Rc<Block> {
+        let extent = (pa, pa + (block.len - 1));
+        let block = Rc::new(block);
+        self.blocks.insert(pa, block.clone());
+        self.pages.entry(pa >> PAGE_SHIFT).or_default().push(extent);
+        block
+    }
+}
+
+impl Machine {
+    /// Decode from `pa` up to a branch, a page boundary or
+    /// `BLOCK_LEN` instructions, fusing pairs on the way. `None`
+    /// when the first instruction doesn't decode.
+    fn translate_block(&mut self, pa: BIT) -> Option<Rc<Block>> {
+        let mut code = Vec::new();
+        let mut addr = pa;
+        while code.len() < BLOCK_LEN {
+            if addr & PAGE_MASK > PAGE_MASK - (OP_LEN - 1) {
+                break;
+            }
+            let Some(ins) = self
+                .mem
+                .read_u32(addr)
+                .ok()
+                .and_then(|w| Instruction::try_from(w).ok())
+            else {
+                break;
+            };
+            code.push(ins);
+            addr = addr.wrapping_add(OP_LEN);
+            if ends_block(&ins) || addr & PAGE_MASK == 0 {
+                break;
+            }
+        }
+
+        if code.is_empty() {
+            return None;
+        }
+
+        let mut uops = Vec::with_capacity(code.len());
+        let mut offset = 0;
+        let mut i = 0;
+        while i < code.len() {
+            let uop = match (code[i], code.get(i + 1)) {
+                (
+                    Instruction::Cmp(r, o),
+                    Some(&Instruction::B(c, off)),
+                ) => Uop::CmpBranch(r, o, c, off),
+                (
+                    Instruction::Push(o),
+                    Some(&Instruction::Pop(Operand::Reg(rd))),
+                ) => Uop::PushPop(o, rd),
+                (ins, _) => Uop::One(ins),
+            };
+            uops.push((offset, uop));
+            offset += uop.len();
+            i += (uop.len() / OP_LEN) as usize;
+        }
+
+        let block = Block {
+            len: offset,
+            uops: uops.into_boxed_slice(),
+            hits: Default::default(),
+            native: Default::default(),
+        };
+        Some(self.blocks.insert(pa, block))
+    }
+
+    /// Run the block at `PC`, translating it first if needed. Falls
+    /// back to `step` wherever the fetch path would fault, so the
+    /// architectural result always matches single stepping.
+    pub(super) fn step_block(&mut self) -> Result<(), Exception> {
+        let pc = self[PC];
+        if self.split(pc) {
+            return self.step();
+        }
+        let Ok(pa) = self.translate(pc, Access::Execute) else {
+            return self.step();
+        };
+        let block = match self.blocks.blocks.get(&pa) {
+            Some(block) => block.clone(),
+            None => match self.translate_block(pa) {
+                Some(block) => block,
+                None => return self.step(),
+            },
+        };
+
+        let mut start = 0;
+        if self.engine == Engine::Jit && !self.mpu.is_enabled() {
+            if let Some(n) = self.run_native(&block) {
+                self.steps += n as u64;
+                // native code stops before whatever it can't run,
+                // pick up from there unless it branched away
+                let offset = n * OP_LEN;
+                if self[PC] != pc.wrapping_add(offset) {
+                    return Ok(());
+                }
+                match block.uops.iter().position(|u| u.0 == offset) {
+                    Some(i) => start = i,
+                    None => return Ok(()),
+                }
+            }
+        }
+
+        self.blocks.dirty = false;
+        for &(offset, uop) in block.uops[start..].iter() {
+            let upc = pc.wrapping_add(offset);
+            let next = upc.wrapping_add(uop.len());
+            if let Err((at, e)) = self.exec_uop(upc, pa + offset, uop)
+            {
+                return self.fault(at, e);
+            }
+            if self.halt || self.blocks.dirty || self[PC] != next {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute one uop at `pc`. Errors carry the pc of the
+    /// instruction that raised them.
+    #[inline]
+    fn exec_uop(
+        &mut self,
+        pc: BIT,
+        pa: BIT,
+        uop: Uop,
+    ) -> Result<(), (BIT, Exception)> {
+        let second = pc.wrapping_add(OP_LEN);
+        self.steps += 1;
+        self.mpu
+            .check(pa, OP_LEN, Access::Execute)
+            .map_err(|e| (pc, e))?;
+        match uop {
+            Uop::One(ins) => {
+                self[PC] = second;
+                self.exec(pc, ins).map_err(|e| (pc, e))
+            }
+            Uop::CmpBranch(r, o, cond, offset) => {
+                let rhs = match o {
+                    Operand::Reg(r) => self[r],
+                    Operand::Imm(i) => i,
+                };
+                self.compare(self[r], rhs);
+                self[PC] = second;
+
+                self.steps += 1;
+                self.mpu
+                    .check(pa + OP_LEN, OP_LEN, Access::Execute)
+                    .map_err(|e| (second, e))?;
+                self[PC] = if cond.holds(self[FLAGS]) {
+                    second.wrapping_add(offset)
+                } else {
+                    second.wrapping_add(OP_LEN)
+                };
+                Ok(())
+            }
+            Uop::PushPop(o, rd) => {
+                let value = match o {
+                    Operand::Reg(r) => self[r],
+                    Operand::Imm(i) => i,
+                };
+                self[PC] = second;
+                self.push(value).map_err(|e| (pc, e))?;
+                // the push may have stored over the pop, which then
+                // runs from memory next dispatch
+                if self.blocks.dirty {
+                    return Ok(());
+                }
+
+                self.steps += 1;
+                self.mpu
+                    .check(pa + OP_LEN, OP_LEN, Access::Execute)
+                    .map_err(|e| (second, e))?;
+                self[PC] = second.wrapping_add(OP_LEN);
+                let value = self.pop().map_err(|e| (second, e))?;
+                self[rd] = value;
+                Ok(())
+            }
+        }
+    }
+}
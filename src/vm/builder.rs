@@ -0,0 +1,179 @@
+use super::{Machine, TrapMode, BIT};
+use crate::{
+    error::Exception,
+    memory::{Addressable, SparseMemory, MEMORY_LEN},
+    register::*,
+};
+
+#[derive(Debug)]
+enum Ram {
+    Flat(usize),
+    Sparse,
+    Custom(Box<dyn Addressable>),
+}
+
+/// Configures memory and the initial register state of a
+/// `Machine`. Defaults match `Machine::new`: 10 KiB of flat RAM,
+/// `SP` at 0x400 and the program loaded and entered at zero.
+#[derive(Debug)]
+pub struct MachineBuilder<'p> {
+    ram: Ram,
+    sp: BIT,
+    bp: BIT,
+    load: BIT,
+    entry: Option<BIT>,
+    program: &'p [u8],
+    trap_mode: TrapMode,
+}
+
+impl Default for MachineBuilder<'_> {
+    fn default() -> Self {
+        Self {
+            ram: Ram::Flat(MEMORY_LEN),
+            sp: 0x400,
+            bp: 0,
+            load: 0,
+            entry: None,
+            program: &[],
+            trap_mode: TrapMode::default(),
+        }
+    }
+}
+
+impl<'p> MachineBuilder<'p> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flat, zeroed RAM of `len` bytes starting at address zero.
+    pub fn ram(mut self, len: usize) -> Self {
+        self.ram = Ram::Flat(len);
+        self
+    }
+
+    /// Lazily allocated memory covering the whole address space.
+    pub fn sparse(mut self) -> Self {
+        self.ram = Ram::Sparse;
+        self
+    }
+
+    pub fn memory(mut self, mem: Box<dyn Addressable>) -> Self {
+        self.ram = Ram::Custom(mem);
+        self
+    }
+
+    pub fn stack(mut self, sp: BIT) -> Self {
+        self.sp = sp;
+        self
+    }
+
+    pub fn bp(mut self, bp: BIT) -> Self {
+        self.bp = bp;
+        self
+    }
+
+    /// Where `program` is copied to, and the entry point unless
+    /// `entry` is set.
+    pub fn load_address(mut self, addr: BIT) -> Self {
+        self.load = addr;
+        self
+    }
+
+    pub fn entry(mut self, addr: BIT) -> Self {
+        self.entry = Some(addr);
+        self
+    }
+
+    pub fn program(mut self, program: &'p [u8]) -> Self {
+        self.program = program;
+        self
+    }
+
+    pub fn trap_mode(mut self, mode: TrapMode) -> Self {
+        self.trap_mode = mode;
+        self
+    }
+
+    /// Fails if the program doesn't fit in memory.
+    pub fn build(self) -> Result<Machine, Exception> {
+        let mem: Box<dyn Addressable> = match self.ram {
+            Ram::Flat(len) => Box::new(vec![0; len]),
+            Ram::Sparse => Box::new(SparseMemory::new()),
+            Ram::Custom(mem) => mem,
+        };
+
+        let mut vm = Machine::with_memory(mem);
+        vm.trap_mode = self.trap_mode;
+
+        self.program.iter().enumerate().try_for_each(|(i, &b)| {
+            vm.mem.write(self.load.wrapping_add(i as BIT), b)
+        })?;
+
+        vm[SP] = self.sp;
+        vm[BP] = self.bp;
+        vm[PC] = self.entry.unwrap_or(self.load);
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let vm = MachineBuilder::new().build().unwrap();
+        assert_eq!((vm[SP], vm[BP], vm[PC]), (0x400, 0, 0));
+        assert_eq!(vm.trap_mode(), TrapMode::Abort);
+        let last = MEMORY_LEN as BIT - 1;
+        assert_eq!(vm.mem.read(last), Ok(0));
+        assert_eq!(
+            vm.mem.read(last + 1),
+            Err(Exception::InvalidMemoryAccess(last + 1))
+        );
+    }
+
+    #[test]
+    fn program_placement() {
+        let program = [1, 2, 3, 4, 5];
+        let vm = MachineBuilder::new()
+            .program(&program)
+            .load_address(0x100)
+            .stack(0x80)
+            .bp(0x40)
+            .build()
+            .unwrap();
+        assert_eq!((vm[SP], vm[BP], vm[PC]), (0x80, 0x40, 0x100));
+        assert_eq!(vm.mem.read_u32(0x100), Ok(0x0403_0201));
+        assert_eq!(vm.mem.read(0x104), Ok(5));
+        assert_eq!(vm.mem.read(0xff), Ok(0));
+
+        let vm = MachineBuilder::new()
+            .program(&program)
+            .load_address(0x100)
+            .entry(0x104)
+            .build()
+            .unwrap();
+        assert_eq!(vm[PC], 0x104);
+    }
+
+    #[test]
+    fn program_fits() {
+        let program = [0; 8];
+        let small = MachineBuilder::new().ram(12).program(&program);
+        assert!(small.load_address(4).build().is_ok());
+        let small = MachineBuilder::new().ram(12).program(&program);
+        assert_eq!(
+            small.load_address(8).build().unwrap_err(),
+            Exception::InvalidMemoryAccess(12)
+        );
+
+        let vm = MachineBuilder::new()
+            .sparse()
+            .program(&program)
+            .load_address(0xffff_0000)
+            .build()
+            .unwrap();
+        assert_eq!(vm[PC], 0xffff_0000);
+    }
+}
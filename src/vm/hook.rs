@@ -0,0 +1,287 @@
+use std::any::Any;
+
+use super::{Machine, BIT, OP_LEN};
+use crate::{error::Exception, opcode::Instruction, register::*};
+
+/// Callbacks into a running machine, all doing nothing by default.
+/// While any hook is installed every dispatch single steps through
+/// the interpreter so each instruction is seen.
+pub trait Hook: Any {
+    /// `ins` at `pc` was fetched and is about to execute.
+    fn before(&mut self, _: &Machine, _pc: BIT, _ins: &Instruction) {}
+
+    /// `ins` at `pc` executed without faulting, `m[PC]` is the next
+    /// instruction.
+    fn after(&mut self, _m: &Machine, _pc: BIT, _ins: &Instruction) {}
+
+    /// A guest load of `value` from virtual address `addr`.
+    fn mem_read(&mut self, _addr: BIT, _value: BIT) {}
+
+    /// A guest store of `value` to virtual address `addr`.
+    fn mem_write(&mut self, _addr: BIT, _value: BIT) {}
+
+    /// Control went from the instruction at `from` to `to` instead of
+    /// the next one.
+    fn branch(&mut self, _from: BIT, _to: BIT) {}
+
+    /// The instruction at `pc` raised `e`, before the trap mode
+    /// decides what happens to it.
+    fn exception(&mut self, _pc: BIT, _e: &Exception) {}
+
+    /// `svc #n` at `pc`, before a host function or the trap runs.
+    fn syscall(&mut self, _pc: BIT, _n: u32) {}
+}
+
+/// Handle for removing a hook again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookId(u32);
+
+#[derive(Default)]
+pub(super) struct Hooks {
+    hooks: Vec<(HookId, Box<dyn Hook>)>,
+    next: u32,
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.hooks.iter().map(|(id, _)| id))
+            .finish()
+    }
+}
+
+impl Hooks {
+    #[inline]
+    pub(super) fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub(super) fn each(&mut self, mut f: impl FnMut(&mut dyn Hook)) {
+        for (_, hook) in &mut self.hooks {
+            f(hook.as_mut());
+        }
+    }
+}
+
+impl Machine {
+    pub fn add_hook(&mut self, hook: Box<dyn Hook>) -> HookId {
+        let id = HookId(self.hooks.next);
+        self.hooks.next += 1;
+        self.hooks.hooks.push((id, hook));
+        id
+    }
+
+    pub fn remove_hook(
+        &mut self,
+        id: HookId,
+    ) -> Option<Box<dyn Hook>> {
+        let idx = self.hooks.hooks.iter().position(|h| h.0 == id)?;
+        Some(self.hooks.hooks.remove(idx).1)
+    }
+
+    /// Installed hook `id`, if it's a `T`.
+    pub fn hook<T: Hook>(&self, id: HookId) -> Option<&T> {
+        let (_, hook) =
+            self.hooks.hooks.iter().find(|h| h.0 == id)?;
+        (hook.as_ref() as &dyn Any).downcast_ref()
+    }
+
+    /// Run `f` on every hook with the machine borrowed alongside.
+    fn with_hooks(
+        &mut self,
+        mut f: impl FnMut(&mut dyn Hook, &Machine),
+    ) {
+        let mut hooks = std::mem::take(&mut self.hooks);
+        hooks.each(|h| f(h, self));
+        // hooks can't be added while they run, keep the id counter
+        self.hooks = hooks;
+    }
+
+    /// `step` with every hook called along the way.
+    #[cold]
+    pub(super) fn step_hooked(&mut self) -> Result<(), Exception> {
+        let pc = self[PC];
+        self.steps += 1;
+        let next = pc.wrapping_add(OP_LEN);
+
+        let res = self.fetch(pc).and_then(|ins| {
+            self.with_hooks(|h, m| h.before(m, pc, &ins));
+            if let Instruction::Svc(n) = ins {
+                self.hooks.each(|h| h.syscall(pc, n));
+            }
+            self[PC] = next;
+            self.exec(pc, ins)?;
+
+            let to = self[PC];
+            if to != next {
+                self.hooks.each(|h| h.branch(pc, to));
+            }
+            self.with_hooks(|h, m| h.after(m, pc, &ins));
+            Ok(())
+        });
+
+        match res {
+            Err(e) => {
+                self.hooks.each(|h| h.exception(pc, &e));
+                self.fault(pc, e)
+            }
+            ok => ok,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, vm::Engine};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Event {
+        Before(BIT),
+        After(BIT, BIT),
+        Read(BIT, BIT),
+        Write(BIT, BIT),
+        Branch(BIT, BIT),
+        Exception(BIT),
+        Syscall(BIT, u32),
+    }
+
+    #[derive(Default)]
+    struct Recorder(Vec<Event>);
+
+    impl Hook for Recorder {
+        fn before(&mut self, _: &Machine, pc: BIT, _: &Instruction) {
+            self.0.push(Event::Before(pc));
+        }
+
+        fn after(&mut self, m: &Machine, pc: BIT, _: &Instruction) {
+            self.0.push(Event::After(pc, m[PC]));
+        }
+
+        fn mem_read(&mut self, addr: BIT, value: BIT) {
+            self.0.push(Event::Read(addr, value));
+        }
+
+        fn mem_write(&mut self, addr: BIT, value: BIT) {
+            self.0.push(Event::Write(addr, value));
+        }
+
+        fn branch(&mut self, from: BIT, to: BIT) {
+            self.0.push(Event::Branch(from, to));
+        }
+
+        fn exception(&mut self, pc: BIT, _: &Exception) {
+            self.0.push(Event::Exception(pc));
+        }
+
+        fn syscall(&mut self, pc: BIT, n: u32) {
+            self.0.push(Event::Syscall(pc, n));
+        }
+    }
+
+    const PROGRAM: &str = "
+    ldr r1, #40
+loop:
+    push r1
+    pop r2
+    call f
+    sub r1, r1, #1
+    cmp r1, #0
+    bne loop
+    svc #7
+    div r3, r3, #0
+f:
+    ret
+";
+
+    fn machine(engine: Engine) -> Machine {
+        let code = assemble("test.jasm", PROGRAM).code;
+        let mut vm =
+            Machine::builder().program(&code).build().unwrap();
+        vm.set_engine(engine);
+        vm.host.bind(7, "nothing", |_| Ok(()));
+        vm
+    }
+
+    #[test]
+    fn once_per_instruction() {
+        // where an unhooked interpreter goes
+        let mut reference = machine(Engine::Interpreter);
+        let mut pcs = Vec::new();
+        while reference.dispatch().is_ok() {
+            pcs.push(reference[PC]);
+        }
+
+        let mut seen = None;
+        for engine in
+            [Engine::Interpreter, Engine::Block, Engine::Jit]
+        {
+            let mut vm = machine(engine);
+            let id = vm.add_hook(Box::<Recorder>::default());
+            assert_eq!(
+                vm.run(false),
+                Err(Exception::DivisionByZero),
+                "{engine:?}"
+            );
+            assert_eq!(vm.steps(), reference.steps(), "{engine:?}");
+            let events = vm.remove_hook(id).unwrap();
+            let events = (events.as_ref() as &dyn Any)
+                .downcast_ref::<Recorder>()
+                .unwrap()
+                .0
+                .clone();
+
+            let count = |f: fn(&Event) -> bool| {
+                events.iter().filter(|e| f(e)).count() as u64
+            };
+            let steps = vm.steps();
+            assert_eq!(
+                count(|e| matches!(e, Event::Before(_))),
+                steps
+            );
+            // all but the faulting div
+            let afters = events.iter().filter_map(|e| match *e {
+                Event::After(_, next) => Some(next),
+                _ => None,
+            });
+            assert!(afters.eq(pcs.iter().copied()), "{engine:?}");
+            assert_eq!(
+                count(|e| matches!(e, Event::Exception(_))),
+                1
+            );
+            assert_eq!(
+                count(|e| matches!(e, Event::Syscall(_, 7))),
+                1
+            );
+            // a push and a call each iteration, a pop and a ret
+            assert_eq!(count(|e| matches!(e, Event::Write(..))), 80);
+            assert_eq!(count(|e| matches!(e, Event::Read(..))), 80);
+            // call, ret and bne back, but the last bne falls through
+            assert_eq!(
+                count(|e| matches!(e, Event::Branch(..))),
+                119
+            );
+
+            match &seen {
+                None => seen = Some(events),
+                Some(seen) => assert_eq!(&events, seen, "{engine:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn hooks_by_id() {
+        let mut vm = machine(Engine::Interpreter);
+        let a = vm.add_hook(Box::<Recorder>::default());
+        let b = vm.add_hook(Box::<Recorder>::default());
+        vm.step().unwrap();
+        assert!(vm.remove_hook(a).is_some());
+        assert!(vm.remove_hook(a).is_none());
+        vm.step().unwrap();
+        assert_eq!(vm.hook::<Recorder>(b).unwrap().0.len(), 5);
+        assert!(vm.hook::<Recorder>(a).is_none());
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+use super::{Machine, BIT};
+use crate::{error::Exception, memory::Access, register::*};
+
+/// Host function called with the machine of the guest calling it.
+/// Arguments are passed in `R0..=R3` and the result in `R0`, see the
+/// helpers on `Machine`.
+pub type HostFn =
+    Box<dyn FnMut(&mut Machine) -> Result<(), Exception>>;
+
+/// Index of a registered host function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HostId(usize);
+
+/// Named host functions, callable from the guest through the
+/// syscall numbers they're bound to. A bound `svc` never reaches the
+/// trap vector.
+#[derive(Default)]
+pub struct HostFunctions {
+    // `None` while the function runs
+    funcs: Vec<(Box<str>, Option<HostFn>)>,
+    names: HashMap<Box<str>, HostId>,
+    syscalls: HashMap<u32, HostId>,
+}
+
+impl std::fmt::Debug for HostFunctions {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        f.debug_struct("HostFunctions")
+            .field("names", &self.names)
+            .field("syscalls", &self.syscalls)
+            .finish()
+    }
+}
+
+impl HostFunctions {
+    /// Add `f` as `name`, replacing any function by that name.
+    pub fn register<F>(&mut self, name: &str, f: F) -> HostId
+    where
+        F: FnMut(&mut Machine) -> Result<(), Exception> + 'static,
+    {
+        if let Some(&id) = self.names.get(name) {
+            self.funcs[id.0].1 = Some(Box::new(f));
+            return id;
+        }
+        let id = HostId(self.funcs.len());
+        self.funcs.push((name.into(), Some(Box::new(f))));
+        self.names.insert(name.into(), id);
+        id
+    }
+
+    /// Have `svc #n` call `id`.
+    pub fn bind_syscall(&mut self, n: u32, id: HostId) {
+        self.syscalls.insert(n, id);
+    }
+
+    /// Register `f` as `name` and bind it to `svc #n`.
+    pub fn bind<F>(&mut self, n: u32, name: &str, f: F) -> HostId
+    where
+        F: FnMut(&mut Machine) -> Result<(), Exception> + 'static,
+    {
+        let id = self.register(name, f);
+        self.bind_syscall(n, id);
+        id
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<HostId> {
+        self.names.get(name).copied()
+    }
+
+    pub fn name(&self, id: HostId) -> &str {
+        &self.funcs[id.0].0
+    }
+
+    pub(super) fn syscall(&self, n: u32) -> Option<HostId> {
+        self.syscalls.get(&n).copied()
+    }
+}
+
+impl Machine {
+    /// Call host function `id`, `None` if it's already running
+    /// further up the stack.
+    pub fn call_host(
+        &mut self,
+        id: HostId,
+    ) -> Option<Result<(), Exception>> {
+        let mut f = self.host.funcs[id.0].1.take()?;
+        let res = f(self);
+        self.host.funcs[id.0].1 = Some(f);
+        Some(res)
+    }
+
+    /// Argument `n` of a host call, `R0` to `R3`. Past those it's
+    /// an `InvalidReg` fault for the calling `svc`.
+    pub fn arg(&self, n: u8) -> Result<BIT, Exception> {
+        match n {
+            0..4 => Ok(self.ctx.register[n as usize]),
+            _ => Err(Exception::InvalidReg(n)),
+        }
+    }
+
+    /// Return `value` from a host call.
+    pub fn set_ret(&mut self, value: BIT) {
+        self[R0] = value;
+    }
+
+    /// Copy `len` bytes of guest memory at virtual address `addr`,
+    /// checked like a guest load.
+    pub fn read_bytes(
+        &mut self,
+        addr: BIT,
+        len: BIT,
+    ) -> Result<Vec<u8>, Exception> {
+        (0..len)
+            .map(|i| self.load_u8(addr.wrapping_add(i)))
+            .collect()
+    }
+
+    /// Copy `bytes` to guest memory at virtual address `addr`,
+    /// checked like a guest store.
+    pub fn write_bytes(
+        &mut self,
+        addr: BIT,
+        bytes: &[u8],
+    ) -> Result<(), Exception> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.store_u8(addr.wrapping_add(i as BIT), byte)?;
+        }
+        Ok(())
+    }
+
+    /// NUL terminated string at `addr`, at most `max` bytes long.
+    /// Invalid UTF-8 is replaced.
+    pub fn read_cstr(
+        &mut self,
+        addr: BIT,
+        max: BIT,
+    ) -> Result<String, Exception> {
+        let mut bytes = Vec::new();
+        for i in 0..max {
+            match self.load_u8(addr.wrapping_add(i))? {
+                0 => break,
+                byte => bytes.push(byte),
+            }
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn load_u8(&mut self, addr: BIT) -> Result<u8, Exception> {
+        let pa = self.translate(addr, Access::Read)?;
+        self.mpu.check(pa, 1, Access::Read)?;
+        self.mem.read(pa)
+    }
+
+    fn store_u8(
+        &mut self,
+        addr: BIT,
+        value: u8,
+    ) -> Result<(), Exception> {
+        let pa = self.translate(addr, Access::Write)?;
+        self.mpu.check(pa, 1, Access::Write)?;
+        self.mem.write(pa, value)?;
+        self.icache.invalidate(pa, 1);
+        self.blocks.invalidate(pa, 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn machine(source: &str) -> Machine {
+        let code = assemble("test.jasm", source).code;
+        Machine::builder().program(&code).build().unwrap()
+    }
+
+    #[test]
+    fn args() {
+        let mut vm = machine("ldr r1, #7\nsvc #9\nsvc #10\nhalt\n");
+        vm.host.bind(9, "double", |m| {
+            let n = m.arg(1)?;
+            m.set_ret(2 * n);
+            Ok(())
+        });
+        vm.host.bind(10, "fifth", |m| m.arg(4).map(|_| ()));
+        assert_eq!(vm.run(false), Err(Exception::InvalidReg(4)));
+        assert_eq!(vm[R0], 14);
+    }
+
+    #[test]
+    fn guest_memory() {
+        let mut vm = machine("halt\n");
+        vm.write_bytes(0x100, b"hi\0there").unwrap();
+        assert_eq!(vm.read_cstr(0x100, 64), Ok("hi".to_string()));
+        assert_eq!(vm.read_cstr(0x103, 3), Ok("the".to_string()));
+        assert_eq!(vm.read_bytes(0x101, 2), Ok(b"i\0".to_vec()));
+        let end = crate::memory::MEMORY_LEN as BIT;
+        assert_eq!(
+            vm.write_bytes(end - 1, b"ab"),
+            Err(Exception::InvalidMemoryAccess(end))
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use super::{BIT, OP_LEN};
+use crate::opcode::Instruction;
+
+pub const ICACHE_LEN: usize = 4096;
+
+/// Direct mapped cache of decoded instructions keyed by physical
+/// address. Stores drop any entry whose word they overlap, so
+/// self modifying code stays coherent.
+#[derive(Debug, Clone)]
+pub struct ICache {
+    enabled: bool,
+    entries: Box<[Option<(BIT, Instruction)>]>,
+}
+
+impl Default for ICache {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            entries: vec![None; ICACHE_LEN].into_boxed_slice(),
+        }
+    }
+}
+
+impl ICache {
+    pub fn enable(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.flush();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.fill(None);
+    }
+
+    #[inline]
+    fn slot(pa: BIT) -> usize {
+        (pa / OP_LEN) as usize % ICACHE_LEN
+    }
+
+    #[inline]
+    pub fn get(&self, pa: BIT) -> Option<Instruction> {
+        match self.entries[Self::slot(pa)] {
+            Some((tag, ins)) if tag == pa => Some(ins),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn insert(&mut self, pa: BIT, ins: Instruction) {
+        if !self.enabled {
+            return;
+        }
+
+        self.entries[Self::slot(pa)] = Some((pa, ins));
+    }
+
+    /// Called for every byte range stored to, drops cached words
+    /// overlapping `pa..pa + len`.
+    #[inline]
+    pub fn invalidate(&mut self, pa: BIT, len: BIT) {
+        let start = pa.wrapping_sub(OP_LEN - 1);
+        for i in 0..len + OP_LEN - 1 {
+            let addr = start.wrapping_add(i);
+            let slot = &mut self.entries[Self::slot(addr)];
+            if slot.is_some_and(|(tag, _)| tag == addr) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assembler::assemble,
+        register::*,
+        vm::{Engine, Machine},
+    };
+
+    #[test]
+    fn invalidate_overlapping() {
+        let mut cache = ICache::default();
+        let alias = 0x100 + ICACHE_LEN as BIT * OP_LEN;
+        for (pa, len, dropped) in [
+            (0x104, 4, false),
+            (0xfc, 4, false),
+            (alias, 4, false),
+            (0xfd, 4, true),
+            (0x103, 1, true),
+            (0x100, 4, true),
+        ] {
+            cache.insert(0x100, Instruction::Nop);
+            cache.invalidate(pa, len);
+            assert_eq!(
+                cache.get(0x100).is_none(),
+                dropped,
+                "{pa:#x}+{len}"
+            );
+        }
+    }
+
+    #[test]
+    fn store_over_cached_code() {
+        // runs `patch` twice, storing over it in between
+        let code = assemble(
+            "test.jasm",
+            "
+    ldr r4, #2
+    la r5, template
+    ldr r1, r5
+    la r6, patch
+    add r6, r6, #4
+patch:
+    ldr r2, #1
+    add r3, r3, r2
+    sub r4, r4, #1
+    cmp r4, #0
+    beq done
+    add r13, r6, #0
+    push r1
+    b patch
+done:
+    halt
+template:
+    ldr r2, #7
+",
+        )
+        .code;
+        for engine in [Engine::Interpreter, Engine::Block] {
+            let mut vm =
+                Machine::builder().program(&code).build().unwrap();
+            vm.set_engine(engine);
+            vm.run(false).unwrap();
+            assert_eq!((vm[R2], vm[R3]), (7, 8), "{engine:?}");
+        }
+    }
+}
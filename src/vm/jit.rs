@@ -0,0 +1,443 @@
+//! x86-64 code for hot blocks. Only register to register work is
+//! compiled: native code stops in front of anything touching memory,
+//! raising an exception or changing mode, and the block engine runs
+//! the rest. Memory faults, MMIO and self modifying code therefore
+//! never reach native code, and a store over a block drops its code
+//! along with it.
+
+use std::{ffi::c_void, mem::offset_of};
+
+use super::{
+    block::{Block, Uop},
+    Context, Machine, BIT, OP_LEN,
+};
+use crate::{
+    opcode::{Cond, Instruction, Operand},
+    register::*,
+};
+
+/// Runs of a block before it's compiled.
+pub const JIT_THRESHOLD: u32 = 16;
+const ARENA_LEN: usize = 1 << 20;
+
+/// Compiled block. Takes the register file with `PC` at the start of
+/// the block, leaves `PC` at the next instruction and returns the
+/// number of instructions retired.
+pub(super) type NativeFn =
+    unsafe extern "sysv64" fn(*mut Context) -> u32;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+#[cfg(target_os = "linux")]
+const MAP_ANONYMOUS: i32 = 0x20;
+#[cfg(target_os = "macos")]
+const MAP_ANONYMOUS: i32 = 0x1000;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: i32,
+        flags: i32,
+        fd: i32,
+        offset: i64,
+    ) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+/// Executable memory, filled front to back and reset when full.
+/// Mapped on first use, never writable and executable at once.
+pub(super) struct CodeArena {
+    ptr: *mut u8,
+    used: usize,
+    // the arena couldn't be mapped or made executable
+    failed: bool,
+}
+
+impl Default for CodeArena {
+    fn default() -> Self {
+        Self {
+            ptr: std::ptr::null_mut(),
+            used: 0,
+            failed: false,
+        }
+    }
+}
+
+impl std::fmt::Debug for CodeArena {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        f.debug_struct("CodeArena")
+            .field("used", &self.used)
+            .finish()
+    }
+}
+
+impl CodeArena {
+    /// Copy `code` in, `None` when it doesn't fit or the arena can't
+    /// be mapped or protected, which turns the JIT off for good.
+    fn alloc(&mut self, code: &[u8]) -> Option<NativeFn> {
+        if self.failed {
+            return None;
+        }
+        if self.ptr.is_null() {
+            // SAFETY: fresh anonymous mapping, no existing memory
+            // is touched.
+            let ptr = unsafe {
+                mmap(
+                    std::ptr::null_mut(),
+                    ARENA_LEN,
+                    PROT_READ | PROT_EXEC,
+                    MAP_PRIVATE | MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            if ptr == MAP_FAILED {
+                self.failed = true;
+                return None;
+            }
+            self.ptr = ptr.cast();
+        }
+
+        // keep entry points 16 byte aligned
+        let start = self.used.next_multiple_of(16);
+        if start + code.len() > ARENA_LEN {
+            return None;
+        }
+
+        // SAFETY: `ptr..ptr + ARENA_LEN` is our mapping and
+        // `start + code.len()` is in bounds. Nothing runs native code
+        // while the arena is writable.
+        unsafe {
+            let arena = self.ptr.cast();
+            if mprotect(arena, ARENA_LEN, PROT_READ | PROT_WRITE) != 0
+            {
+                self.failed = true;
+                return None;
+            }
+            let dst = self.ptr.add(start);
+            std::ptr::copy_nonoverlapping(
+                code.as_ptr(),
+                dst,
+                code.len(),
+            );
+            // refused on some hardened kernels, where the arena stays
+            // writable and the block engine runs everything
+            if mprotect(arena, ARENA_LEN, PROT_READ | PROT_EXEC) != 0
+            {
+                self.failed = true;
+                return None;
+            }
+            self.used = start + code.len();
+            Some(std::mem::transmute::<*mut u8, NativeFn>(dst))
+        }
+    }
+
+    /// Forget all code. Every `NativeFn` handed out is dangling after
+    /// this.
+    pub(super) fn reset(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for CodeArena {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            // SAFETY: our own mapping, code in it is unreachable once
+            // the machine is gone.
+            unsafe { munmap(self.ptr.cast(), ARENA_LEN) };
+        }
+    }
+}
+
+const PC_DISP: u8 = (PC as usize * 4) as u8;
+const FLAGS_DISP: u8 =
+    (offset_of!(Context, special) + FLAGS as usize * 4) as u8;
+
+/// `setcc` / `jcc` condition code for the x86 flags after
+/// `cmp lhs, rhs`, which match ours.
+fn x86_cond(cond: Cond) -> Option<u8> {
+    match cond {
+        Cond::Al => None,
+        Cond::Eq => Some(0x4),
+        Cond::Ne => Some(0x5),
+        Cond::Lt => Some(0xc),
+        Cond::Ge => Some(0xd),
+        Cond::Gt => Some(0xf),
+        Cond::Le => Some(0xe),
+        Cond::Lo => Some(0x2),
+        Cond::Hs => Some(0x3),
+        Cond::Hi => Some(0x7),
+        Cond::Ls => Some(0x6),
+    }
+}
+
+/// Native code for one block. The register file is at `rdi`, the
+/// block start pc is kept in `r8d`, `eax`, `ecx`, `edx`, `r9` and
+/// `r10` are scratch.
+#[derive(Default)]
+struct Asm {
+    code: Vec<u8>,
+    // rel32 to patch with an exit and its instruction count
+    exits: Vec<(usize, u32)>,
+}
+
+impl Asm {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm(&mut self, op: &[u8], imm: u32) {
+        self.emit(op);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// `reg` of the ModRM byte: 0 for `eax`, 1 for `ecx`.
+    fn load(&mut self, reg: u8, r: Register, offset: BIT) {
+        if r == PC {
+            // lea reg, [r8 + offset + 4]
+            let disp = offset.wrapping_add(OP_LEN);
+            self.imm(&[0x41, 0x8d, 0x80 | reg << 3], disp);
+        } else {
+            // mov reg, [rdi + r]
+            self.emit(&[0x8b, 0x47 | reg << 3, r as u8 * 4]);
+        }
+    }
+
+    fn operand(&mut self, o: Operand, offset: BIT) {
+        match o {
+            Operand::Reg(r) => self.load(1, r, offset),
+            // mov ecx, imm32
+            Operand::Imm(i) => self.imm(&[0xb9], i),
+        }
+    }
+
+    /// Leave with `PC` at `offset` from the block start.
+    fn exit(&mut self, offset: BIT, count: u32) {
+        // lea eax, [r8 + offset]
+        self.imm(&[0x41, 0x8d, 0x80], offset);
+        // mov [rdi + PC], eax
+        self.emit(&[0x89, 0x47, PC_DISP]);
+        // mov eax, count; ret
+        self.imm(&[0xb8], count);
+        self.emit(&[0xc3]);
+    }
+
+    /// `mov [rdi + rd], eax`. A write to `PC` ends the block.
+    fn store(&mut self, rd: Register, count: u32) -> bool {
+        self.emit(&[0x89, 0x47, rd as u8 * 4]);
+        if rd == PC {
+            self.imm(&[0xb8], count);
+            self.emit(&[0xc3]);
+        }
+        rd == PC
+    }
+
+    /// `cmp eax, ecx` and the FLAGS condition bits, leaving the
+    /// outcome of `cond` in `r10b`.
+    fn compare(&mut self, cond: Option<u8>) {
+        self.emit(&[0x39, 0xc8]);
+        // sete al; sets cl; setae dl; seto r9b
+        self.emit(&[0x0f, 0x94, 0xc0, 0x0f, 0x98, 0xc1]);
+        self.emit(&[0x0f, 0x93, 0xc2, 0x41, 0x0f, 0x90, 0xc1]);
+        if let Some(cc) = cond {
+            // setcc r10b
+            self.emit(&[0x41, 0x0f, 0x90 | cc, 0xc2]);
+        }
+
+        // movzx eax, al
+        self.emit(&[0x0f, 0xb6, 0xc0]);
+        let bits = [
+            ([0x0f, 0xb6, 0xc9].as_slice(), FLAG_N),
+            (&[0x0f, 0xb6, 0xca], FLAG_C),
+            (&[0x41, 0x0f, 0xb6, 0xc9], FLAG_V),
+        ];
+        for (movzx, flag) in bits {
+            // movzx ecx, ..; shl ecx, n; or eax, ecx
+            self.emit(movzx);
+            self.emit(&[0xc1, 0xe1, flag.trailing_zeros() as u8]);
+            self.emit(&[0x09, 0xc8]);
+        }
+
+        // mov edx, [FLAGS]; and edx, !COND_FLAGS; or edx, eax
+        self.emit(&[0x8b, 0x57, FLAGS_DISP]);
+        self.imm(&[0x81, 0xe2], !COND_FLAGS);
+        self.emit(&[0x09, 0xc2]);
+        // mov [FLAGS], edx
+        self.emit(&[0x89, 0x57, FLAGS_DISP]);
+    }
+
+    /// Branch at `offset` to `offset + target`, taken when `r10b` is
+    /// set or always without a condition.
+    fn branch(
+        &mut self,
+        cond: bool,
+        offset: BIT,
+        target: BIT,
+        count: u32,
+    ) {
+        let taken = offset.wrapping_add(target);
+        if !cond {
+            self.exit(taken, count);
+            return;
+        }
+
+        // test r10b, r10b; jz not_taken
+        self.emit(&[0x45, 0x84, 0xd2, 0x74, 0]);
+        let patch = self.code.len();
+        self.exit(taken, count);
+        self.code[patch - 1] = (self.code.len() - patch) as u8;
+        self.exit(offset + OP_LEN, count);
+    }
+
+    /// Translate one uop at `offset`, `count` instructions into the
+    /// block. `None` when it can't be compiled, `Some(true)` when it
+    /// left the block.
+    fn uop(
+        &mut self,
+        offset: BIT,
+        count: u32,
+        uop: Uop,
+    ) -> Option<bool> {
+        let done = match uop {
+            Uop::One(Instruction::Ldr(rd, Operand::Imm(i))) => {
+                // mov eax, imm32
+                self.imm(&[0xb8], i);
+                self.store(rd, count + 1)
+            }
+            Uop::One(
+                Instruction::Add(rd, rs, o)
+                | Instruction::Sub(rd, rs, o)
+                | Instruction::Mul(rd, rs, o),
+            ) => {
+                self.load(0, rs, offset);
+                self.operand(o, offset);
+                self.emit(match uop {
+                    // add eax, ecx
+                    Uop::One(Instruction::Add(..)) => &[0x01, 0xc8],
+                    // sub eax, ecx
+                    Uop::One(Instruction::Sub(..)) => &[0x29, 0xc8],
+                    // imul eax, ecx
+                    _ => &[0x0f, 0xaf, 0xc1],
+                });
+                self.store(rd, count + 1)
+            }
+            Uop::One(Instruction::Div(rd, rs, o)) => {
+                if o == Operand::Imm(0) {
+                    return None;
+                }
+                self.load(0, rs, offset);
+                self.operand(o, offset);
+                if let Operand::Reg(_) = o {
+                    // test ecx, ecx; jz exit, the block engine raises
+                    // the division by zero
+                    self.emit(&[0x85, 0xc9, 0x0f, 0x84, 0, 0, 0, 0]);
+                    self.exits.push((self.code.len(), count));
+                }
+                // xor edx, edx; div ecx
+                self.emit(&[0x31, 0xd2, 0xf7, 0xf1]);
+                self.store(rd, count + 1)
+            }
+            Uop::One(Instruction::Cmp(r, o)) => {
+                self.load(0, r, offset);
+                self.operand(o, offset);
+                self.compare(None);
+                false
+            }
+            Uop::One(Instruction::B(Cond::Al, target)) => {
+                self.branch(false, offset, target, count + 1);
+                true
+            }
+            Uop::CmpBranch(r, o, cond, target) => {
+                self.load(0, r, offset);
+                self.operand(o, offset);
+                let cc = x86_cond(cond);
+                self.compare(cc);
+                let at = offset + OP_LEN;
+                self.branch(cc.is_some(), at, target, count + 2);
+                true
+            }
+            Uop::One(Instruction::Nop) => false,
+            _ => return None,
+        };
+        Some(done)
+    }
+}
+
+/// Native code for the longest compilable prefix of `uops`, `None`
+/// if that's empty.
+fn assemble(uops: &[(BIT, Uop)], len: BIT) -> Option<Vec<u8>> {
+    let mut asm = Asm::default();
+    // mov r8d, [rdi + PC]
+    asm.emit(&[0x44, 0x8b, 0x47, PC_DISP]);
+
+    let mut end = len;
+    for &(offset, uop) in uops {
+        match asm.uop(offset, offset / OP_LEN, uop) {
+            Some(true) => {
+                end = BIT::MAX;
+                break;
+            }
+            Some(false) => {}
+            None if offset == 0 => return None,
+            None => {
+                end = offset;
+                break;
+            }
+        }
+    }
+    if end != BIT::MAX {
+        asm.exit(end, end / OP_LEN);
+    }
+
+    for (patch, count) in std::mem::take(&mut asm.exits) {
+        let rel = (asm.code.len() - patch) as u32;
+        asm.code[patch - 4..patch]
+            .copy_from_slice(&rel.to_le_bytes());
+        asm.exit(count * OP_LEN, count);
+    }
+    Some(asm.code)
+}
+
+impl Machine {
+    /// Run `block` natively once it's hot. `None` until then and for
+    /// blocks that don't compile, otherwise the number of
+    /// instructions retired, with `PC` after the last.
+    pub(super) fn run_native(
+        &mut self,
+        block: &Block,
+    ) -> Option<u32> {
+        if self.jit.failed {
+            return None;
+        }
+        let hits = block.hits.get().saturating_add(1);
+        block.hits.set(hits);
+        if hits < JIT_THRESHOLD {
+            return None;
+        }
+
+        let native = *block.native.get_or_init(|| {
+            let code = assemble(&block.uops, block.len)?;
+            self.jit.alloc(&code).or_else(|| {
+                if self.jit.failed {
+                    return None;
+                }
+                // out of space, drop every block pointing into it
+                self.blocks.flush();
+                self.jit.reset();
+                self.jit.alloc(&code)
+            })
+        });
+
+        // SAFETY: the code only touches the register file it's given
+        // and stays in the arena until the blocks using it are gone.
+        native.map(|f| unsafe { f(&mut self.ctx) })
+    }
+}
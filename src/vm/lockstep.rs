@@ -0,0 +1,502 @@
+//! Differential check of one machine against another, typically the
+//! block engine against the plain interpreter.
+
+use std::ops::Range;
+
+use super::{Machine, BIT};
+use crate::error::Exception;
+
+/// First point where two machines disagree.
+#[derive(Debug)]
+pub struct Divergence {
+    /// Instructions retired by the reference when it was found.
+    pub steps: u64,
+    pub what: String,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        write!(
+            f,
+            "diverged after {} steps: {}",
+            self.steps, self.what
+        )
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+fn diverge(steps: u64, what: String) -> Result<u64, Divergence> {
+    Err(Divergence { steps, what })
+}
+
+/// Run `subject` with `dispatch` and single step `reference` up to
+/// the same instruction count after every dispatch, comparing
+/// results, registers and the halt state, and at the end the bytes of
+/// `mem`. Stops at halt, the first error or once `max_steps`
+/// instructions ran, returning the instruction count.
+pub fn lockstep(
+    reference: &mut Machine,
+    subject: &mut Machine,
+    max_steps: u64,
+    mem: Range<BIT>,
+) -> Result<u64, Divergence> {
+    reference.halt = false;
+    subject.halt = false;
+    while subject.steps() < max_steps && !subject.is_halted() {
+        let res = subject.dispatch();
+
+        let mut expected: Result<(), Exception> = Ok(());
+        while reference.steps() < subject.steps() {
+            expected = reference.step();
+            if expected.is_err() || reference.is_halted() {
+                break;
+            }
+        }
+
+        let steps = reference.steps();
+        if steps != subject.steps() {
+            return diverge(
+                steps,
+                format!("subject retired {}", subject.steps()),
+            );
+        }
+        if expected != res {
+            return diverge(
+                steps,
+                format!("expected {expected:?}, got {res:?}"),
+            );
+        }
+        if reference.ctx.register != subject.ctx.register {
+            return diverge(
+                steps,
+                format!(
+                    "registers {:x?} != {:x?}",
+                    reference.ctx.register, subject.ctx.register
+                ),
+            );
+        }
+        if reference.ctx.special != subject.ctx.special {
+            return diverge(
+                steps,
+                format!(
+                    "special registers {:x?} != {:x?}",
+                    reference.ctx.special, subject.ctx.special
+                ),
+            );
+        }
+        if reference.is_halted() != subject.is_halted() {
+            return diverge(steps, "halt state".into());
+        }
+        if res.is_err() {
+            break;
+        }
+    }
+
+    for addr in mem {
+        let a = reference.mem.read(addr);
+        let b = subject.mem.read(addr);
+        if a != b {
+            return diverge(
+                reference.steps(),
+                format!("memory at {addr:#x}: {a:?} != {b:?}"),
+            );
+        }
+    }
+
+    Ok(subject.steps())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lockstep;
+    use crate::{
+        assembler::assemble,
+        register::*,
+        vm::{
+            Engine, Machine, MachineBuilder, TrapMode, JIT_THRESHOLD,
+        },
+    };
+
+    const RAM: u32 = 0x10000;
+
+    /// Handlers that step over the faulting instruction, counting in
+    /// `r11`, and stop on a privilege violation with the cause in
+    /// `r9`.
+    const VECTORS: &str = "
+skip:
+    mrs r7, epc
+    add r7, r7, #4
+    msr epc, r7
+    add r11, r11, #1
+    eret
+priv:
+    mrs r9, cause
+    halt
+vectors:
+    exit #90
+    exit #91
+    exit #92
+    exit #93
+    exit #94
+    b skip
+    b skip
+    b priv
+    exit #98
+    b skip
+";
+
+    fn machine(
+        code: &[u8],
+        trap: TrapMode,
+        engine: Engine,
+    ) -> Machine {
+        let mut machine = MachineBuilder::new()
+            .ram(RAM as usize)
+            .program(code)
+            .trap_mode(trap)
+            .build()
+            .unwrap();
+        machine.set_engine(engine);
+        machine
+    }
+
+    /// Run `code` on `engine` in lockstep with the interpreter,
+    /// returning the interpreter.
+    fn run(code: &[u8], trap: TrapMode, engine: Engine) -> Machine {
+        let mut reference = machine(code, trap, Engine::Interpreter);
+        let mut subject = machine(code, trap, engine);
+        if let Err(e) =
+            lockstep(&mut reference, &mut subject, 100_000, 0..RAM)
+        {
+            panic!("{engine:?}: {e}");
+        }
+        reference
+    }
+
+    /// `source` run on the block engine and the JIT.
+    fn check(source: &str, trap: TrapMode) -> Machine {
+        let code = assemble("test.jasm", source).code;
+        run(&code, trap, Engine::Block);
+        run(&code, trap, Engine::Jit)
+    }
+
+    #[test]
+    fn branches() {
+        let m = check(
+            "
+    ldr r0, #100
+    ldr r1, #0
+loop:
+    add r1, r1, r0
+    sub r0, r0, #1
+    cmp r0, #0
+    bne loop
+    call double
+    cmp r1, #10100
+    beq 1f
+    exit #1
+1:
+    b 1f
+    exit #2
+1:
+    la r3, done
+    call r3
+    halt
+done:
+    ldr r4, #1
+    ret
+double:
+    mul r1, r1, #2
+    ret
+",
+            TrapMode::Abort,
+        );
+        assert!(m.is_halted());
+        assert_eq!((m[R1], m[R4]), (10100, 1));
+    }
+
+    #[test]
+    fn fused_cmp_branch() {
+        let m = check(
+            "
+    ldr r0, #0
+    sub r5, r0, #1
+    ldr r6, #0
+    cmp r5, #1
+    blt 1f
+    add r6, r6, #1
+1:
+    cmp r5, #1
+    blo 1f
+    add r6, r6, #2
+1:
+    cmp r5, r5
+    bne 1f
+    add r6, r6, #4
+1:
+    cmp r0, r5
+    bhi 1f
+    add r6, r6, #8
+1:
+    cmp r5, #0
+    bge 1f
+    add r6, r6, #16
+1:
+    halt
+",
+            TrapMode::Abort,
+        );
+        assert_eq!(m[R6], 30);
+    }
+
+    #[test]
+    fn fused_push_pop() {
+        let m = check(
+            "
+    ldr r1, #5
+    push r1
+    pop r2
+    push #9
+    pop r3
+    la r4, 1f
+    push r4
+    pop r15
+    halt
+1:
+    push r2
+    push r3
+    pop r5
+    pop r6
+    halt
+",
+            TrapMode::Abort,
+        );
+        assert_eq!((m[R2], m[R3], m[R5], m[R6]), (5, 9, 9, 5));
+
+        // the fused push overflows the stack
+        let m = check(
+            "
+    ldr r0, #1024
+    msr sbase, r0
+    ldr r0, #1020
+    msr slimit, r0
+    push #1
+    push #2
+    pop r1
+    halt
+",
+            TrapMode::Abort,
+        );
+        assert!(!m.is_halted());
+        assert_eq!(m[SP], 1020);
+    }
+
+    #[test]
+    fn traps() {
+        let m = check(
+            &format!(
+                "
+    la r0, vectors
+    msr vbar, r0
+    ldr r1, #0
+    div r2, r1, r1
+    svc #3
+    la r0, user
+    msr epc, r0
+    mrs r0, flags
+    add r0, r0, #512
+    msr flags, r0
+    ldr r0, #2048
+    msr bsp, r0
+    eret
+user:
+    add r1, r1, #1
+    svc #4
+    add r1, r1, #1
+    cli
+    halt
+{VECTORS}"
+            ),
+            TrapMode::Vector,
+        );
+        assert!(m.is_halted());
+        assert_eq!((m[R1], m[R9], m[R11]), (2, 7, 3));
+    }
+
+    #[test]
+    fn mmu() {
+        let m = check(
+            &format!(
+                "
+    la r0, vectors
+    msr vbar, r0
+    ; level one entry 0 -> the level two table at 8192
+    ldr r13, #4100
+    push #8193
+    ; page 0 mapped to itself, page 5 to frame 0
+    ldr r13, #8196
+    push #15
+    ldr r13, #8216
+    push #15
+    ldr r13, #1024
+    ldr r0, #4096
+    msr ptbr, r0
+    mrs r0, flags
+    add r0, r0, #4096
+    msr flags, r0
+    la r1, aliased
+    ldr r8, #20480
+    add r1, r1, r8
+    call r1
+    ldr r6, r8
+    ldr r9, #36864
+    ldr r7, r9
+    ; off, page 5 to frame 4096 and on again
+    mrs r0, flags
+    sub r0, r0, #4096
+    msr flags, r0
+    ldr r13, #8216
+    push #4111
+    ldr r13, #1024
+    mrs r0, flags
+    add r0, r0, #4096
+    msr flags, r0
+    ldr r10, r8
+    halt
+aliased:
+    add r3, r3, #1
+    ret
+{VECTORS}"
+            ),
+            TrapMode::Vector,
+        );
+        assert!(m.is_halted());
+        let first = m.mem.read_u32(0).unwrap();
+        assert_eq!((m[R3], m[R6], m[R11]), (1, first, 1));
+        // the level one entry, not a stale translation to frame 0
+        assert_eq!(m[R10], 8193);
+    }
+
+    #[test]
+    fn self_modifying_stores() {
+        let m = check(
+            "
+    la r5, template
+    ldr r1, r5
+    la r0, patch
+    add r13, r0, #4
+    push r1
+patch:
+    ldr r2, #1
+    ldr r13, #1024
+    halt
+template:
+    ldr r2, #7
+",
+            TrapMode::Abort,
+        );
+        assert_eq!(m[R2], 7);
+    }
+
+    #[test]
+    fn push_over_fused_pop() {
+        let m = check(
+            "
+    la r5, template
+    ldr r1, r5
+    la r0, fused
+    add r13, r0, #8
+fused:
+    push r1
+    pop r3
+    ldr r13, #1024
+    halt
+template:
+    ldr r2, #7
+",
+            TrapMode::Abort,
+        );
+        assert_eq!((m[R2], m[R3]), (7, 0));
+    }
+
+    #[test]
+    fn hot_loops() {
+        let m = check(
+            &format!(
+                "
+    ldr r0, #{n}
+    ldr r1, #0
+    ldr r2, #1
+loop:
+    add r1, r1, r0
+    mul r2, r2, #3
+    div r3, r2, #7
+    sub r4, r3, r1
+    cmp r4, r1
+    bgt 1f
+    add r5, r5, #1
+1:
+    sub r0, r0, #1
+    cmp r0, #0
+    bne loop
+    ; a hot block ending in a division by zero
+    ldr r0, #{n}
+    ldr r6, #0
+2:
+    sub r0, r0, #1
+    cmp r0, #1
+    bne 2b
+    div r7, r0, r6
+    halt
+",
+                n = 4 * JIT_THRESHOLD
+            ),
+            TrapMode::Abort,
+        );
+        assert!(!m.is_halted());
+        assert_eq!(m[R0], 1);
+    }
+
+    #[test]
+    fn store_over_hot_block() {
+        let m = check(
+            &format!(
+                "
+    ldr r3, #2
+again:
+    ldr r0, #{n}
+loop:
+    add r2, r2, #1
+patch:
+    add r2, r2, #1
+    sub r0, r0, #1
+    cmp r0, #0
+    bne loop
+    sub r3, r3, #1
+    cmp r3, #0
+    beq done
+    ; rewrite the compiled loop and run it again
+    la r5, template
+    ldr r1, r5
+    la r0, patch
+    add r13, r0, #4
+    push r1
+    ldr r13, #1024
+    b again
+done:
+    halt
+template:
+    add r2, r2, #100
+",
+                n = 2 * JIT_THRESHOLD
+            ),
+            TrapMode::Abort,
+        );
+        let n = 2 * JIT_THRESHOLD;
+        assert_eq!(m[R2], 2 * n + 101 * n);
+    }
+}
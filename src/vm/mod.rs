@@ -0,0 +1,807 @@
+mod block;
+mod builder;
+mod hook;
+mod host;
+mod icache;
+#[cfg_attr(
+    not(all(
+        target_arch = "x86_64",
+        any(target_os = "linux", target_os = "macos")
+    )),
+    path = "nojit.rs"
+)]
+mod jit;
+pub mod lockstep;
+mod run;
+
+pub use block::{BlockCache, BLOCK_LEN};
+pub use builder::MachineBuilder;
+pub use hook::{Hook, HookId};
+pub use host::{HostFn, HostFunctions, HostId};
+pub use icache::ICache;
+pub use jit::JIT_THRESHOLD;
+pub use run::{StopReason, SYS_EXIT};
+
+use crate::{
+    error::{Exception, VECTOR_LEN},
+    memory::{Access, Addressable},
+    mmu::{Tlb, PAGE_MASK},
+    mpu::Mpu,
+    opcode::Instruction,
+    register::*,
+};
+use std::{
+    collections::HashSet,
+    ops::{Index, IndexMut},
+};
+
+pub type BIT = u32;
+pub const OP_LEN: BIT = std::mem::size_of::<BIT>() as BIT;
+
+/// What `Machine::step` does with a CPU exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrapMode {
+    /// Return the exception as `Err`, stopping `run`.
+    #[default]
+    Abort,
+    /// Deliver the exception to the guest: `EPC` gets the faulting
+    /// pc, `CAUSE` the cause number, `TVAL` the fault value and
+    /// execution continues in supervisor mode at `VBAR + CAUSE * 4`.
+    /// `eret` resumes at `EPC` in the saved mode, so a handler that
+    /// means to go past the instruction, as for `svc`, adds 4 to
+    /// `EPC` first or runs it again.
+    Vector,
+}
+
+/// How `Machine::dispatch` executes code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// One instruction per dispatch through `Machine::step`.
+    #[default]
+    Interpreter,
+    /// Translate straight line code into cached blocks of fused
+    /// micro ops and run a whole block per dispatch. Architectural
+    /// state after each dispatch matches single stepping.
+    Block,
+    /// The block engine, with blocks run `JIT_THRESHOLD` times
+    /// compiled to native code. Only on x86-64 Linux and macOS, and
+    /// not while the MPU is enabled, the block engine otherwise.
+    Jit,
+}
+
+/// Register file, laid out for native code from the JIT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[repr(C)]
+struct Context {
+    register: [BIT; REGISTER_LEN],
+    special: [BIT; SPECIAL_REGISTER_LEN],
+}
+
+#[derive(Debug)]
+pub struct Machine {
+    ctx: Context,
+    // stack: Stack<BIT, STACK_LEN>,
+    pub mem: Box<dyn Addressable>,
+    pub mpu: Mpu,
+    pub host: HostFunctions,
+    tlb: Tlb,
+    icache: ICache,
+    blocks: BlockCache,
+    jit: jit::CodeArena,
+    engine: Engine,
+    breakpoints: HashSet<BIT>,
+    hooks: hook::Hooks,
+    steps: u64,
+    halt: bool,
+    exit: Option<BIT>,
+    trap_mode: TrapMode,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new()
+    }
+}
+
+impl Machine {
+    pub fn new() -> Self {
+        MachineBuilder::new().build().unwrap()
+    }
+
+    pub fn builder<'p>() -> MachineBuilder<'p> {
+        MachineBuilder::new()
+    }
+
+    fn with_memory(mem: Box<dyn Addressable>) -> Self {
+        Self {
+            ctx: Context {
+                register: [0; REGISTER_LEN],
+                special: [0; SPECIAL_REGISTER_LEN],
+            },
+            // stack: Stack::new(),
+            mem,
+            mpu: Mpu::default(),
+            host: HostFunctions::default(),
+            tlb: Tlb::default(),
+            icache: ICache::default(),
+            blocks: BlockCache::default(),
+            jit: jit::CodeArena::default(),
+            breakpoints: HashSet::new(),
+            hooks: hook::Hooks::default(),
+            engine: Engine::default(),
+            steps: 0,
+            halt: false,
+            exit: None,
+            trap_mode: TrapMode::default(),
+        }
+    }
+
+    pub fn state(&self) {
+        for (i, regs) in self.ctx.register.chunks(4).enumerate() {
+            let line = regs
+                .iter()
+                .enumerate()
+                .map(|(j, v)| format!("R{:<2}: {v:<10}", i * 4 + j))
+                .collect::<Vec<_>>();
+            println!("{}", line.join(" ").trim_end());
+        }
+        println!("FL : {}", self[FLAGS]);
+    }
+
+    pub fn set_trap_mode(&mut self, mode: TrapMode) {
+        self.trap_mode = mode;
+    }
+
+    pub fn trap_mode(&self) -> TrapMode {
+        self.trap_mode
+    }
+
+    /// Toggle the decoded instruction cache, on by default.
+    pub fn set_icache(&mut self, enabled: bool) {
+        self.icache.enable(enabled);
+    }
+
+    /// Drop every cached instruction and translated block. Needed
+    /// after changing code through `mem` directly, guest stores
+    /// invalidate on their own.
+    pub fn flush_icache(&mut self) {
+        self.icache.flush();
+        self.blocks.flush();
+        self.jit.reset();
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    /// Instructions executed so far, including faulting ones.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halt
+    }
+
+    /// Code given to `exit` by the last run, `None` if it stopped any
+    /// other way.
+    pub fn exit_code(&self) -> Option<BIT> {
+        self.exit
+    }
+
+    pub fn run(&mut self, f: bool) -> Result<(), Exception> {
+        self.halt = false;
+        self.exit = None;
+        while !self.halt {
+            self.dispatch()?;
+            if f {
+                self.state();
+                println!("{}", "-".repeat(10));
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the next instruction, or the next block with
+    /// `Engine::Block`.
+    pub fn dispatch(&mut self) -> Result<(), Exception> {
+        if !self.hooks.is_empty() {
+            return self.step_hooked();
+        }
+        match self.engine {
+            Engine::Interpreter => self.step(),
+            Engine::Block | Engine::Jit => self.step_block(),
+        }
+    }
+
+    pub fn step(&mut self) -> Result<(), Exception> {
+        if !self.hooks.is_empty() {
+            return self.step_hooked();
+        }
+        let pc = self[PC];
+        self.steps += 1;
+        match self.execute(pc) {
+            Err(e) => self.fault(pc, e),
+            res => res,
+        }
+    }
+
+    /// Handle an exception raised by the instruction at `pc` as the
+    /// trap mode says.
+    fn fault(
+        &mut self,
+        pc: BIT,
+        e: Exception,
+    ) -> Result<(), Exception> {
+        match self.trap_mode {
+            TrapMode::Vector => self.trap(pc, e),
+            TrapMode::Abort => Err(e),
+        }
+    }
+
+    /// Enter the vector table slot for `e`. Exceptions without a
+    /// cause number, and faults raised from inside the vector table
+    /// itself, can't be handled by the guest and are returned.
+    fn trap(
+        &mut self,
+        pc: BIT,
+        e: Exception,
+    ) -> Result<(), Exception> {
+        let Some(cause) = e.cause() else {
+            return Err(e);
+        };
+
+        let vector = self[VBAR];
+        if pc.wrapping_sub(vector) < VECTOR_LEN * OP_LEN {
+            return Err(e);
+        }
+
+        // save the interrupted mode and enter supervisor mode
+        let flags = self[FLAGS];
+        self.set_user(false);
+        self[FLAGS] &= !(FLAG_PREV_USER | FLAG_IE | FLAG_PREV_IE);
+        if flags & FLAG_USER != 0 {
+            self[FLAGS] |= FLAG_PREV_USER;
+        }
+        if flags & FLAG_IE != 0 {
+            self[FLAGS] |= FLAG_PREV_IE;
+        }
+
+        self[EPC] = pc;
+        self[CAUSE] = cause;
+        self[TVAL] = e.value();
+        self[PC] = vector.wrapping_add(cause * OP_LEN);
+        Ok(())
+    }
+
+    pub fn is_user(&self) -> bool {
+        self[FLAGS] & FLAG_USER != 0
+    }
+
+    /// Switch privilege mode, swapping in the other mode's stack
+    /// pointer from `BSP`.
+    fn set_user(&mut self, user: bool) {
+        if self.is_user() != user {
+            std::mem::swap(
+                &mut self.ctx.register[SP as usize],
+                &mut self.ctx.special[BSP as usize],
+            );
+            self[FLAGS] ^= FLAG_USER;
+        }
+    }
+
+    /// Special register write through `msr`: the mode bit, and in
+    /// user mode every system bit, of FLAGS is read only.
+    fn set_special(&mut self, s: SpecialRegister, value: BIT) {
+        let mmu = self[FLAGS] & FLAG_MMU;
+        if s == FLAGS {
+            let keep = if self.is_user() {
+                SYSTEM_FLAGS
+            } else {
+                FLAG_USER
+            };
+            self[FLAGS] = value & !keep | self[FLAGS] & keep;
+        } else {
+            self[s] = value;
+        }
+
+        // like a page table switch on most cpus, and the tables may
+        // have changed while the mmu was off
+        if s == PTBR || self[FLAGS] & FLAG_MMU != mmu {
+            self.tlb.flush();
+        }
+    }
+
+    /// Virtual to physical address, the identity unless `FLAG_MMU`
+    /// is set.
+    #[inline]
+    fn translate(
+        &mut self,
+        addr: BIT,
+        access: Access,
+    ) -> Result<BIT, Exception> {
+        if self[FLAGS] & FLAG_MMU == 0 {
+            return Ok(addr);
+        }
+
+        let user = self.is_user();
+        self.tlb
+            .translate(&*self.mem, self[PTBR], addr, access, user)
+    }
+
+    /// Whether a word access at `addr` spans two pages, which may
+    /// map to unrelated frames.
+    #[inline]
+    fn split(&self, addr: BIT) -> bool {
+        self[FLAGS] & FLAG_MMU != 0
+            && addr & PAGE_MASK > PAGE_MASK - 3
+    }
+
+    #[inline]
+    fn fetch(&mut self, pc: BIT) -> Result<Instruction, Exception> {
+        if self.split(pc) {
+            let word = self.load_bytes(pc, Access::Execute)?;
+            return Instruction::try_from(word);
+        }
+        let pa = self.translate(pc, Access::Execute)?;
+        self.mpu.check(pa, OP_LEN, Access::Execute)?;
+        if let Some(ins) = self.icache.get(pa) {
+            return Ok(ins);
+        }
+
+        let ins = Instruction::try_from(self.mem.read_u32(pa)?)?;
+        self.icache.insert(pa, ins);
+        Ok(ins)
+    }
+
+    #[inline]
+    fn load(&mut self, addr: BIT) -> Result<BIT, Exception> {
+        let value = if self.split(addr) {
+            self.load_bytes(addr, Access::Read)?
+        } else {
+            let pa = self.translate(addr, Access::Read)?;
+            self.mpu.check(pa, OP_LEN, Access::Read)?;
+            self.mem.read_u32(pa)?
+        };
+        if !self.hooks.is_empty() {
+            self.hooks.each(|h| h.mem_read(addr, value));
+        }
+        Ok(value)
+    }
+
+    #[inline]
+    fn store(
+        &mut self,
+        addr: BIT,
+        value: BIT,
+    ) -> Result<(), Exception> {
+        if self.split(addr) {
+            self.store_bytes(addr, value)?;
+        } else {
+            let pa = self.translate(addr, Access::Write)?;
+            self.mpu.check(pa, OP_LEN, Access::Write)?;
+            self.mem.write_u32(pa, value)?;
+            self.icache.invalidate(pa, OP_LEN);
+            self.blocks.invalidate(pa, OP_LEN);
+        }
+        if !self.hooks.is_empty() {
+            self.hooks.each(|h| h.mem_write(addr, value));
+        }
+        Ok(())
+    }
+
+    #[cold]
+    fn load_bytes(
+        &mut self,
+        addr: BIT,
+        access: Access,
+    ) -> Result<BIT, Exception> {
+        let mut bytes = [0; OP_LEN as usize];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let pa =
+                self.translate(addr.wrapping_add(i as BIT), access)?;
+            self.mpu.check(pa, 1, access)?;
+            *byte = self.mem.read(pa)?;
+        }
+        Ok(BIT::from_le_bytes(bytes))
+    }
+
+    #[cold]
+    fn store_bytes(
+        &mut self,
+        addr: BIT,
+        value: BIT,
+    ) -> Result<(), Exception> {
+        // translate every byte first so a fault leaves memory as is
+        let mut pas = [0; OP_LEN as usize];
+        for (i, pa) in pas.iter_mut().enumerate() {
+            *pa = self.translate(
+                addr.wrapping_add(i as BIT),
+                Access::Write,
+            )?;
+            self.mpu.check(*pa, 1, Access::Write)?;
+        }
+        pas.into_iter().zip(value.to_le_bytes()).try_for_each(
+            |(pa, byte)| {
+                self.mem.write(pa, byte)?;
+                self.icache.invalidate(pa, 1);
+                self.blocks.invalidate(pa, 1);
+                Ok(())
+            },
+        )
+    }
+
+    /// Set the FLAGS condition bits for `lhs - rhs`.
+    #[inline]
+    fn compare(&mut self, lhs: BIT, rhs: BIT) {
+        let res = lhs.wrapping_sub(rhs);
+        let mut flags = self[FLAGS] & !COND_FLAGS;
+        if res == 0 {
+            flags |= FLAG_Z;
+        }
+        if res >> 31 != 0 {
+            flags |= FLAG_N;
+        }
+        if lhs >= rhs {
+            flags |= FLAG_C;
+        }
+        if ((lhs ^ rhs) & (lhs ^ res)) >> 31 != 0 {
+            flags |= FLAG_V;
+        }
+        self[FLAGS] = flags;
+    }
+
+    /// Push a word, faulting with `StackOverflow` when it would go
+    /// below `SLIMIT`. Bounds are only checked while `SBASE` is set.
+    fn push(&mut self, value: BIT) -> Result<(), Exception> {
+        let sp = self[SP];
+        let next = if self[SBASE] == 0 {
+            sp.wrapping_sub(OP_LEN)
+        } else {
+            sp.checked_sub(OP_LEN)
+                .filter(|&next| next >= self[SLIMIT])
+                .ok_or(Exception::StackOverflow(sp))?
+        };
+        self.store(next, value)?;
+        self[SP] = next;
+        Ok(())
+    }
+
+    /// Pop a word, faulting with `StackUnderflow` when it would go
+    /// above `SBASE`.
+    fn pop(&mut self) -> Result<BIT, Exception> {
+        let sp = self[SP];
+        let next = if self[SBASE] == 0 {
+            sp.wrapping_add(OP_LEN)
+        } else {
+            sp.checked_add(OP_LEN)
+                .filter(|&next| next <= self[SBASE])
+                .ok_or(Exception::StackUnderflow(sp))?
+        };
+        let value = self.load(sp)?;
+        self[SP] = next;
+        Ok(value)
+    }
+
+    fn execute(&mut self, pc: BIT) -> Result<(), Exception> {
+        // print!("DEBUG: pc={} ", pc);
+
+        let op = self.fetch(pc)?;
+        self[PC] = pc.wrapping_add(OP_LEN);
+        self.exec(pc, op)
+    }
+
+    /// Execute a decoded instruction fetched from `pc`, with `PC`
+    /// already pointing past it.
+    #[inline]
+    fn exec(
+        &mut self,
+        pc: BIT,
+        op: Instruction,
+    ) -> Result<(), Exception> {
+        // println!("instruction: {op:?}");
+        if self.is_user() && op.is_privileged() {
+            return Err(Exception::PrivilegeViolation(
+                u32::try_from(op)?,
+            ));
+        }
+        match op {
+            Instruction::Nop => Ok(()),
+            Instruction::Halt => {
+                self.halt = true;
+                Ok(())
+            }
+            Instruction::Exit(o) => {
+                self.exit = Some(match o {
+                    crate::opcode::Operand::Reg(r) => self[r],
+                    crate::opcode::Operand::Imm(i) => i,
+                });
+                self.halt = true;
+                Ok(())
+            }
+
+            Instruction::Add(r1, r2, r3) => {
+                let value = self[r2].wrapping_add(match r3 {
+                    crate::opcode::Operand::Reg(r) => self[r],
+                    crate::opcode::Operand::Imm(i) => i,
+                });
+                self[r1] = value;
+                Ok(())
+            }
+            Instruction::Sub(r1, r2, r3) => {
+                let value = self[r2].wrapping_sub(match r3 {
+                    crate::opcode::Operand::Reg(r) => self[r],
+                    crate::opcode::Operand::Imm(i) => i,
+                });
+                self[r1] = value;
+                Ok(())
+            }
+            Instruction::Mul(r1, r2, r3) => {
+                let value = self[r2].wrapping_mul(match r3 {
+                    crate::opcode::Operand::Reg(r) => self[r],
+                    crate::opcode::Operand::Imm(i) => i,
+                });
+                self[r1] = value;
+                Ok(())
+            }
+            Instruction::Div(r1, r2, r3) => {
+                let div = match r3 {
+                    crate::opcode::Operand::Reg(r) => self[r],
+                    crate::opcode::Operand::Imm(i) => i,
+                };
+
+                if div == 0 {
+                    return Err(Exception::DivisionByZero);
+                }
+
+                self[r1] = self[r2] / div;
+                Ok(())
+            }
+            Instruction::Cmp(r, o) => {
+                let rhs = match o {
+                    crate::opcode::Operand::Reg(r) => self[r],
+                    crate::opcode::Operand::Imm(i) => i,
+                };
+                self.compare(self[r], rhs);
+                Ok(())
+            }
+            Instruction::B(cond, offset) => {
+                if cond.holds(self[FLAGS]) {
+                    self[PC] = pc.wrapping_add(offset);
+                }
+                Ok(())
+            }
+            Instruction::Ldr(r, o) => {
+                let value = match o {
+                    crate::opcode::Operand::Reg(r) => {
+                        self.load(self[r])?
+                    }
+                    crate::opcode::Operand::Imm(i) => i,
+                };
+                self[r] = value;
+                Ok(())
+            }
+            Instruction::Push(o) => {
+                self.push(match o {
+                    crate::opcode::Operand::Reg(r) => self[r],
+                    crate::opcode::Operand::Imm(i) => i,
+                })?;
+                // println!("DEBUG: pushed to {}", self[SP]);
+                Ok(())
+            }
+            Instruction::Pop(o) => {
+                let r = match o {
+                    crate::opcode::Operand::Reg(r) => r,
+                    crate::opcode::Operand::Imm(_) => {
+                        return Err(Exception::InvalidOp(
+                            crate::opcode::Op::Pop as u8,
+                        ))
+                    }
+                };
+                // println!("DEBUG: popping from {}", self[SP]);
+                let value = self.pop()?;
+                self[r] = value;
+                Ok(())
+            }
+
+            Instruction::Call(o) => {
+                let target = match o {
+                    crate::opcode::Operand::Reg(r) => self[r],
+                    crate::opcode::Operand::Imm(i) => {
+                        pc.wrapping_add(i)
+                    }
+                };
+                self.push(self[PC])?;
+                self[PC] = target;
+                Ok(())
+            }
+            Instruction::Ret => {
+                self[PC] = self.pop()?;
+                Ok(())
+            }
+
+            Instruction::Svc(n) => {
+                match self
+                    .host
+                    .syscall(n)
+                    .and_then(|id| self.call_host(id))
+                {
+                    Some(res) => res,
+                    None => Err(Exception::Syscall(n)),
+                }
+            }
+            Instruction::Eret => {
+                let flags = self[FLAGS];
+                self.set_user(flags & FLAG_PREV_USER != 0);
+                self[FLAGS] &= !FLAG_IE;
+                if flags & FLAG_PREV_IE != 0 {
+                    self[FLAGS] |= FLAG_IE;
+                }
+                self[PC] = self[EPC];
+                Ok(())
+            }
+            Instruction::Cli => {
+                self[FLAGS] &= !FLAG_IE;
+                Ok(())
+            }
+            Instruction::Sti => {
+                self[FLAGS] |= FLAG_IE;
+                Ok(())
+            }
+            Instruction::TlbFlush => {
+                self.tlb.flush();
+                Ok(())
+            }
+            Instruction::Mrs(r, s) => {
+                self[r] = self[s];
+                Ok(())
+            }
+            Instruction::Msr(s, r) => {
+                self.set_special(s, self[r]);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Index<Register> for Machine {
+    type Output = BIT;
+
+    #[inline]
+    fn index(&self, index: Register) -> &Self::Output {
+        &self.ctx.register[index as usize]
+    }
+}
+
+impl IndexMut<Register> for Machine {
+    #[inline]
+    fn index_mut(&mut self, index: Register) -> &mut Self::Output {
+        &mut self.ctx.register[index as usize]
+    }
+}
+
+impl Index<SpecialRegister> for Machine {
+    type Output = BIT;
+
+    #[inline]
+    fn index(&self, index: SpecialRegister) -> &Self::Output {
+        &self.ctx.special[index as usize]
+    }
+}
+
+impl IndexMut<SpecialRegister> for Machine {
+    #[inline]
+    fn index_mut(
+        &mut self,
+        index: SpecialRegister,
+    ) -> &mut Self::Output {
+        &mut self.ctx.special[index as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    /// A machine loaded with `source`, and how running it ended.
+    fn run(source: &str) -> (Machine, Result<(), Exception>) {
+        let code = assemble("test.jasm", source).code;
+        let mut vm =
+            Machine::builder().program(&code).build().unwrap();
+        let res = vm.run(false);
+        (vm, res)
+    }
+
+    /// The stack is the two words below 1024, where `SP` starts.
+    const BOUNDS: &str = "
+    ldr r1, #1024
+    msr sbase, r1
+    ldr r1, #1016
+    msr slimit, r1
+";
+
+    #[test]
+    fn stack_overflow() {
+        let (vm, res) = run(&format!(
+            "{BOUNDS}push #1\npush #2\npush #3\nhalt\n"
+        ));
+        assert_eq!(res, Err(Exception::StackOverflow(1016)));
+        assert_eq!(vm[SP], 1016);
+
+        let (vm, res) = run(&format!(
+            "{BOUNDS}push #1\npush #2\ncall f\nf: halt\n"
+        ));
+        assert_eq!(res, Err(Exception::StackOverflow(1016)));
+        assert_eq!(vm[SP], 1016);
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let (vm, res) =
+            run(&format!("{BOUNDS}push #7\npop r2\npop r3\n"));
+        assert_eq!(res, Err(Exception::StackUnderflow(1024)));
+        assert_eq!((vm[SP], vm[R2]), (1024, 7));
+
+        let (_, res) = run(&format!("{BOUNDS}ret\n"));
+        assert_eq!(res, Err(Exception::StackUnderflow(1024)));
+    }
+
+    #[test]
+    fn stack_within_bounds() {
+        let (vm, res) = run(&format!(
+            "{BOUNDS}push #1\ncall f\npop r2\nhalt\nf: ret\n"
+        ));
+        assert_eq!(res, Ok(()));
+        assert_eq!((vm[SP], vm[R2]), (1024, 1));
+    }
+
+    #[test]
+    fn stack_unchecked_without_sbase() {
+        // SLIMIT alone does nothing
+        let (vm, res) = run("
+    ldr r1, #1020
+    msr slimit, r1
+    push #1
+    push #2
+    pop r2
+    pop r2
+    pop r2
+    halt
+");
+        assert_eq!(res, Ok(()));
+        assert_eq!(vm[SP], 1028);
+    }
+
+    #[test]
+    fn exit_codes() {
+        let (vm, res) = run("exit #5\nexit #6\n");
+        assert_eq!(res, Ok(()));
+        assert!(vm.is_halted());
+        assert_eq!((vm.exit_code(), vm[PC]), (Some(5), 4));
+
+        let (vm, _) = run("ldr r3, #9\nexit r3\n");
+        assert_eq!(vm.exit_code(), Some(9));
+
+        // halt has none, and a new run forgets the last one
+        let (mut vm, _) = run("exit #1\nhalt\n");
+        assert_eq!(vm.run(false), Ok(()));
+        assert_eq!((vm.exit_code(), vm[PC]), (None, 8));
+    }
+
+    #[test]
+    fn nop_changes_nothing() {
+        let (mut vm, _) = run("ldr r1, #3\ncmp r1, #5\nhalt\nnop\n");
+        let before = (vm.ctx.register, vm.ctx.special);
+        vm.step().unwrap();
+        let after = (vm.ctx.register, vm.ctx.special);
+        let mut expected = before;
+        expected.0[PC as usize] += 4;
+        assert_eq!(after, expected);
+        assert!(vm[FLAGS] != 0);
+    }
+}
@@ -0,0 +1,21 @@
+//! Stand in for the JIT on targets without a code generator,
+//! `Engine::Jit` runs as the block engine.
+
+use super::{block::Block, Context, Machine};
+
+pub const JIT_THRESHOLD: u32 = u32::MAX;
+
+pub(super) type NativeFn = fn(*mut Context) -> u32;
+
+#[derive(Debug, Default)]
+pub(super) struct CodeArena;
+
+impl CodeArena {
+    pub(super) fn reset(&mut self) {}
+}
+
+impl Machine {
+    pub(super) fn run_native(&mut self, _: &Block) -> Option<u32> {
+        None
+    }
+}
@@ -0,0 +1,277 @@
+use std::time::Instant;
+
+use super::{Machine, BIT, BLOCK_LEN};
+use crate::{error::Exception, register::*};
+
+/// `svc` number that ends the program with the code in `R0`. Only
+/// seen by the host with `TrapMode::Abort`, a guest kernel handles
+/// it like any other syscall otherwise.
+pub const SYS_EXIT: u32 = 0;
+
+// dispatches between clock reads in deadline runs
+const DEADLINE_INTERVAL: u32 = 1024;
+
+/// Why a bounded run returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// `halt` stopped the machine.
+    Halted,
+    /// The instruction budget ran out.
+    FuelExhausted,
+    /// The deadline passed.
+    Deadline,
+    /// About to execute the instruction at a breakpoint.
+    Breakpoint(BIT),
+    /// The `run_until` predicate held.
+    Condition,
+    /// An exception the guest didn't handle.
+    Exception(Exception),
+    /// `exit` or `svc #SYS_EXIT` with the code from `R0`.
+    Exit(BIT),
+}
+
+impl Machine {
+    /// Stop before executing the instruction at virtual address
+    /// `addr`. Runs started on a breakpoint step over it.
+    pub fn add_breakpoint(&mut self, addr: BIT) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: BIT) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Run at most `fuel` more instructions.
+    pub fn run_for(&mut self, fuel: u64) -> StopReason {
+        self.run_with(Some(fuel), None, |_| false)
+    }
+
+    /// Run until `f` holds, checked after every dispatch. That is a
+    /// whole block with the block engine, use `Engine::Interpreter`
+    /// to check after every instruction.
+    pub fn run_until<F>(&mut self, f: F) -> StopReason
+    where
+        F: FnMut(&Machine) -> bool,
+    {
+        self.run_with(None, None, f)
+    }
+
+    /// Run until `deadline` passes. The clock is only read every
+    /// few thousand instructions.
+    pub fn run_until_deadline(
+        &mut self,
+        deadline: Instant,
+    ) -> StopReason {
+        self.run_with(None, Some(deadline), |_| false)
+    }
+
+    /// Run until halted, an exception, exit or a breakpoint, or
+    /// whichever of the given limits is hit first.
+    pub fn run_with<F>(
+        &mut self,
+        fuel: Option<u64>,
+        deadline: Option<Instant>,
+        mut f: F,
+    ) -> StopReason
+    where
+        F: FnMut(&Machine) -> bool,
+    {
+        let limit = fuel.map(|n| self.steps.saturating_add(n));
+        let mut first = true;
+        let mut clock = 0;
+        self.halt = false;
+        self.exit = None;
+
+        loop {
+            if self.halt {
+                return match self.exit {
+                    Some(code) => StopReason::Exit(code),
+                    None => StopReason::Halted,
+                };
+            }
+            let left =
+                limit.map(|limit| limit.saturating_sub(self.steps));
+            if left == Some(0) {
+                return StopReason::FuelExhausted;
+            }
+            if let Some(deadline) = deadline {
+                clock += 1;
+                if clock == DEADLINE_INTERVAL {
+                    clock = 0;
+                    if Instant::now() >= deadline {
+                        return StopReason::Deadline;
+                    }
+                }
+            }
+
+            let pc = self[PC];
+            if !first && self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint(pc);
+            }
+            first = false;
+
+            // blocks could run past the budget or a breakpoint
+            let exact = left.is_some_and(|n| n < BLOCK_LEN as u64)
+                || !self.breakpoints.is_empty();
+            let res =
+                if exact { self.step() } else { self.dispatch() };
+            match res {
+                Ok(()) => {}
+                Err(Exception::Syscall(SYS_EXIT)) => {
+                    return StopReason::Exit(self[R0]);
+                }
+                Err(e) => return StopReason::Exception(e),
+            }
+
+            if f(self) {
+                return StopReason::Condition;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        assembler::assemble,
+        vm::{Engine, TrapMode},
+    };
+
+    const SPIN: &str = "
+loop:
+    add r1, r1, #1
+    add r2, r2, r1
+    cmp r1, #0
+    bne loop
+    halt
+";
+
+    fn machine(source: &str, engine: Engine) -> Machine {
+        let code = assemble("test.jasm", source).code;
+        let mut vm =
+            Machine::builder().program(&code).build().unwrap();
+        vm.set_engine(engine);
+        vm
+    }
+
+    const ENGINES: [Engine; 3] =
+        [Engine::Interpreter, Engine::Block, Engine::Jit];
+
+    #[test]
+    fn fuel_is_exact() {
+        for n in [0, 1, 3, 31, 32, 33, 64, 100, 1001] {
+            let mut reference = machine(SPIN, Engine::Interpreter);
+            assert_eq!(
+                reference.run_for(n),
+                StopReason::FuelExhausted
+            );
+            assert_eq!(reference.steps(), n);
+            for engine in ENGINES {
+                let mut vm = machine(SPIN, engine);
+                assert_eq!(vm.run_for(n), StopReason::FuelExhausted);
+                assert_eq!(vm.steps(), n, "{engine:?}");
+                assert_eq!(
+                    (vm[PC], vm[R1], vm[R2]),
+                    (reference[PC], reference[R1], reference[R2]),
+                    "{engine:?} after {n}"
+                );
+            }
+        }
+
+        // and adds up over runs
+        for engine in ENGINES {
+            let mut vm = machine(SPIN, engine);
+            for _ in 0..10 {
+                vm.run_for(37);
+            }
+            assert_eq!(vm.steps(), 370, "{engine:?}");
+        }
+    }
+
+    #[test]
+    fn stop_reasons() {
+        let cases = [
+            ("halt", StopReason::Halted),
+            ("exit #3", StopReason::Exit(3)),
+            ("ldr r0, #4\nsvc #0", StopReason::Exit(4)),
+            (
+                "div r1, r1, #0",
+                StopReason::Exception(Exception::DivisionByZero),
+            ),
+        ];
+        for engine in ENGINES {
+            for (source, want) in &cases {
+                let mut vm = machine(source, engine);
+                assert_eq!(
+                    &vm.run_for(100),
+                    want,
+                    "{engine:?} {source}"
+                );
+            }
+        }
+
+        // a guest kernel gets svc #0 like any other
+        let mut vm = machine(
+            "ldr r1, #64\nmsr vbar, r1\nsvc #0",
+            Engine::Interpreter,
+        );
+        vm.set_trap_mode(TrapMode::Vector);
+        assert_eq!(vm.run_for(3), StopReason::FuelExhausted);
+        assert_eq!(vm[PC], 64 + 6 * 4);
+        assert_eq!(vm[CAUSE], 6);
+    }
+
+    #[test]
+    fn until() {
+        let mut vm = machine(SPIN, Engine::Interpreter);
+        assert_eq!(
+            vm.run_until(|m| m[R2] >= 55),
+            StopReason::Condition
+        );
+        assert_eq!((vm[R1], vm[R2]), (10, 55));
+
+        // checked per dispatch, so at the end of a block at worst
+        let mut vm = machine(SPIN, Engine::Block);
+        assert_eq!(
+            vm.run_until(|m| m[R1] >= 10),
+            StopReason::Condition
+        );
+        assert_eq!(vm[R1], 10);
+    }
+
+    #[test]
+    fn deadline() {
+        let mut vm = machine(SPIN, Engine::Interpreter);
+        let past = Instant::now();
+        assert_eq!(vm.run_until_deadline(past), StopReason::Deadline);
+        // the clock is only read every DEADLINE_INTERVAL dispatches
+        assert_eq!(vm.steps(), DEADLINE_INTERVAL as u64 - 1);
+
+        let mut vm = machine("ldr r1, #5\nhalt", Engine::Interpreter);
+        let later = Instant::now() + Duration::from_secs(60);
+        assert_eq!(vm.run_until_deadline(later), StopReason::Halted);
+    }
+
+    #[test]
+    fn breakpoints() {
+        // on the second add, 4 bytes into the loop
+        for engine in ENGINES {
+            let mut vm = machine(SPIN, engine);
+            vm.add_breakpoint(4);
+            for i in 1..=3 {
+                assert_eq!(
+                    vm.run_for(1000),
+                    StopReason::Breakpoint(4)
+                );
+                assert_eq!((vm[PC], vm[R1]), (4, i), "{engine:?}");
+            }
+            assert!(vm.remove_breakpoint(4));
+            assert!(!vm.remove_breakpoint(4));
+            vm.run_for(100);
+            assert!(vm[R1] > 3);
+        }
+    }
+}